@@ -1,4 +1,5 @@
 import { t } from './i18n.js';
+import { formatDbTime } from './sessions.js';
 
 function renderUserList(users) {
     const userListContainer = document.getElementById('user-list-container');
@@ -27,6 +28,7 @@ function renderUserList(users) {
                     <td data-label="${t('tableHeaderUsername')}">${user.username}</td>
                     <td data-label="${t('tableHeaderRole')}">${user.role}</td>
                     <td data-label="${t('tableHeaderActions')}">
+                        <button class="btn-admin btn-safe btn-small" data-action="reset-password" data-user-id="${user._id}" data-username="${user.username}">${t('resetPasswordButton')}</button>
                         <button class="btn-admin btn-danger btn-small" data-action="delete-user" data-user-id="${user._id}" data-username="${user.username}">${t('deleteButton')}</button>
                     </td>
                 </tr>
@@ -76,4 +78,82 @@ function renderRoomList(rooms) {
     roomListContainer.appendChild(table);
 }
 
-export { renderUserList, renderRoomList };
\ No newline at end of file
+function renderLockoutList(lockouts) {
+    const lockoutListContainer = document.getElementById('lockout-list-container');
+    if (!lockoutListContainer) return;
+
+    if (!lockouts || lockouts.length === 0) {
+        lockoutListContainer.innerHTML = `<p>${t('noLockouts')}</p>`;
+        return;
+    }
+
+    const table = document.createElement('table');
+    table.classList.add('responsive-table');
+    table.innerHTML = `
+        <thead>
+            <tr>
+                <th>${t('tableHeaderType')}</th>
+                <th>${t('tableHeaderKey')}</th>
+                <th>${t('tableHeaderFailures')}</th>
+                <th>${t('tableHeaderLockedFor')}</th>
+                <th>${t('tableHeaderActions')}</th>
+            </tr>
+        </thead>
+        <tbody>
+            ${lockouts.map(lockout => `
+                <tr>
+                    <td data-label="${t('tableHeaderType')}">${lockout.kind === 'ip' ? t('lockoutKindIp') : t('lockoutKindUsername')}</td>
+                    <td data-label="${t('tableHeaderKey')}">${lockout.key}</td>
+                    <td data-label="${t('tableHeaderFailures')}">${lockout.failures}</td>
+                    <td data-label="${t('tableHeaderLockedFor')}">${lockout.locked_for_secs > 0 ? `${lockout.locked_for_secs}s` : '-'}</td>
+                    <td data-label="${t('tableHeaderActions')}">
+                        <button class="btn-admin btn-safe btn-small" data-action="clear-lockout" data-kind="${lockout.kind}" data-key="${lockout.key}">${t('clearButton')}</button>
+                    </td>
+                </tr>
+            `).join('')}
+        </tbody>
+    `;
+    lockoutListContainer.innerHTML = '';
+    lockoutListContainer.appendChild(table);
+}
+
+function renderInviteList(invites) {
+    const inviteListContainer = document.getElementById('invite-list-container');
+    if (!inviteListContainer) return;
+
+    if (!invites || invites.length === 0) {
+        inviteListContainer.innerHTML = `<p>${t('noInvites')}</p>`;
+        return;
+    }
+
+    const table = document.createElement('table');
+    table.classList.add('responsive-table');
+    table.innerHTML = `
+        <thead>
+            <tr>
+                <th>${t('tableHeaderCode')}</th>
+                <th>${t('tableHeaderUses')}</th>
+                <th>${t('tableHeaderExpires')}</th>
+                <th>${t('tableHeaderCreatedBy')}</th>
+                <th>${t('tableHeaderActions')}</th>
+            </tr>
+        </thead>
+        <tbody>
+            ${invites.map(invite => `
+                <tr>
+                    <td data-label="${t('tableHeaderCode')}"><code>${invite.code}</code></td>
+                    <td data-label="${t('tableHeaderUses')}">${invite.uses} / ${invite.max_uses ?? '∞'}</td>
+                    <td data-label="${t('tableHeaderExpires')}">${invite.expires_at ? formatDbTime(invite.expires_at) : t('inviteNeverExpires')}</td>
+                    <td data-label="${t('tableHeaderCreatedBy')}">${invite.created_by ?? '-'}</td>
+                    <td data-label="${t('tableHeaderActions')}">
+                        <button class="btn-admin btn-danger btn-small" data-action="revoke-invite" data-code="${invite.code}">${t('revokeButton')}</button>
+                    </td>
+                </tr>
+            `).join('')}
+        </tbody>
+    `;
+    inviteListContainer.innerHTML = '';
+    inviteListContainer.appendChild(table);
+}
+
+export { renderUserList, renderRoomList, renderLockoutList, renderInviteList };
\ No newline at end of file
@@ -76,4 +76,78 @@ function stopAudioCapture() {
     console.log("Audio capture stopped.");
 }
 
-export { startAudioCapture, stopAudioCapture, playAudioChunk, setMute };
+// --- Voice Notes ---
+
+const VOICE_NOTE_SAMPLE_RATE = 8000; // The server stores voice notes at this rate
+let recorder = null; // The voice note being recorded
+
+async function startVoiceNoteRecording() {
+    const stream = await navigator.mediaDevices.getUserMedia({ audio: true });
+    const context = new (window.AudioContext || window.webkitAudioContext)();
+    const source = context.createMediaStreamSource(stream);
+    const processor = context.createScriptProcessor(4096, 1, 1);
+    const chunks = [];
+    processor.onaudioprocess = (event) => chunks.push(new Float32Array(event.inputBuffer.getChannelData(0)));
+    source.connect(processor);
+    processor.connect(context.destination);
+    recorder = { stream, context, source, processor, chunks };
+}
+
+// Stops recording and returns the clip as a WAV file, or null if nothing was recorded.
+function stopVoiceNoteRecording() {
+    if (!recorder) return null;
+    const { stream, context, source, processor, chunks } = recorder;
+    recorder = null;
+    processor.disconnect();
+    source.disconnect();
+    stream.getTracks().forEach(track => track.stop());
+    context.close();
+
+    const length = chunks.reduce((sum, chunk) => sum + chunk.length, 0);
+    if (length === 0) return null;
+    const samples = new Float32Array(length);
+    let offset = 0;
+    chunks.forEach(chunk => { samples.set(chunk, offset); offset += chunk.length; });
+    // The server only takes the longest notes when they are uploaded at the rate it stores them at.
+    const rate = Math.min(context.sampleRate, VOICE_NOTE_SAMPLE_RATE);
+    return encodeWav(downsample(samples, context.sampleRate, rate), rate);
+}
+
+// Averages the samples that fall into each output sample.
+function downsample(samples, fromRate, toRate) {
+    if (fromRate === toRate) return samples;
+    const ratio = fromRate / toRate;
+    const result = new Float32Array(Math.floor(samples.length / ratio));
+    for (let i = 0; i < result.length; i++) {
+        const start = Math.floor(i * ratio);
+        const end = Math.max(start + 1, Math.min(samples.length, Math.floor((i + 1) * ratio)));
+        let sum = 0;
+        for (let j = start; j < end; j++) sum += samples[j];
+        result[i] = sum / (end - start);
+    }
+    return result;
+}
+
+// Wraps mono samples in a 16-bit PCM WAV file.
+function encodeWav(samples, sampleRate) {
+    const buffer = new ArrayBuffer(44 + samples.length * 2);
+    const view = new DataView(buffer);
+    const writeString = (offset, text) => [...text].forEach((c, i) => view.setUint8(offset + i, c.charCodeAt(0)));
+    writeString(0, 'RIFF');
+    view.setUint32(4, 36 + samples.length * 2, true);
+    writeString(8, 'WAVE');
+    writeString(12, 'fmt ');
+    view.setUint32(16, 16, true);
+    view.setUint16(20, 1, true); // PCM
+    view.setUint16(22, 1, true); // Mono
+    view.setUint32(24, sampleRate, true);
+    view.setUint32(28, sampleRate * 2, true);
+    view.setUint16(32, 2, true);
+    view.setUint16(34, 16, true);
+    writeString(36, 'data');
+    view.setUint32(40, samples.length * 2, true);
+    samples.forEach((sample, i) => view.setInt16(44 + i * 2, Math.max(-1, Math.min(1, sample)) * 0x7FFF, true));
+    return new Blob([buffer], { type: 'audio/wav' });
+}
+
+export { startAudioCapture, stopAudioCapture, playAudioChunk, setMute, startVoiceNoteRecording, stopVoiceNoteRecording };
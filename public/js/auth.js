@@ -15,6 +15,10 @@ function handleAuth(action, handlers) {
     }
     
     const payload = { username, password };
+    if (action === 'register') {
+        const inviteCode = document.getElementById('invite-code-input').value.trim();
+        if (inviteCode) payload.inviteCode = inviteCode;
+    }
 
     const ws = getWebSocket();
     if (ws && ws.readyState === WebSocket.OPEN) {
@@ -1,5 +1,6 @@
 import { t } from './i18n.js';
 import { currentUser } from './websocket.js';
+import { formatDbTime } from './sessions.js';
 
 function renderChatList(chats) {
     const chatList = document.getElementById('chat-list');
@@ -36,11 +37,212 @@ function renderChatList(chats) {
         const membersString = participants.length > 0 ? t('chatMembers').replace('{members}', participants.join(', ')) : '';
 
         chatItem.innerHTML = `
-            <div class="chat-name">${displayName || t('Unnamed Chat')}</div>
-            <div class="chat-members">${membersString}</div>
+            <div class="chat-summary">
+                <div class="chat-name"></div>
+                <div class="chat-preview"></div>
+            </div>
+            <div class="chat-members"></div>
         `;
+        // Group names are chosen by users, so never parse them as HTML.
+        chatItem.querySelector('.chat-name').textContent = displayName || t('unnamedChat');
+        chatItem.querySelector('.chat-members').textContent = membersString;
+        if (chat.is_group) {
+            const icon = document.createElement('i');
+            icon.className = 'fas fa-user-friends group-icon';
+            chatItem.querySelector('.chat-name').prepend(icon);
+        }
+
+        const last = chat.last_message;
+        if (last) {
+            chatItem.querySelector('.chat-preview').textContent = last.deleted
+                ? t('messageDeleted')
+                : `${last.sender_username}: ${last.content}`;
+        }
+        if (chat.unread_count > 0) {
+            chatItem.classList.add('unread');
+            const badge = document.createElement('span');
+            badge.className = 'badge';
+            badge.textContent = chat.unread_count;
+            chatItem.querySelector('.chat-name').appendChild(badge);
+        }
         chatList.appendChild(chatItem);
     });
 }
 
-export { renderChatList };
\ No newline at end of file
+// Returns the user's role in a room from its chat list entry.
+function roomRoleOf(room, username) {
+    if (!room || !room.is_group) return 'member';
+    if (room.owner === username) return 'owner';
+    return room.moderators.includes(username) ? 'moderator' : 'member';
+}
+
+const ROLE_RANK = { member: 0, moderator: 1, owner: 2 };
+
+// Shows the open group's name, topic and members. Moderators can remove members below
+// them, and the owner can appoint moderators or hand the group over.
+function renderGroupPanel(room) {
+    const panel = document.getElementById('group-panel');
+    panel.classList.toggle('hidden', !room || !room.is_group);
+    if (!room || !room.is_group) return;
+
+    const myRole = currentUser ? roomRoleOf(room, currentUser.username) : 'member';
+    const canModerate = ROLE_RANK[myRole] >= ROLE_RANK.moderator;
+    document.getElementById('group-name').textContent = room.name || t('unnamedChat');
+    document.getElementById('group-topic').textContent = room.topic || '';
+    document.getElementById('rename-group-btn').classList.toggle('hidden', !canModerate);
+    document.getElementById('set-topic-btn').classList.toggle('hidden', !canModerate);
+    const togglePublicBtn = document.getElementById('toggle-public-btn');
+    togglePublicBtn.classList.toggle('hidden', myRole !== 'owner');
+    togglePublicBtn.querySelector('.btn-text').textContent = t(room.is_public ? 'makePrivateButton' : 'makePublicButton');
+
+    const memberList = document.getElementById('group-member-list');
+    memberList.innerHTML = '';
+    room.participants.forEach(username => {
+        const role = roomRoleOf(room, username);
+        const memberItem = document.createElement('li');
+        memberItem.className = 'group-member';
+        memberItem.dataset.username = username;
+        memberItem.dataset.role = role;
+        memberItem.textContent = username;
+        if (role !== 'member') {
+            const roleLabel = document.createElement('span');
+            roleLabel.className = 'group-role';
+            roleLabel.textContent = t(role === 'owner' ? 'roleOwner' : 'roleModerator');
+            memberItem.appendChild(roleLabel);
+        }
+        if (currentUser && username !== currentUser.username) {
+            if (myRole === 'owner') {
+                memberItem.appendChild(createMemberButton('toggle-moderator-btn', role === 'moderator' ? 'fa-user-shield' : 'fa-shield-alt', t(role === 'moderator' ? 'removeModerator' : 'makeModerator')));
+                memberItem.appendChild(createMemberButton('make-owner-btn', 'fa-crown', t('makeOwner')));
+            }
+            if (ROLE_RANK[myRole] > ROLE_RANK[role] && canModerate) {
+                memberItem.appendChild(createMemberButton('kick-member-btn', 'fa-user-minus', t('removeFromGroup')));
+            }
+        }
+        memberList.appendChild(memberItem);
+    });
+}
+
+function createMemberButton(className, icon, title) {
+    const button = document.createElement('button');
+    button.className = `${className} member-action secondary`;
+    button.title = title;
+    button.innerHTML = `<i class="fas ${icon}"></i>`;
+    return button;
+}
+
+// Shows the public groups found by a directory search.
+function renderPublicRoomList(rooms) {
+    const roomList = document.getElementById('public-room-list');
+    roomList.innerHTML = '';
+    roomList.classList.remove('hidden');
+
+    if (rooms.length === 0) {
+        const emptyItem = document.createElement('li');
+        emptyItem.textContent = t('noPublicRooms');
+        roomList.appendChild(emptyItem);
+        return;
+    }
+
+    rooms.forEach(room => {
+        const roomItem = document.createElement('li');
+        roomItem.className = 'public-room-item';
+        roomItem.dataset.roomId = room.room_id;
+        roomItem.innerHTML = `
+            <div class="chat-summary">
+                <div class="chat-name"></div>
+                <div class="chat-preview"></div>
+            </div>
+            <button class="join-public-room-btn"></button>
+        `;
+        roomItem.querySelector('.chat-name').textContent = room.name || t('unnamedChat');
+        roomItem.querySelector('.chat-preview').textContent = [
+            t('memberCount').replace('{count}', room.member_count),
+            room.topic,
+        ].filter(Boolean).join(' · ');
+        roomItem.querySelector('.join-public-room-btn').textContent = t(room.joined ? 'openRoomButton' : 'joinRoomButton');
+        roomList.appendChild(roomItem);
+    });
+}
+
+// Shows the results of a message search. Later pages of the same search are appended.
+function renderSearchResults(results, append) {
+    const resultList = document.getElementById('search-result-list');
+    if (!append) {
+        resultList.innerHTML = '';
+    }
+    resultList.classList.remove('hidden');
+
+    if (!append && results.length === 0) {
+        const emptyItem = document.createElement('li');
+        emptyItem.textContent = t('noSearchResults');
+        resultList.appendChild(emptyItem);
+        return;
+    }
+
+    results.forEach(result => {
+        const resultItem = document.createElement('li');
+        resultItem.className = 'search-result-item';
+        resultItem.dataset.roomId = result.room_id;
+        resultItem.dataset.messageId = result.id;
+
+        const meta = document.createElement('div');
+        meta.className = 'chat-members';
+        meta.textContent = t('searchResultSender')
+            .replace('{username}', result.sender_username)
+            .replace('{time}', formatDbTime(result.timestamp));
+
+        // The server escapes the snippet and only adds <mark> around the matches.
+        const snippet = document.createElement('div');
+        snippet.className = 'search-snippet';
+        snippet.innerHTML = result.snippet;
+
+        const body = document.createElement('div');
+        body.append(snippet, meta);
+        resultItem.appendChild(body);
+        resultList.appendChild(resultItem);
+    });
+}
+
+// Lists unread mentions and shows their number on the Chats tab.
+function renderMentionList(mentions) {
+    const mentionList = document.getElementById('mention-list');
+    mentionList.innerHTML = '';
+
+    const badge = document.getElementById('mention-badge');
+    badge.textContent = mentions.length;
+    badge.classList.toggle('hidden', mentions.length === 0);
+    document.getElementById('mark-mentions-read-btn').classList.toggle('hidden', mentions.length === 0);
+
+    if (mentions.length === 0) {
+        const emptyItem = document.createElement('li');
+        emptyItem.textContent = t('noMentions');
+        mentionList.appendChild(emptyItem);
+        return;
+    }
+
+    mentions.forEach(mention => {
+        const mentionItem = document.createElement('li');
+        mentionItem.className = 'mention-item';
+        mentionItem.dataset.mentionId = mention.id;
+        mentionItem.dataset.roomId = mention.room_id;
+        mentionItem.dataset.messageId = mention.message_id;
+
+        const content = document.createElement('div');
+        content.className = 'chat-name';
+        content.textContent = mention.content;
+
+        const meta = document.createElement('div');
+        meta.className = 'chat-members';
+        meta.textContent = t('searchResultSender')
+            .replace('{username}', mention.from_username)
+            .replace('{time}', formatDbTime(mention.timestamp));
+
+        const body = document.createElement('div');
+        body.append(content, meta);
+        mentionItem.appendChild(body);
+        mentionList.appendChild(mentionItem);
+    });
+}
+
+export { renderChatList, renderGroupPanel, roomRoleOf, renderPublicRoomList, renderSearchResults, renderMentionList };
\ No newline at end of file
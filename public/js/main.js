@@ -1,12 +1,18 @@
 import { handleAuth } from './auth.js';
-import { initWebSocket, sendWsMessage, setCurrentUser } from './websocket.js';
-import { showMessage, addChatMessage, showPage } from './ui.js';
-import { renderChatList } from './chats.js';
+import { initWebSocket, sendWsMessage, currentUser, setCurrentUser, getWebSocket } from './websocket.js';
+import { showMessage, formatFileSize, formatDuration, addChatMessage, prependChatMessages, addPendingChatMessage, markChatMessageSent, markChatMessageFailed, applyMessageEdit, applyMessageDeletion, applyReactionUpdate, toggleReactionPicker, applyReadReceipt, renderThread, setCurrentRoomRole, showPage } from './ui.js';
+import { renderChatList, renderGroupPanel, roomRoleOf, renderPublicRoomList, renderSearchResults, renderMentionList } from './chats.js';
 import { renderFriendRequestList, addFriendRequestToList, renderFriendList } from './friends.js';
-import { renderUserList, renderRoomList } from './admin.js';
-import { startAudioCapture, stopAudioCapture, setMute } from './audio.js';
+import { renderUserList, renderRoomList, renderLockoutList, renderInviteList } from './admin.js';
+import { renderSessionList, formatDbTime } from './sessions.js';
+import { startAudioCapture, stopAudioCapture, setMute, startVoiceNoteRecording, stopVoiceNoteRecording } from './audio.js';
 import { initI18n, setLanguage, t } from './i18n.js';
 
+// While typing, typing_start is repeated this often to keep the server's indicator alive.
+const TYPING_REPEAT_MS = 3000;
+// typing_stop is sent after the input has been left alone this long.
+const TYPING_IDLE_MS = 4000;
+
 document.addEventListener('DOMContentLoaded', async () => {
     // --- Views & Pages ---
     const setupView = document.getElementById('setup-view');
@@ -19,9 +25,17 @@ document.addEventListener('DOMContentLoaded', async () => {
     const registerBtn = document.getElementById('register-btn');
     const addFriendBtn = document.getElementById('add-friend-btn');
     const logoutBtn = document.getElementById('logout-btn');
+    const sessionList = document.getElementById('session-list');
+    const revokeOtherSessionsBtn = document.getElementById('revoke-other-sessions-btn');
+    const changePasswordBtn = document.getElementById('change-password-btn');
     const backToMainBtn = document.getElementById('back-to-main-btn');
     const sendChatBtn = document.getElementById('send-chat-btn');
     const chatInput = document.getElementById('chat-input');
+    const chatMessages = document.getElementById('chat-messages');
+    const replyBar = document.getElementById('reply-bar');
+    const cancelReplyBtn = document.getElementById('cancel-reply-btn');
+    const threadPanel = document.getElementById('thread-panel');
+    const closeThreadBtn = document.getElementById('close-thread-btn');
     const chatList = document.getElementById('chat-list');
     const friendList = document.getElementById('friend-list');
     const adminPanelBtn = document.getElementById('admin-panel-btn');
@@ -31,6 +45,26 @@ document.addEventListener('DOMContentLoaded', async () => {
     const voiceControls = document.getElementById('voice-controls');
     const refreshUsersBtn = document.getElementById('refresh-users-btn');
     const refreshRoomsBtn = document.getElementById('refresh-rooms-btn');
+    const refreshLockoutsBtn = document.getElementById('refresh-lockouts-btn');
+    const loadOlderBtn = document.getElementById('load-older-btn');
+    const messageSearchInput = document.getElementById('message-search-input');
+    const messageSearchSenderInput = document.getElementById('message-search-sender-input');
+    const messageSearchBtn = document.getElementById('message-search-btn');
+    const searchResultList = document.getElementById('search-result-list');
+    const moreSearchResultsBtn = document.getElementById('more-search-results-btn');
+    const groupNameInput = document.getElementById('group-name-input');
+    const groupMembersInput = document.getElementById('group-members-input');
+    const createGroupBtn = document.getElementById('create-group-btn');
+    const groupPublicInput = document.getElementById('group-public-input');
+    const publicRoomSearchInput = document.getElementById('public-room-search-input');
+    const publicRoomList = document.getElementById('public-room-list');
+    const searchMessageArea = document.getElementById('search-message-area');
+    const mentionList = document.getElementById('mention-list');
+    const markMentionsReadBtn = document.getElementById('mark-mentions-read-btn');
+    const refreshInvitesBtn = document.getElementById('refresh-invites-btn');
+    const createInviteBtn = document.getElementById('create-invite-btn');
+    const inviteListContainer = document.getElementById('invite-list-container');
+    const lockoutListContainer = document.getElementById('lockout-list-container');
     const createUserBtn = document.getElementById('create-user-btn');
     const shutdownServerBtn = document.getElementById('shutdown-server-btn');
     const changePortBtn = document.getElementById('change-port-btn');
@@ -45,6 +79,7 @@ document.addEventListener('DOMContentLoaded', async () => {
 
     // --- Profile Page ---
     const profileUsername = document.getElementById('profile-username');
+    const readReceiptsToggle = document.getElementById('read-receipts-toggle');
 
     // --- App State ---
     let isMuted = false;
@@ -53,14 +88,184 @@ document.addEventListener('DOMContentLoaded', async () => {
     let lastChatList = [];
     let lastFriendList = [];
     let lastFriendRequestList = [];
+    let lastMentionList = [];
+    let lastSearch = null; // Filters of the shown search, reused to fetch more results
+    let searchRequestId = null;
+    let groupRequestId = null; // Last group command, so its errors are shown
+    let appendSearchResults = false;
+    let pendingJumpMessageId = null; // Search result to scroll to once its room's history arrives
+    let replyingTo = null; // Message the next chat message answers, as { id, sender_username, content }
+    let pendingAttachments = []; // Uploaded files the next chat message will carry
+    let voiceNoteTimer = null; // Ticks while a voice note is being recorded
+    let voiceNoteMaxMs = 0; // Sent by the server on login, as it depends on the upload limit
+    const typingUsers = new Set(); // Others typing in the open room
+    let typingSentAt = 0; // When we last sent typing_start, 0 if we are not typing
+    let typingStopTimer = null;
 
     // Initialize i18n
     await initI18n();
 
+    function setReplyingTo(message) {
+        replyingTo = message;
+        replyBar.classList.toggle('hidden', !message);
+        if (message) {
+            document.getElementById('reply-bar-text').textContent = t('replyingTo')
+                .replace('{username}', message.sender_username)
+                .replace('{content}', message.content);
+            chatInput.focus();
+        }
+    }
+
+    function renderPendingAttachments() {
+        const container = document.getElementById('pending-attachments');
+        container.innerHTML = '';
+        container.classList.toggle('hidden', pendingAttachments.length === 0);
+        pendingAttachments.forEach(attachment => {
+            const chip = document.createElement('span');
+            chip.className = 'pending-attachment';
+            chip.textContent = `${attachment.filename} (${formatFileSize(attachment.size)}) `;
+            const removeBtn = document.createElement('button');
+            removeBtn.className = 'secondary';
+            removeBtn.innerHTML = '<i class="fas fa-times"></i>';
+            removeBtn.onclick = () => {
+                pendingAttachments = pendingAttachments.filter(a => a.id !== attachment.id);
+                renderPendingAttachments();
+            };
+            chip.appendChild(removeBtn);
+            container.appendChild(chip);
+        });
+    }
+
+    // Uploads a file over HTTP; the returned attachment is sent with the next message.
+    async function uploadAttachment(file, name = file.name, voice = false) {
+        const response = await fetch(`/upload?name=${encodeURIComponent(name)}${voice ? '&voice=true' : ''}`, {
+            method: 'POST',
+            headers: { 'Authorization': `Bearer ${localStorage.getItem('authToken')}` },
+            body: file,
+        });
+        if (!response.ok) {
+            const message = response.status === 413
+                ? t('attachmentTooLarge')
+                : (await response.json().catch(() => null))?.message || response.statusText;
+            throw new Error(message);
+        }
+        return response.json();
+    }
+
+    // <img>, <audio> and download links cannot send the login token, so the server keeps it
+    // in an HttpOnly cookie for them.
+    function setSessionCookie(token) {
+        return fetch('/session', { method: 'POST', headers: { 'Authorization': `Bearer ${token}` } });
+    }
+
+    function clearSessionCookie() {
+        return fetch('/session', { method: 'DELETE' }).catch(() => {});
+    }
+
+    // Exports are streamed, so the browser saves them as they arrive. The session cookie
+    // authenticates the link.
+    function downloadExport(url) {
+        const link = document.createElement('a');
+        link.href = url;
+        link.download = '';
+        link.click();
+    }
+
+    function renderTypingIndicator() {
+        const indicator = document.getElementById('typing-indicator');
+        const names = [...typingUsers];
+        indicator.classList.toggle('hidden', names.length === 0);
+        if (names.length === 1) {
+            indicator.textContent = t('typingOne').replace('{username}', names[0]);
+        } else if (names.length > 1) {
+            indicator.textContent = t('typingMany').replace('{usernames}', names.join(', '));
+        }
+    }
+
+    // Tells the room we are typing, repeating it every few seconds while we keep going,
+    // and that we stopped once the input is cleared or left alone.
+    function updateTyping() {
+        const roomId = Number(chatInput.dataset.currentRoomId);
+        clearTimeout(typingStopTimer);
+        if (!chatInput.value) {
+            stopTyping(roomId);
+            return;
+        }
+        if (Date.now() - typingSentAt > TYPING_REPEAT_MS) {
+            typingSentAt = Date.now();
+            sendWsMessage('typing_start', { roomId });
+        }
+        typingStopTimer = setTimeout(() => stopTyping(roomId), TYPING_IDLE_MS);
+    }
+
+    function stopTyping(roomId) {
+        clearTimeout(typingStopTimer);
+        if (typingSentAt) {
+            typingSentAt = 0;
+            sendWsMessage('typing_stop', { roomId });
+        }
+    }
+
+    function sendSearch(beforeId) {
+        appendSearchResults = beforeId !== undefined;
+        moreSearchResultsBtn.disabled = true;
+        searchRequestId = sendWsMessage('search_messages', { ...lastSearch, beforeId });
+    }
+
+    // Scrolls to the message picked from the search results, paging back through the
+    // room's history until it is loaded.
+    function continueJumpToMessage(hasMore) {
+        if (pendingJumpMessageId === null) return;
+
+        const target = document.querySelector(`#chat-messages [data-message-id="${pendingJumpMessageId}"]`);
+        const oldest = document.querySelector('#chat-messages [data-message-id]');
+        if (target) {
+            pendingJumpMessageId = null;
+            target.scrollIntoView({ block: 'center' });
+            target.classList.add('highlighted');
+            setTimeout(() => target.classList.remove('highlighted'), 2000);
+        } else if (hasMore && oldest) {
+            loadOlderBtn.disabled = true;
+            sendWsMessage('load_older_messages', {
+                roomId: Number(chatInput.dataset.currentRoomId),
+                beforeId: Number(oldest.dataset.messageId),
+                limit: 200,
+            });
+        } else {
+            pendingJumpMessageId = null;
+            alert(t('messageNotFound'));
+        }
+    }
+
+    // Updates one room of the chat list in place, or reloads the list for a room it doesn't know yet.
+    function updateChatListRoom(roomId, changes) {
+        const chat = lastChatList.find(c => c.room_id === roomId);
+        if (!chat) {
+            sendWsMessage('get_chat_list');
+            return;
+        }
+        Object.assign(chat, changes);
+        if (isUserAuthenticated) {
+            renderChatList(lastChatList);
+        }
+    }
+
+    function leaveCallView() {
+        callView.classList.add('hidden');
+        mainView.classList.remove('hidden');
+        stopAudioCapture();
+        isVoiceActive = false;
+    }
+
+    function sendGroupCommand(type, payload) {
+        groupRequestId = sendWsMessage(type, payload);
+    }
+
     function rerenderDynamicLists() {
         renderChatList(lastChatList);
         renderFriendList(lastFriendList);
         renderFriendRequestList(lastFriendRequestList);
+        renderMentionList(lastMentionList);
     }
 
     // --- WebSocket Message Handlers ---
@@ -76,15 +281,51 @@ document.addEventListener('DOMContentLoaded', async () => {
         auth_fail: (payload) => {
             isUserAuthenticated = false;
             localStorage.removeItem('authToken');
+            clearSessionCookie();
             showMessage(document.getElementById('message-area'), t('genericError').replace('{message}', payload), 'error');
             mainView.classList.add('hidden');
             callView.classList.add('hidden');
             setupView.classList.remove('hidden');
         },
-        auth_ok: (payload) => {
+        auth_locked: (payload) => {
+            isUserAuthenticated = false;
+            showMessage(document.getElementById('message-area'), t('authLocked').replace('{seconds}', payload.retry_after_secs), 'error');
+        },
+        auth_expired: () => {
+            isUserAuthenticated = false;
+            localStorage.removeItem('authToken');
+            clearSessionCookie();
+            showMessage(document.getElementById('message-area'), t('sessionExpired'), 'error');
+            mainView.classList.add('hidden');
+            callView.classList.add('hidden');
+            setupView.classList.remove('hidden');
+        },
+        logout_ok: async () => {
+            localStorage.removeItem('authToken');
+            await clearSessionCookie();
+            window.location.reload();
+        },
+        session_revoked: () => {
+            isUserAuthenticated = false;
+            localStorage.removeItem('authToken');
+            clearSessionCookie();
+            showMessage(document.getElementById('message-area'), t('sessionRevoked'), 'error');
+            mainView.classList.add('hidden');
+            callView.classList.add('hidden');
+            adminPanelView.classList.add('hidden');
+            setupView.classList.remove('hidden');
+        },
+        session_list: (payload) => renderSessionList(payload),
+        session_error: (payload) => alert(t('genericError').replace('{message}', payload.error)),
+        change_password_ok: () => showMessage(document.getElementById('change-password-message-area'), t('changePasswordSuccess'), 'success'),
+        read_receipts_setting: (payload) => { readReceiptsToggle.checked = payload.enabled; },
+        change_password_fail: (payload) => showMessage(document.getElementById('change-password-message-area'), t('genericError').replace('{message}', payload.error), 'error'),
+        auth_ok: async (payload) => {
             if (payload.token) {
                 localStorage.setItem('authToken', payload.token);
             }
+            // Set the cookie before any attachment can be shown.
+            await setSessionCookie(localStorage.getItem('authToken')).catch(() => {});
             setCurrentUser(payload);
             isUserAuthenticated = true; // Set authentication flag
 
@@ -93,6 +334,8 @@ document.addEventListener('DOMContentLoaded', async () => {
             showPage('chats-page');
 
             profileUsername.textContent = payload.username;
+            readReceiptsToggle.checked = payload.read_receipts_enabled;
+            voiceNoteMaxMs = payload.voice_note_max_secs * 1000;
             if (payload.role === 'admin') {
                 adminPanelBtn.classList.remove('hidden');
             }
@@ -106,6 +349,17 @@ document.addEventListener('DOMContentLoaded', async () => {
             mainView.classList.add('hidden');
             callView.classList.remove('hidden');
             document.getElementById('status-text').textContent = `${t('tableHeaderId')}: ${payload.roomId}`;
+            setReplyingTo(null);
+            threadPanel.classList.add('hidden');
+            const room = lastChatList.find(c => c.room_id === payload.roomId);
+            setCurrentRoomRole(roomRoleOf(room, currentUser.username));
+            renderGroupPanel(room);
+            typingUsers.clear();
+            renderTypingIndicator();
+            clearTimeout(typingStopTimer);
+            typingSentAt = 0;
+            pendingAttachments = [];
+            renderPendingAttachments();
             
             voiceControls.classList.add('hidden');
             startVoiceBtn.classList.remove('hidden');
@@ -116,9 +370,17 @@ document.addEventListener('DOMContentLoaded', async () => {
             chatInput.dataset.currentRoomId = currentRoomId;
             sendChatBtn.onclick = () => {
                  const content = chatInput.value;
-                 if (content) {
-                    sendWsMessage('send_chat_message', { roomId: currentRoomId, content });
+                 if (content || pendingAttachments.length > 0) {
+                    const attachments = pendingAttachments.map(a => a.id);
+                    const requestId = sendWsMessage('send_chat_message', { roomId: currentRoomId, content, replyTo: replyingTo?.id, attachments });
+                    addPendingChatMessage(requestId, currentUser.username, content, replyingTo, pendingAttachments);
                     chatInput.value = '';
+                    setReplyingTo(null);
+                    pendingAttachments = [];
+                    renderPendingAttachments();
+                    // Sending ends our typing indicator on the server.
+                    clearTimeout(typingStopTimer);
+                    typingSentAt = 0;
                  }
             };
              chatInput.onkeyup = (e) => {
@@ -134,10 +396,102 @@ document.addEventListener('DOMContentLoaded', async () => {
             }
         },
         message_history: (payload) => {
-            document.getElementById('chat-messages').innerHTML = '';
-            payload.forEach(addChatMessage);
+            chatMessages.innerHTML = '';
+            payload.messages.forEach(addChatMessage);
+            sendWsMessage('mark_read', { roomId: payload.room_id });
+            loadOlderBtn.classList.toggle('hidden', !payload.has_more);
+            loadOlderBtn.disabled = false;
+            continueJumpToMessage(payload.has_more);
+        },
+        older_messages: (payload) => {
+            prependChatMessages(payload.messages);
+            loadOlderBtn.classList.toggle('hidden', !payload.has_more);
+            loadOlderBtn.disabled = false;
+            continueJumpToMessage(payload.has_more);
+        },
+        search_results: (payload) => {
+            searchMessageArea.textContent = '';
+            renderSearchResults(payload.results, appendSearchResults);
+            moreSearchResultsBtn.classList.toggle('hidden', !payload.has_more);
+            moreSearchResultsBtn.disabled = false;
+        },
+        new_chat_message: (payload) => {
+            addChatMessage(payload);
+            if (typingUsers.delete(payload.sender_username)) renderTypingIndicator();
+            if (!callView.classList.contains('hidden')) {
+                sendWsMessage('mark_read', { roomId: payload.room_id, messageId: payload.id });
+                return;
+            }
+            // The room is still joined while the chat list is shown, so count it here.
+            const chat = lastChatList.find(c => c.room_id === payload.room_id);
+            const fromOthers = payload.sender_username !== currentUser.username;
+            updateChatListRoom(payload.room_id, {
+                unread_count: (chat ? chat.unread_count : 0) + (fromOthers ? 1 : 0),
+                last_message: { ...payload, deleted: false },
+            });
+        },
+        room_updated: (payload, requestId) => {
+            const index = lastChatList.findIndex(c => c.room_id === payload.room_id);
+            if (payload.room && index >= 0) {
+                lastChatList[index] = payload.room;
+            } else if (payload.room) {
+                lastChatList.unshift(payload.room);
+            } else if (index >= 0) {
+                lastChatList.splice(index, 1);
+            }
+            if (isUserAuthenticated) {
+                renderChatList(lastChatList);
+            }
+
+            if (String(payload.room_id) !== chatInput.dataset.currentRoomId || callView.classList.contains('hidden')) return;
+            if (payload.room) {
+                setCurrentRoomRole(roomRoleOf(payload.room, currentUser.username));
+                renderGroupPanel(payload.room);
+            } else {
+                delete chatInput.dataset.currentRoomId;
+                leaveCallView();
+                if (requestId !== groupRequestId) alert(t('removedFromGroup'));
+            }
+        },
+        public_rooms: (payload) => renderPublicRoomList(payload),
+        export_ready: (payload) => {
+            downloadExport(payload.url);
+        },
+        room_unread: (payload) => updateChatListRoom(payload.room_id, {
+            unread_count: payload.unread_count,
+            last_message: payload.last_message,
+        }),
+        typing: (payload) => {
+            if (String(payload.room_id) !== chatInput.dataset.currentRoomId) return;
+            if (payload.typing) {
+                typingUsers.add(payload.username);
+            } else {
+                typingUsers.delete(payload.username);
+            }
+            renderTypingIndicator();
+        },
+        read_receipt: (payload) => {
+            if (String(payload.room_id) === chatInput.dataset.currentRoomId) applyReadReceipt(payload);
+        },
+        message_ack: (payload, requestId) => markChatMessageSent(requestId, payload),
+        message_edited: (payload) => applyMessageEdit(payload),
+        message_deleted: (payload) => applyMessageDeletion(payload),
+        thread: (payload) => renderThread(payload),
+        reaction_updated: (payload) => applyReactionUpdate(payload),
+        mentions: (payload) => {
+            lastMentionList = payload;
+            if (isUserAuthenticated) {
+                renderMentionList(lastMentionList);
+            }
+        },
+        mention: (payload) => {
+            lastMentionList = [payload, ...lastMentionList];
+            renderMentionList(lastMentionList);
+        },
+        message_edits: (payload) => {
+            const versions = payload.edits.map(edit => `[${formatDbTime(edit.edited_at)}] ${edit.content}`);
+            alert(`${t('editHistoryTitle')}\n\n${versions.join('\n')}`);
         },
-        new_chat_message: (payload) => addChatMessage(payload),
 
         // Friend Requests & Invitations
         friend_list: (payload) => {
@@ -186,15 +540,36 @@ document.addEventListener('DOMContentLoaded', async () => {
         // Admin
         admin_all_users: (payload) => renderUserList(payload),
         admin_all_rooms: (payload) => renderRoomList(payload),
+        admin_lockouts: (payload) => renderLockoutList(payload),
+        admin_invites: (payload) => renderInviteList(payload),
         admin_create_user_ok: (payload) => alert(t('genericSuccess').replace('{message}', payload)),
-        admin_create_user_fail: (payload) => alert(t('genericError').replace('{message}', payload)),
+        admin_create_user_fail: (payload) => alert(t('genericError').replace('{message}', payload.error || payload)),
         admin_change_port_ok: () => alert(t('changePortSuccess')),
-        admin_change_port_fail: (payload) => alert(t('changePortFail').replace('{error}', payload)),
+        admin_change_port_fail: (payload) => alert(t('changePortFail').replace('{error}', payload.error || payload)),
         admin_generic_ok: (payload) => alert(t('genericSuccess').replace('{message}', payload)),
-        admin_error: (payload) => alert(t('genericError').replace('{message}', payload)),
+        admin_error: (payload) => alert(t('genericError').replace('{message}', payload.error || payload)),
 
         // General
-        error: (e) => {
+        error: (payload, requestId) => {
+            // The server rejected a request without acting on it.
+            console.warn(`[WS] Request rejected (${payload.code}): ${payload.message}`);
+            if (requestId && markChatMessageFailed(requestId)) return;
+            if (requestId && requestId === groupRequestId) {
+                alert(t('genericError').replace('{message}', payload.message));
+                return;
+            }
+            if (requestId && requestId === searchRequestId) {
+                moreSearchResultsBtn.disabled = false;
+                showMessage(searchMessageArea, t('genericError').replace('{message}', payload.message), 'error');
+                return;
+            }
+            if (!isUserAuthenticated) {
+                showMessage(document.getElementById('message-area'), t('genericError').replace('{message}', payload.message), 'error');
+            } else if (!['malformed', 'unknown_type', 'invalid_payload'].includes(payload.code)) {
+                alert(t('genericError').replace('{message}', payload.message));
+            }
+        },
+        connection_error: (e) => {
             console.error('[WS] WebSocket error:', e);
             showMessage(document.getElementById('message-area'), t('connectionError'));
         },
@@ -213,10 +588,17 @@ document.addEventListener('DOMContentLoaded', async () => {
         rerenderDynamicLists();
     });
 
+    readReceiptsToggle.addEventListener('change', () => {
+        sendWsMessage('set_read_receipts', { enabled: readReceiptsToggle.checked });
+    });
+
     // Navigation
     navChatsBtn.addEventListener('click', () => showPage('chats-page'));
     navFriendsBtn.addEventListener('click', () => showPage('friends-page'));
-    navProfileBtn.addEventListener('click', () => showPage('profile-page'));
+    navProfileBtn.addEventListener('click', () => {
+        showPage('profile-page');
+        sendWsMessage('list_sessions');
+    });
 
     adminPanelBtn.addEventListener('click', () => {
         mainView.classList.add('hidden');
@@ -230,6 +612,45 @@ document.addEventListener('DOMContentLoaded', async () => {
 
     refreshUsersBtn.addEventListener('click', () => sendWsMessage('admin_get_all_users'));
     refreshRoomsBtn.addEventListener('click', () => sendWsMessage('admin_get_all_rooms'));
+    refreshLockoutsBtn.addEventListener('click', () => sendWsMessage('admin_get_lockouts'));
+
+    chatInput.addEventListener('input', updateTyping);
+
+    loadOlderBtn.addEventListener('click', () => {
+        const oldest = document.querySelector('#chat-messages [data-message-id]');
+        if (!oldest) return;
+
+        loadOlderBtn.disabled = true;
+        sendWsMessage('load_older_messages', {
+            roomId: Number(chatInput.dataset.currentRoomId),
+            beforeId: Number(oldest.dataset.messageId),
+        });
+    });
+    refreshInvitesBtn.addEventListener('click', () => sendWsMessage('admin_get_invites'));
+
+    createInviteBtn.addEventListener('click', () => {
+        const maxUsesInput = document.getElementById('invite-max-uses-input');
+        const expiresInput = document.getElementById('invite-expires-input');
+        const max_uses = maxUsesInput.value ? parseInt(maxUsesInput.value, 10) : null;
+        const expires_in_hours = expiresInput.value ? parseInt(expiresInput.value, 10) : null;
+        sendWsMessage('admin_create_invite', { max_uses, expires_in_hours });
+        maxUsesInput.value = '';
+        expiresInput.value = '';
+    });
+
+    inviteListContainer.addEventListener('click', (e) => {
+        const target = e.target.closest('[data-action="revoke-invite"]');
+        if (!target) return;
+
+        sendWsMessage('admin_revoke_invite', { code: target.dataset.code });
+    });
+
+    lockoutListContainer.addEventListener('click', (e) => {
+        const target = e.target.closest('[data-action="clear-lockout"]');
+        if (!target) return;
+
+        sendWsMessage('admin_clear_lockout', { kind: target.dataset.kind, key: target.dataset.key });
+    });
 
     createUserBtn.addEventListener('click', () => {
         const newUsernameInput = document.getElementById('new-username-input');
@@ -268,6 +689,16 @@ document.addEventListener('DOMContentLoaded', async () => {
     });
 
     userListContainer.addEventListener('click', (e) => {
+        const resetTarget = e.target.closest('[data-action="reset-password"]');
+        if (resetTarget) {
+            const userId = parseInt(resetTarget.dataset.userId, 10);
+            const newPassword = prompt(t('promptResetPassword').replace('{username}', resetTarget.dataset.username));
+            if (newPassword) {
+                sendWsMessage('admin_reset_password', { user_id: userId, new_password: newPassword });
+            }
+            return;
+        }
+
         const target = e.target.closest('[data-action="delete-user"]');
         if (!target) return;
 
@@ -311,10 +742,128 @@ document.addEventListener('DOMContentLoaded', async () => {
     });
 
     logoutBtn.addEventListener('click', () => {
-        localStorage.removeItem('authToken');
-        window.location.reload();
+        const ws = getWebSocket();
+        if (ws && ws.readyState === WebSocket.OPEN) {
+            // The server revokes the token and replies with logout_ok.
+            sendWsMessage('logout');
+        } else {
+            localStorage.removeItem('authToken');
+            clearSessionCookie().then(() => window.location.reload());
+        }
+    });
+
+    sessionList.addEventListener('click', (e) => {
+        const revokeBtn = e.target.closest('.revoke-session-btn');
+        if (!revokeBtn) return;
+
+        const sessionId = parseInt(revokeBtn.closest('.session-list-item').dataset.sessionId, 10);
+        sendWsMessage('revoke_session', { sessionId });
     });
 
+    changePasswordBtn.addEventListener('click', () => {
+        const oldPasswordInput = document.getElementById('old-password-input');
+        const newPasswordInput = document.getElementById('new-password-change-input');
+        const confirmPasswordInput = document.getElementById('confirm-password-input');
+        const messageArea = document.getElementById('change-password-message-area');
+
+        if (!oldPasswordInput.value || !newPasswordInput.value) {
+            showMessage(messageArea, t('authEmptyFields'));
+            return;
+        }
+        if (newPasswordInput.value !== confirmPasswordInput.value) {
+            showMessage(messageArea, t('passwordMismatch'));
+            return;
+        }
+
+        sendWsMessage('change_password', { oldPassword: oldPasswordInput.value, newPassword: newPasswordInput.value });
+        oldPasswordInput.value = '';
+        newPasswordInput.value = '';
+        confirmPasswordInput.value = '';
+    });
+
+    revokeOtherSessionsBtn.addEventListener('click', () => {
+        if (confirm(t('confirmRevokeOtherSessions'))) {
+            sendWsMessage('revoke_all_other_sessions');
+        }
+    });
+
+    messageSearchBtn.addEventListener('click', () => {
+        const query = messageSearchInput.value.trim();
+        if (!query) return;
+
+        lastSearch = { query, sender: messageSearchSenderInput.value.trim() || null };
+        sendSearch();
+    });
+    messageSearchInput.addEventListener('keyup', (e) => {
+        if (e.key === 'Enter') messageSearchBtn.click();
+    });
+
+    moreSearchResultsBtn.addEventListener('click', () => {
+        const last = searchResultList.querySelector('.search-result-item:last-child');
+        if (lastSearch && last) {
+            sendSearch(Number(last.dataset.messageId));
+        }
+    });
+
+    searchResultList.addEventListener('click', (e) => {
+        const resultItem = e.target.closest('.search-result-item');
+        if (resultItem) {
+            // Joining sends the newest history page; the jump continues from there.
+            pendingJumpMessageId = Number(resultItem.dataset.messageId);
+            sendWsMessage('join_room', { roomId: Number(resultItem.dataset.roomId) });
+        }
+    });
+
+    chatMessages.addEventListener('click', (e) => {
+        const msgDiv = e.target.closest('.chat-message[data-message-id]');
+        if (!msgDiv) return;
+        const messageId = Number(msgDiv.dataset.messageId);
+
+        const reaction = e.target.closest('.reaction, .reaction-option');
+        if (reaction) {
+            const own = reaction.classList.contains('reaction') && reaction.classList.contains('mine');
+            sendWsMessage(own ? 'remove_reaction' : 'add_reaction', { messageId, emoji: reaction.dataset.emoji });
+            msgDiv.querySelector('.reaction-picker')?.remove();
+        } else if (e.target.closest('.add-reaction-btn')) {
+            toggleReactionPicker(msgDiv);
+        } else if (e.target.closest('.reply-message-btn')) {
+            setReplyingTo({
+                id: messageId,
+                sender_username: msgDiv.dataset.sender,
+                content: msgDiv.querySelector('.content').textContent,
+            });
+        } else if (e.target.closest('.view-thread-btn')) {
+            sendWsMessage('get_thread', { messageId });
+        } else if (e.target.closest('.reply-quote')) {
+            sendWsMessage('get_thread', { messageId: Number(e.target.closest('.reply-quote').dataset.replyTo) });
+        } else if (e.target.closest('.edit-message-btn')) {
+            const current = msgDiv.querySelector('.content').textContent;
+            const content = prompt(t('editMessagePrompt'), current);
+            if (content && content.trim() && content !== current) {
+                sendWsMessage('edit_message', { messageId, content });
+            }
+        } else if (e.target.closest('.delete-message-btn')) {
+            if (confirm(t('confirmDeleteMessage'))) {
+                sendWsMessage('delete_message', { messageId });
+            }
+        } else if (e.target.closest('.edited-marker')) {
+            sendWsMessage('get_message_edits', { messageId });
+        }
+    });
+
+    mentionList.addEventListener('click', (e) => {
+        const mentionItem = e.target.closest('.mention-item');
+        if (mentionItem) {
+            sendWsMessage('mark_mentions_read', { ids: [Number(mentionItem.dataset.mentionId)] });
+            pendingJumpMessageId = Number(mentionItem.dataset.messageId);
+            sendWsMessage('join_room', { roomId: Number(mentionItem.dataset.roomId) });
+        }
+    });
+    markMentionsReadBtn.addEventListener('click', () => sendWsMessage('mark_mentions_read', {}));
+
+    cancelReplyBtn.addEventListener('click', () => setReplyingTo(null));
+    closeThreadBtn.addEventListener('click', () => threadPanel.classList.add('hidden'));
+
     chatList.addEventListener('click', (e) => {
         const chatItem = e.target.closest('.chat-list-item');
         if (chatItem) {
@@ -344,11 +893,149 @@ document.addEventListener('DOMContentLoaded', async () => {
         }
     });
 
-    backToMainBtn.addEventListener('click', () => {
-        callView.classList.add('hidden');
-        mainView.classList.remove('hidden');
-        stopAudioCapture();
-        isVoiceActive = false;
+    backToMainBtn.addEventListener('click', leaveCallView);
+
+    createGroupBtn.addEventListener('click', () => {
+        const name = groupNameInput.value.trim();
+        if (!name) return;
+        const members = groupMembersInput.value.split(',').map(m => m.trim()).filter(Boolean);
+        sendGroupCommand('create_group', { name, members, public: groupPublicInput.checked });
+        groupNameInput.value = '';
+        groupMembersInput.value = '';
+        groupPublicInput.checked = false;
+    });
+
+    document.getElementById('rename-group-btn').addEventListener('click', () => {
+        const name = prompt(t('renameGroupPrompt'), document.getElementById('group-name').textContent);
+        if (name && name.trim()) {
+            sendGroupCommand('rename_room', { roomId: Number(chatInput.dataset.currentRoomId), name: name.trim() });
+        }
+    });
+
+    document.getElementById('invite-to-group-btn').addEventListener('click', () => {
+        const username = prompt(t('inviteToGroupPrompt'));
+        if (username && username.trim()) {
+            sendGroupCommand('invite_to_room', { roomId: Number(chatInput.dataset.currentRoomId), username: username.trim() });
+        }
+    });
+
+    document.getElementById('leave-group-btn').addEventListener('click', () => {
+        if (confirm(t('confirmLeaveGroup'))) {
+            sendGroupCommand('leave_room', { roomId: Number(chatInput.dataset.currentRoomId) });
+        }
+    });
+
+    const attachmentInput = document.getElementById('attachment-input');
+    document.getElementById('attach-btn').addEventListener('click', () => attachmentInput.click());
+    attachmentInput.addEventListener('change', async () => {
+        for (const file of attachmentInput.files) {
+            try {
+                pendingAttachments.push(await uploadAttachment(file));
+            } catch (error) {
+                alert(t('attachmentUploadFailed').replace('{filename}', file.name).replace('{message}', error.message));
+            }
+        }
+        attachmentInput.value = '';
+        renderPendingAttachments();
+    });
+
+    // Recording stops on the second click, or on its own at the server's limit.
+    const recordVoiceBtn = document.getElementById('record-voice-btn');
+    async function finishVoiceNote() {
+        clearInterval(voiceNoteTimer);
+        voiceNoteTimer = null;
+        recordVoiceBtn.classList.remove('recording');
+        recordVoiceBtn.querySelector('i').className = 'fas fa-microphone';
+        recordVoiceBtn.querySelector('.btn-text').textContent = '';
+
+        const clip = stopVoiceNoteRecording();
+        if (!clip) return;
+        try {
+            pendingAttachments.push(await uploadAttachment(clip, 'voice-note.wav', true));
+            sendChatBtn.onclick();
+        } catch (error) {
+            alert(t('attachmentUploadFailed').replace('{filename}', t('voiceNote')).replace('{message}', error.message));
+        }
+    }
+    recordVoiceBtn.addEventListener('click', async () => {
+        if (voiceNoteTimer) {
+            await finishVoiceNote();
+            return;
+        }
+        try {
+            await startVoiceNoteRecording();
+        } catch (error) {
+            console.error('Error recording voice note:', error);
+            alert(t('microphoneError'));
+            return;
+        }
+        const startedAt = Date.now();
+        recordVoiceBtn.classList.add('recording');
+        recordVoiceBtn.querySelector('i').className = 'fas fa-stop';
+        voiceNoteTimer = setInterval(() => {
+            const elapsed = Date.now() - startedAt;
+            recordVoiceBtn.querySelector('.btn-text').textContent = formatDuration(elapsed);
+            if (elapsed >= voiceNoteMaxMs) finishVoiceNote();
+        }, 250);
+    });
+
+    document.getElementById('toggle-public-btn').addEventListener('click', () => {
+        const roomId = Number(chatInput.dataset.currentRoomId);
+        const room = lastChatList.find(c => c.room_id === roomId);
+        if (room) {
+            sendGroupCommand('set_room_public', { roomId, public: !room.is_public });
+        }
+    });
+
+    document.getElementById('export-room-btn').addEventListener('click', () => {
+        const format = document.getElementById('export-format-select').value;
+        sendGroupCommand('export_room', { roomId: Number(chatInput.dataset.currentRoomId), format });
+    });
+    document.getElementById('admin-export-btn').addEventListener('click', () => {
+        sendWsMessage('admin_export_rooms', { format: document.getElementById('admin-export-format-select').value });
+    });
+
+    document.getElementById('public-room-search-btn').addEventListener('click', () => {
+        sendWsMessage('list_public_rooms', { query: publicRoomSearchInput.value.trim() || undefined });
+    });
+    publicRoomSearchInput.addEventListener('keyup', (e) => {
+        if (e.key === 'Enter') document.getElementById('public-room-search-btn').click();
+    });
+
+    // Joining a public group makes the user a member, then opens it.
+    publicRoomList.addEventListener('click', (e) => {
+        const roomItem = e.target.closest('.public-room-item');
+        if (roomItem && e.target.closest('.join-public-room-btn')) {
+            sendGroupCommand('join_room', { roomId: Number(roomItem.dataset.roomId) });
+        }
+    });
+
+    document.getElementById('set-topic-btn').addEventListener('click', () => {
+        const topic = prompt(t('setTopicPrompt'), document.getElementById('group-topic').textContent);
+        if (topic !== null) {
+            sendGroupCommand('set_room_topic', { roomId: Number(chatInput.dataset.currentRoomId), topic });
+        }
+    });
+
+    document.getElementById('group-member-list').addEventListener('click', (e) => {
+        const button = e.target.closest('.member-action');
+        if (!button) return;
+        const member = button.closest('.group-member');
+        const username = member.dataset.username;
+        const roomId = Number(chatInput.dataset.currentRoomId);
+
+        if (button.classList.contains('kick-member-btn')) {
+            if (confirm(t('confirmRemoveFromGroup').replace('{username}', username))) {
+                sendGroupCommand('kick_from_room', { roomId, username });
+            }
+        } else if (button.classList.contains('toggle-moderator-btn')) {
+            const role = member.dataset.role === 'moderator' ? 'member' : 'moderator';
+            sendGroupCommand('set_room_role', { roomId, username, role });
+        } else if (button.classList.contains('make-owner-btn')) {
+            if (confirm(t('confirmMakeOwner').replace('{username}', username))) {
+                sendGroupCommand('set_room_role', { roomId, username, role: 'owner' });
+            }
+        }
     });
 
     startVoiceBtn.addEventListener('click', async () => {
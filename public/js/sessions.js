@@ -0,0 +1,38 @@
+import { t } from './i18n.js';
+
+// SQLite timestamps are UTC without a zone marker.
+function formatDbTime(value) {
+    return value ? new Date(value.replace(' ', 'T') + 'Z').toLocaleString() : '-';
+}
+
+function renderSessionList(sessions) {
+    const sessionList = document.getElementById('session-list');
+    sessionList.innerHTML = ''; // Clear existing list
+
+    if (!sessions || sessions.length === 0) {
+        sessionList.innerHTML = `<li>${t('noSessions')}</li>`;
+        return;
+    }
+
+    sessions.forEach(session => {
+        const sessionItem = document.createElement('li');
+        sessionItem.className = 'session-list-item';
+        sessionItem.dataset.sessionId = session.id;
+
+        const lastUsed = formatDbTime(session.last_used_at);
+        const created = formatDbTime(session.created_at);
+        const current = session.current ? `<span class="status online">${t('currentSession')}</span>` : '';
+
+        sessionItem.innerHTML = `
+            <div class="session-info">
+                <div class="chat-name">${session.ip || '-'} ${current}</div>
+                <div class="chat-members">${session.user_agent || ''}</div>
+                <div class="chat-members">${t('sessionTimes').replace('{created}', created).replace('{lastUsed}', lastUsed)}</div>
+            </div>
+            <button class="revoke-session-btn btn-danger btn-small">${t('revokeButton')}</button>
+        `;
+        sessionList.appendChild(sessionItem);
+    });
+}
+
+export { renderSessionList, formatDbTime };
@@ -1,22 +1,323 @@
+import { t } from './i18n.js';
+import { currentUser } from './websocket.js';
+
 function showMessage(area, text, type = 'error') {
     area.textContent = text;
     area.className = `message ${type}`;
 }
 
+// How long a sent message may wait for the server's ack before it is shown as failed.
+const PENDING_TIMEOUT_MS = 10000;
+
+// The user's role in the open room, as used for moderation actions.
+let currentRoomRole = 'member';
+
+function setCurrentRoomRole(role) {
+    currentRoomRole = role;
+}
+
+// Emoji offered by the reaction picker.
+const QUICK_REACTIONS = ['👍', '❤️', '😂', '🎉', '👀', '✅'];
+
 function addChatMessage(message) {
     const chatMessages = document.getElementById('chat-messages');
+    // Our own messages are already shown from the moment they were acked.
+    if (chatMessages.querySelector(`[data-message-id="${message.id}"]`)) return;
+
+    chatMessages.appendChild(createChatMessageElement(message));
+    chatMessages.scrollTop = chatMessages.scrollHeight;
+}
+
+// Inserts a page of older messages above the ones shown, keeping the view where it was.
+function prependChatMessages(messages) {
+    const chatMessages = document.getElementById('chat-messages');
+    const previousHeight = chatMessages.scrollHeight;
+
+    const fragment = document.createDocumentFragment();
+    messages.forEach(message => fragment.appendChild(createChatMessageElement(message)));
+    chatMessages.prepend(fragment);
+
+    chatMessages.scrollTop += chatMessages.scrollHeight - previousHeight;
+}
+
+function createChatMessageElement(message) {
     const msgDiv = document.createElement('div');
     msgDiv.classList.add('chat-message');
+    msgDiv.dataset.messageId = message.id;
+    msgDiv.dataset.sender = message.sender_username;
     
     const timestamp = new Date(message.timestamp).toLocaleTimeString();
 
     msgDiv.innerHTML = `
         <span class="timestamp">[${timestamp}]</span>
-        <span class="username">${message.sender_username}:</span>
-        <span class="content">${message.content}</span>
+        <span class="username"></span>
+        <span class="content"></span>
     `;
+    msgDiv.querySelector('.username').textContent = `${message.sender_username}:`;
+    if (message.reply_to) addReplyQuote(msgDiv, message.reply_to, message.reply_preview);
+
+    if (message.deleted_at) {
+        setMessageDeleted(msgDiv);
+        return msgDiv;
+    }
+    msgDiv.querySelector('.content').textContent = message.content;
+    if (message.edited_at) setMessageEdited(msgDiv);
+    renderAttachments(msgDiv, message.attachments || []);
+    addMessageActions(msgDiv, message.sender_username);
+    renderReactions(msgDiv, message.reactions || []);
+    return msgDiv;
+}
+
+const ATTACHMENT_PREVIEW_MAX = 240; // Largest side of an image preview, in CSS pixels
+
+function formatDuration(ms) {
+    const seconds = Math.round(ms / 1000);
+    return `${Math.floor(seconds / 60)}:${String(seconds % 60).padStart(2, '0')}`;
+}
+
+function formatFileSize(bytes) {
+    if (bytes < 1024) return `${bytes} B`;
+    if (bytes < 1024 * 1024) return `${(bytes / 1024).toFixed(1)} KB`;
+    return `${(bytes / (1024 * 1024)).toFixed(1)} MB`;
+}
+
+// Shows images as previews, audio as a player and other files as download links.
+function renderAttachments(msgDiv, attachments) {
+    if (attachments.length === 0) return;
+    const list = document.createElement('div');
+    list.className = 'attachments';
+    attachments.forEach(attachment => {
+        if (attachment.mime_type.startsWith('audio/')) {
+            const player = document.createElement('div');
+            player.className = 'attachment-audio';
+            const label = document.createElement('span');
+            label.textContent = attachment.duration_ms != null
+                ? `${t('voiceNote')} (${formatDuration(attachment.duration_ms)})`
+                : attachment.filename;
+            const audio = document.createElement('audio');
+            audio.controls = true;
+            audio.preload = 'none';
+            audio.src = `/attachments/${attachment.id}`;
+            player.append(label, audio);
+            list.appendChild(player);
+            return;
+        }
+        const link = document.createElement('a');
+        link.href = `/attachments/${attachment.id}`;
+        link.target = '_blank';
+        link.rel = 'noopener';
+        if (attachment.thumbnail_url) {
+            const image = document.createElement('img');
+            image.className = 'attachment-image';
+            image.src = attachment.thumbnail_url;
+            image.alt = attachment.filename;
+            image.loading = 'lazy';
+            // Reserve the space up front so the chat does not jump when the preview loads.
+            const scale = Math.min(1, ATTACHMENT_PREVIEW_MAX / Math.max(attachment.width, attachment.height));
+            image.width = Math.round(attachment.width * scale);
+            image.height = Math.round(attachment.height * scale);
+            link.appendChild(image);
+        } else {
+            link.className = 'attachment-file';
+            link.download = attachment.filename;
+            link.innerHTML = '<i class="fas fa-file"></i> ';
+            link.append(`${attachment.filename} (${formatFileSize(attachment.size)})`);
+        }
+        list.appendChild(link);
+    });
+    msgDiv.querySelector('.content').after(list);
+}
+
+// Shows one chip per emoji with its count. Chips of our own reactions are highlighted.
+function renderReactions(msgDiv, reactions) {
+    let row = msgDiv.querySelector('.reactions');
+    if (!row) {
+        row = document.createElement('div');
+        row.className = 'reactions';
+        msgDiv.appendChild(row);
+    }
+    row.innerHTML = '';
+    reactions.forEach(reaction => {
+        const chip = document.createElement('button');
+        chip.className = 'reaction';
+        chip.classList.toggle('mine', reaction.reacted);
+        chip.dataset.emoji = reaction.emoji;
+        chip.textContent = `${reaction.emoji} ${reaction.count}`;
+        row.appendChild(chip);
+    });
+}
+
+function applyReactionUpdate(update) {
+    const msgDiv = document.querySelector(`#chat-messages [data-message-id="${update.message_id}"]`);
+    if (msgDiv && !msgDiv.classList.contains('deleted')) renderReactions(msgDiv, update.reactions);
+}
+
+function toggleReactionPicker(msgDiv) {
+    const existing = msgDiv.querySelector('.reaction-picker');
+    if (existing) {
+        existing.remove();
+        return;
+    }
+    const picker = document.createElement('div');
+    picker.className = 'reaction-picker';
+    QUICK_REACTIONS.forEach(emoji => {
+        const option = document.createElement('button');
+        option.className = 'reaction-option';
+        option.dataset.emoji = emoji;
+        option.textContent = emoji;
+        picker.appendChild(option);
+    });
+    msgDiv.appendChild(picker);
+}
+
+// Shows which message this one answers, above its content.
+function addReplyQuote(msgDiv, replyTo, preview) {
+    const quote = document.createElement('div');
+    quote.className = 'reply-quote';
+    quote.dataset.replyTo = replyTo;
+    quote.title = t('showThread');
+    if (!preview || preview.deleted) {
+        quote.textContent = t('replyToDeleted');
+    } else {
+        quote.textContent = `${preview.sender_username}: ${preview.content}`;
+    }
+    msgDiv.prepend(quote);
+}
+
+// Anyone can reply to a message or open its thread. Own messages can also be edited
+// and deleted, admins and room moderators can delete any message.
+function addMessageActions(msgDiv, senderUsername) {
+    if (!currentUser) return;
+    const isOwn = senderUsername === currentUser.username;
+    const canModerate = currentUser.role === 'admin' || currentRoomRole !== 'member';
+
+    const actions = document.createElement('span');
+    actions.className = 'message-actions';
+    actions.innerHTML = `
+        <button class="reply-message-btn" title="${t('replyToMessage')}"><i class="fas fa-reply"></i></button>
+        <button class="view-thread-btn" title="${t('showThread')}"><i class="fas fa-stream"></i></button>
+        <button class="add-reaction-btn" title="${t('addReaction')}"><i class="far fa-smile"></i></button>
+    `;
+    if (isOwn) {
+        actions.innerHTML += `<button class="edit-message-btn" title="${t('editMessage')}"><i class="fas fa-pen"></i></button>`;
+    }
+    if (isOwn || canModerate) {
+        actions.innerHTML += `<button class="delete-message-btn" title="${t('deleteMessage')}"><i class="fas fa-trash"></i></button>`;
+    }
+    msgDiv.appendChild(actions);
+}
+
+// Shows a message and its replies in the thread panel.
+function renderThread(thread) {
+    const threadMessages = document.getElementById('thread-messages');
+    threadMessages.innerHTML = '';
+    [thread.root, ...thread.replies].forEach(message => {
+        const msgDiv = createChatMessageElement(message);
+        msgDiv.querySelectorAll('.message-actions').forEach(el => el.remove());
+        msgDiv.querySelectorAll('.reaction').forEach(chip => { chip.disabled = true; });
+        threadMessages.appendChild(msgDiv);
+    });
+    document.getElementById('thread-panel').classList.remove('hidden');
+}
+
+function setMessageEdited(msgDiv) {
+    if (msgDiv.querySelector('.edited-marker')) return;
+    const marker = document.createElement('span');
+    marker.className = 'edited-marker';
+    marker.title = t('showEditHistory');
+    marker.textContent = t('messageEdited');
+    msgDiv.querySelector('.content').after(marker);
+}
+
+function setMessageDeleted(msgDiv) {
+    msgDiv.classList.add('deleted');
+    msgDiv.querySelector('.content').textContent = t('messageDeleted');
+    msgDiv.querySelectorAll('.edited-marker, .message-actions, .reactions, .reaction-picker, .attachments').forEach(el => el.remove());
+}
+
+function applyMessageEdit(edit) {
+    const msgDiv = document.querySelector(`#chat-messages [data-message-id="${edit.id}"]`);
+    if (!msgDiv || msgDiv.classList.contains('deleted')) return;
+
+    msgDiv.querySelector('.content').textContent = edit.content;
+    setMessageEdited(msgDiv);
+}
+
+function applyMessageDeletion(deletion) {
+    const msgDiv = document.querySelector(`#chat-messages [data-message-id="${deletion.id}"]`);
+    if (msgDiv) setMessageDeleted(msgDiv);
+}
+
+// Marks the newest of our own messages that the other participant has read as seen.
+function applyReadReceipt(receipt) {
+    const chatMessages = document.getElementById('chat-messages');
+    const seen = [...chatMessages.querySelectorAll('.chat-message[data-message-id]:not(.deleted)')]
+        .filter(msgDiv => msgDiv.dataset.sender === currentUser.username && Number(msgDiv.dataset.messageId) <= receipt.message_id)
+        .at(-1);
+    if (!seen) return;
+
+    chatMessages.querySelectorAll('.read-receipt').forEach(el => el.remove());
+    const marker = document.createElement('span');
+    marker.className = 'read-receipt';
+    marker.textContent = t('messageSeen');
+    seen.appendChild(marker);
+}
+
+// Shows a message we sent but the server has not confirmed yet. `replyTo` is the
+// answered message as { id, sender_username, content }, if any.
+function addPendingChatMessage(requestId, username, content, replyTo = null, attachments = []) {
+    const chatMessages = document.getElementById('chat-messages');
+    const msgDiv = document.createElement('div');
+    msgDiv.classList.add('chat-message', 'pending');
+    msgDiv.dataset.sender = username;
+    if (requestId !== null) msgDiv.dataset.requestId = requestId;
+
+    msgDiv.innerHTML = `
+        <span class="timestamp">[${new Date().toLocaleTimeString()}]</span>
+        <span class="username"></span>
+        <span class="content"></span>
+        <span class="status">${t('messagePending')}</span>
+    `;
+    msgDiv.querySelector('.username').textContent = `${username}:`;
+    msgDiv.querySelector('.content').textContent = content;
+    if (replyTo) addReplyQuote(msgDiv, replyTo.id, replyTo);
+    renderAttachments(msgDiv, attachments);
     chatMessages.appendChild(msgDiv);
     chatMessages.scrollTop = chatMessages.scrollHeight;
+
+    if (requestId === null) {
+        setChatMessageFailed(msgDiv);
+    } else {
+        setTimeout(() => {
+            if (msgDiv.classList.contains('pending')) setChatMessageFailed(msgDiv);
+        }, PENDING_TIMEOUT_MS);
+    }
+}
+
+function markChatMessageSent(requestId, ack) {
+    const msgDiv = document.querySelector(`#chat-messages [data-request-id="${requestId}"]`);
+    if (!msgDiv) return;
+
+    msgDiv.classList.remove('pending', 'failed');
+    msgDiv.dataset.messageId = ack.id;
+    msgDiv.querySelector('.timestamp').textContent = `[${new Date(ack.timestamp).toLocaleTimeString()}]`;
+    msgDiv.querySelector('.status').remove();
+    addMessageActions(msgDiv, currentUser.username);
+}
+
+// Returns true if the request was a pending chat message, which is now shown as failed.
+function markChatMessageFailed(requestId) {
+    const msgDiv = document.querySelector(`#chat-messages [data-request-id="${requestId}"].pending`);
+    if (!msgDiv) return false;
+
+    setChatMessageFailed(msgDiv);
+    return true;
+}
+
+function setChatMessageFailed(msgDiv) {
+    msgDiv.classList.remove('pending');
+    msgDiv.classList.add('failed');
+    msgDiv.querySelector('.status').textContent = t('messageFailed');
 }
 
 function showPage(pageId) {
@@ -41,4 +342,4 @@ function showPage(pageId) {
     }
 }
 
-export { showMessage, addChatMessage, showPage };
\ No newline at end of file
+export { showMessage, formatFileSize, formatDuration, addChatMessage, prependChatMessages, addPendingChatMessage, markChatMessageSent, markChatMessageFailed, applyMessageEdit, applyMessageDeletion, applyReactionUpdate, toggleReactionPicker, applyReadReceipt, renderThread, setCurrentRoomRole, showPage };
\ No newline at end of file
@@ -2,6 +2,7 @@ import { playAudioChunk } from './audio.js';
 
 let ws;
 let currentUser;
+let nextRequestId = 1;
 
 function getWebSocket() {
     return ws;
@@ -34,14 +35,14 @@ function initWebSocket(onOpenCallback, handlers) {
         console.log('[WS] Received message:', msg);
 
         if (handlers[msg.type]) {
-            handlers[msg.type](msg.payload);
+            handlers[msg.type](msg.payload, msg.request_id);
         }
     };
 
     ws.onerror = (e) => {
         console.error('[WS] WebSocket error:', e);
-        if (handlers.error) {
-            handlers.error(e);
+        if (handlers.connection_error) {
+            handlers.connection_error(e);
         }
     };
     ws.onclose = (e) => {
@@ -53,12 +54,16 @@ function initWebSocket(onOpenCallback, handlers) {
     };
 }
 
+// Returns the request id the server will echo on its reply, or null if nothing was sent.
 function sendWsMessage(type, payload = {}) {
     if (ws && ws.readyState === WebSocket.OPEN) {
-        console.log(`[WS] Sending message: { type: '${type}', ... }`);
-        ws.send(JSON.stringify({ type, payload }));
+        const request_id = nextRequestId++;
+        console.log(`[WS] Sending message: { type: '${type}', request_id: ${request_id}, ... }`);
+        ws.send(JSON.stringify({ type, payload, request_id }));
+        return request_id;
     } else {
         console.error(`[WS] Could not send message. WebSocket is not open. State: ${ws ? ws.readyState : 'null'}`);
+        return null;
     }
 }
 
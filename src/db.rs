@@ -1,8 +1,9 @@
 use lazy_static::lazy_static;
-use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, OptionalExtension, Result};
-use serde::Serialize;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, OptionalExtension, Result, Transaction, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use uuid::Uuid;
 
 // A type alias for the connection pool.
@@ -26,11 +27,96 @@ pub struct User {
 pub struct ChatMessage {
     pub id: i32,
     pub room_id: i64,
+    #[serde(skip_serializing)]
+    pub sender_id: Option<i32>, // None for messages of deleted users
     pub sender_username: String,
+    pub content: String, // Empty once the message is deleted
+    pub timestamp: String,
+    pub edited_at: Option<String>,
+    pub deleted_at: Option<String>,
+    pub reply_to: Option<i32>,
+    pub reply_preview: Option<ReplyPreview>, // None if there is no reply_to
+    pub reactions: Vec<ReactionSummary>, // Filled in by `attach_reactions`
+    pub attachments: Vec<Attachment>, // Filled in by `attach_files`
+}
+
+/// A file uploaded over HTTP and sent with a message.
+#[derive(Debug, Serialize, Clone)]
+pub struct Attachment {
+    pub id: i64,
+    pub filename: String,
+    pub mime_type: String, // Sniffed from the content, not taken from the client
+    pub size: i64,
+    pub width: Option<u32>, // Set for images, as they are meant to be shown
+    pub height: Option<u32>,
+    pub thumbnail_url: Option<String>,
+    pub duration_ms: Option<u32>, // Set for voice notes
+}
+
+/// What was learned from an uploaded image or recording.
+#[derive(Default)]
+pub struct MediaDetails<'a> {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub thumbnail_mime: Option<&'a str>,
+    pub duration_ms: Option<u32>,
+}
+
+/// An attachment with what is needed to decide who may download it.
+#[derive(Debug)]
+pub struct StoredAttachment {
+    pub attachment: Attachment,
+    pub sha256: String,
+    pub thumbnail_mime: Option<String>,
+    pub uploader_id: i32,
+    pub room_id: Option<i64>, // None until it is sent with a message
+    pub message_deleted: bool,
+}
+
+/// A message that named the user with `@username`.
+#[derive(Debug, Serialize)]
+pub struct MentionInfo {
+    pub id: i64,
+    pub message_id: i32,
+    pub room_id: i64,
+    pub from_username: String,
     pub content: String,
     pub timestamp: String,
 }
 
+/// How many people reacted to a message with one emoji, from one viewer's perspective.
+#[derive(Debug, Serialize)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: u32,
+    pub reacted: bool, // Whether the viewer is among them
+}
+
+/// A short quote of the message a reply answers.
+#[derive(Debug, Serialize)]
+pub struct ReplyPreview {
+    pub sender_username: String,
+    pub content: String, // Shortened to PREVIEW_CHARS, empty if deleted
+    pub deleted: bool,
+}
+
+/// The latest message of a room, as shown in the chat list.
+#[derive(Debug, Serialize)]
+pub struct LastMessagePreview {
+    pub id: i32,
+    pub sender_username: String,
+    pub content: String, // Shortened to PREVIEW_CHARS, empty if deleted
+    pub timestamp: String,
+    pub deleted: bool,
+}
+
+/// A previous version of an edited message.
+#[derive(Debug, Serialize)]
+pub struct MessageEdit {
+    pub content: String,
+    pub edited_at: String, // When this version was replaced
+}
+
 #[derive(Debug, Serialize)]
 pub struct FriendRequestInfo {
     pub id: i32,
@@ -41,11 +127,96 @@ pub struct FriendRequestInfo {
     pub timestamp: String,
 }
 
+/// A participant's role in a room, from least to most privileged. Moderators can
+/// remove members, delete messages and change the topic; the owner also appoints
+/// moderators. Both sides of a one-on-one chat are members.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomRole {
+    Member,
+    Moderator,
+    Owner,
+}
+
+impl RoomRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RoomRole::Member => "member",
+            RoomRole::Moderator => "moderator",
+            RoomRole::Owner => "owner",
+        }
+    }
+}
+
+impl ToSql for RoomRole {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for RoomRole {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "member" => Ok(RoomRole::Member),
+            "moderator" => Ok(RoomRole::Moderator),
+            "owner" => Ok(RoomRole::Owner),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct RoomInfo {
     pub room_id: i64,
     pub name: Option<String>,
+    pub is_group: bool, // False for one-on-one chats
+    pub is_public: bool,
+    pub topic: Option<String>,
     pub participants: Vec<String>,
+    pub owner: Option<String>, // Usernames, only set for groups
+    pub moderators: Vec<String>,
+    pub unread_count: u32, // Messages from others after the user's read marker
+    pub last_message: Option<LastMessagePreview>,
+}
+
+/// An auth token as shown in the user's session list. The token itself is never exposed.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: i64,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteCode {
+    pub code: String,
+    pub created_by: Option<String>, // Username, None if the admin was deleted
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub max_uses: Option<i64>,
+    pub uses: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub id: i32,
+    pub room_id: i64,
+    pub sender_username: String,
+    pub timestamp: String,
+    pub snippet: String, // HTML-escaped, with matches wrapped in <mark>
+}
+
+/// A public group as listed in the room directory.
+#[derive(Debug, Serialize)]
+pub struct PublicRoomInfo {
+    pub room_id: i64,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub member_count: u32,
+    pub joined: bool, // Whether the listing user is already a member
 }
 
 #[derive(Debug, Serialize)]
@@ -57,27 +228,55 @@ pub struct AdminRoomInfo {
     pub participants: Vec<String>, // Usernames
 }
 
+/// A room as it is written into an export.
+#[derive(Debug, Serialize)]
+pub struct RoomArchiveInfo {
+    pub room_id: i64,
+    pub name: Option<String>,
+    pub is_group: bool,
+    pub is_public: bool,
+    pub topic: Option<String>,
+    pub created_at: String,
+    pub participants: Vec<RoomMember>,
+    pub message_count: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomMember {
+    pub username: String,
+    pub role: RoomRole,
+}
+
 lazy_static! {
     pub static ref DB_POOL: Pool = {
-        let manager = SqliteConnectionManager::file("app.db");
-        r2d2::Pool::new(manager).expect("Failed to create DB pool.")
+        r2d2::Pool::new(with_foreign_keys(SqliteConnectionManager::file("app.db"))).expect("Failed to create DB pool.")
     };
 }
 
+/// Turns on foreign keys for every connection the manager opens. SQLite keeps the setting
+/// per connection and most builds start with it off, so setting it once at startup would
+/// leave the rest of the pool without the `ON DELETE` actions.
+fn with_foreign_keys(manager: SqliteConnectionManager) -> SqliteConnectionManager {
+    manager.with_init(|c| c.execute_batch("PRAGMA foreign_keys=ON;"))
+}
+
 /// Initializes the database and creates tables if they don't exist.
 pub fn init_db() -> Result<()> {
     let conn = DB_POOL.get().expect("Failed to get DB connection from pool.");
+    conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+    create_schema(&conn)
+}
 
-    conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;")?;
-
+/// Creates or upgrades the tables on one connection.
+fn create_schema(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS users (\n            id              INTEGER PRIMARY KEY,\n            username        TEXT NOT NULL UNIQUE,\n            password_hash   TEXT NOT NULL,\n            role            TEXT NOT NULL DEFAULT 'normal'\n        )",
         [],
     )?;
 
     conn.execute(
-        "CREATE TABLE IF NOT EXISTS rooms (\n            id              INTEGER PRIMARY KEY AUTOINCREMENT,\n            name            TEXT, -- For group chats in the future
-            is_private      BOOLEAN NOT NULL DEFAULT TRUE, -- To distinguish 1-on-1 chats
+        "CREATE TABLE IF NOT EXISTS rooms (\n            id              INTEGER PRIMARY KEY AUTOINCREMENT,\n            name            TEXT, -- Set for group chats
+            is_private      BOOLEAN NOT NULL DEFAULT TRUE, -- Public groups are listed for anyone to join
             created_at      DATETIME DEFAULT CURRENT_TIMESTAMP
         )",
         [],
@@ -115,196 +314,1376 @@ pub fn init_db() -> Result<()> {
         )",
         [],
     )?;
-
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS invite_codes (
+            code            TEXT PRIMARY KEY,
+            created_by      INTEGER,
+            created_at      DATETIME DEFAULT CURRENT_TIMESTAMP,
+            expires_at      DATETIME, -- NULL never expires
+            max_uses        INTEGER,  -- NULL allows unlimited uses
+            uses            INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_edits (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id      INTEGER NOT NULL,
+            old_content     TEXT NOT NULL,
+            edited_at       DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_message_edits_message_id ON message_edits (message_id)", [])?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_reactions (
+            message_id      INTEGER NOT NULL,
+            user_id         INTEGER NOT NULL,
+            emoji           TEXT NOT NULL,
+            created_at      DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (message_id, user_id, emoji),
+            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS attachments (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            sha256          TEXT NOT NULL, -- Names the file in the upload directory
+            size            INTEGER NOT NULL,
+            mime_type       TEXT NOT NULL,
+            filename        TEXT NOT NULL,
+            uploader_id     INTEGER NOT NULL,
+            message_id      INTEGER, -- NULL until it is sent with a message
+            created_at      DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (uploader_id) REFERENCES users(id) ON DELETE CASCADE,
+            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_attachments_message_id ON attachments (message_id)", [])?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mentions (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id      INTEGER NOT NULL,
+            user_id         INTEGER NOT NULL,
+            created_at      DATETIME DEFAULT CURRENT_TIMESTAMP,
+            read_at         DATETIME, -- NULL while unread
+            UNIQUE(message_id, user_id),
+            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_mentions_user_id ON mentions (user_id, read_at)", [])?;
+
+    // History is paged backwards by id within a room.
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_messages_room_id ON messages (room_id, id)", [])?;
+
+    // Full-text index over message content. It stores no text of its own and is kept
+    // in sync with `messages` by triggers.
+    let fts_exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'messages_fts')",
+        [],
+        |row| row.get(0),
+    )?;
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(content, content='messages', content_rowid='id');
+        CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+            INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+        END;",
+    )?;
+    if !fts_exists {
+        // Index messages written before search existed.
+        conn.execute("INSERT INTO messages_fts (messages_fts) VALUES ('rebuild')", [])?;
+    }
+
+    // Columns added after the initial schema; existing databases are upgraded in place.
+    add_column_if_missing(conn, "auth_tokens", "last_used_at", "DATETIME")?;
+    add_column_if_missing(conn, "auth_tokens", "ip", "TEXT")?;
+    add_column_if_missing(conn, "auth_tokens", "user_agent", "TEXT")?;
+    add_column_if_missing(conn, "messages", "edited_at", "DATETIME")?;
+    add_column_if_missing(conn, "messages", "deleted_at", "DATETIME")?;
+    add_column_if_missing(conn, "messages", "reply_to", "INTEGER REFERENCES messages(id) ON DELETE SET NULL")?;
+    if add_column_if_missing(conn, "messages", "sender_id", "INTEGER REFERENCES users(id) ON DELETE SET NULL")? {
+        conn.execute(
+            "UPDATE messages SET sender_id = (SELECT id FROM users WHERE users.username = messages.sender_username)",
+            [],
+        )?;
+    }
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_messages_reply_to ON messages (reply_to)", [])?;
+    add_column_if_missing(conn, "users", "read_receipts_enabled", "BOOLEAN NOT NULL DEFAULT TRUE")?;
+    add_column_if_missing(conn, "rooms", "is_group", "BOOLEAN NOT NULL DEFAULT FALSE")?;
+    add_column_if_missing(conn, "rooms", "created_by", "INTEGER REFERENCES users(id) ON DELETE SET NULL")?;
+    add_column_if_missing(conn, "rooms", "topic", "TEXT")?;
+    add_column_if_missing(conn, "attachments", "width", "INTEGER")?;
+    add_column_if_missing(conn, "attachments", "height", "INTEGER")?;
+    add_column_if_missing(conn, "attachments", "thumbnail_mime", "TEXT")?; // NULL if there is no thumbnail
+    add_column_if_missing(conn, "attachments", "duration_ms", "INTEGER")?; // Set for voice notes
+    if add_column_if_missing(conn, "room_participants", "role", "TEXT NOT NULL DEFAULT 'member'")? {
+        // Groups made before roles existed belong to their creator.
+        conn.execute(
+            "UPDATE room_participants SET role = 'owner'
+             WHERE user_id = (SELECT created_by FROM rooms WHERE rooms.id = room_participants.room_id)",
+            [],
+        )?;
+    }
+    if add_column_if_missing(conn, "room_participants", "last_read_message_id", "INTEGER NOT NULL DEFAULT 0")? {
+        // Don't present the whole existing history as unread after upgrading.
+        conn.execute(
+            "UPDATE room_participants SET last_read_message_id =
+                 COALESCE((SELECT MAX(id) FROM messages WHERE messages.room_id = room_participants.room_id), 0)",
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Adds a column to an existing table unless it is already present. Returns true if
+/// the column was added.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(!exists)
+}
+
+// --- Auth Token Functions ---
+
+/// The outcome of looking up an auth token.
+pub enum TokenLookup {
+    Valid(User),
+    Expired,
+    Invalid,
+}
+
+pub fn create_auth_token(conn: &Connection, user_id: i32, ip: &str, user_agent: Option<&str>) -> Result<String> {
+    let token = Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO auth_tokens (token, user_id, last_used_at, ip, user_agent) VALUES (?1, ?2, CURRENT_TIMESTAMP, ?3, ?4)",
+        params![token, user_id, ip, user_agent],
+    )?;
+    Ok(token)
+}
+
+/// Resolves a token to its user. A token expires once it has gone unused for `ttl_secs`;
+/// expired tokens are deleted, valid ones have their lifetime extended.
+pub fn get_user_by_token(conn: &Connection, token: &str, ttl_secs: u64) -> Result<TokenLookup> {
+    let row = conn.query_row(
+        "SELECT u.id, u.username, u.password_hash, u.role,\n                COALESCE(t.last_used_at, t.created_at) < datetime('now', ?2) AS expired\n         FROM users u JOIN auth_tokens t ON u.id = t.user_id \n         WHERE t.token = ?1",
+        params![token, format!("-{} seconds", ttl_secs)],
+        |row| {
+            Ok((
+                User {
+                    _id: row.get(0)?,
+                    username: row.get(1)?,
+                    password_hash: row.get(2)?,
+                    role: row.get(3)?,
+                },
+                row.get::<_, bool>(4)?,
+            ))
+        },
+    ).optional()?;
+
+    match row {
+        Some((_, true)) => {
+            delete_auth_token(conn, token)?;
+            Ok(TokenLookup::Expired)
+        }
+        Some((user, false)) => {
+            touch_auth_token(conn, token)?;
+            Ok(TokenLookup::Valid(user))
+        }
+        None => Ok(TokenLookup::Invalid),
+    }
+}
+
+/// Marks a token as used now, sliding its expiry forward.
+pub fn touch_auth_token(conn: &Connection, token: &str) -> Result<usize> {
+    conn.execute(
+        "UPDATE auth_tokens SET last_used_at = CURRENT_TIMESTAMP WHERE token = ?1",
+        params![token],
+    )
+}
+
+/// Records the client that most recently used a token.
+pub fn update_auth_token_client(conn: &Connection, token: &str, ip: &str, user_agent: Option<&str>) -> Result<usize> {
+    conn.execute(
+        "UPDATE auth_tokens SET ip = ?2, user_agent = ?3 WHERE token = ?1",
+        params![token, ip, user_agent],
+    )
+}
+
+pub fn delete_auth_token(conn: &Connection, token: &str) -> Result<usize> {
+    conn.execute("DELETE FROM auth_tokens WHERE token = ?1", params![token])
+}
+
+/// Lists a user's active sessions, most recently used first.
+pub fn get_user_sessions(conn: &Connection, user_id: i32, current_token: &str) -> Result<Vec<SessionInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT rowid, created_at, last_used_at, ip, user_agent, token = ?2\n         FROM auth_tokens WHERE user_id = ?1\n         ORDER BY COALESCE(last_used_at, created_at) DESC"
+    )?;
+    let session_iter = stmt.query_map(params![user_id, current_token], |row| {
+        Ok(SessionInfo {
+            id: row.get(0)?,
+            created_at: row.get(1)?,
+            last_used_at: row.get(2)?,
+            ip: row.get(3)?,
+            user_agent: row.get(4)?,
+            current: row.get(5)?,
+        })
+    })?;
+    session_iter.collect::<Result<Vec<SessionInfo>>>()
+}
+
+/// Deletes one of a user's sessions and returns its token so live sockets can be closed.
+pub fn revoke_session(conn: &Connection, user_id: i32, session_id: i64) -> Result<Option<String>> {
+    let token: Option<String> = conn.query_row(
+        "SELECT token FROM auth_tokens WHERE rowid = ?1 AND user_id = ?2",
+        params![session_id, user_id],
+        |row| row.get(0),
+    ).optional()?;
+
+    if let Some(ref t) = token {
+        delete_auth_token(conn, t)?;
+    }
+    Ok(token)
+}
+
+/// Deletes every session of a user, returning the revoked tokens.
+pub fn revoke_all_sessions(conn: &Connection, user_id: i32) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT token FROM auth_tokens WHERE user_id = ?1")?;
+    let tokens = stmt.query_map(params![user_id], |row| row.get(0))?
+                     .collect::<Result<Vec<String>>>()?;
+
+    conn.execute("DELETE FROM auth_tokens WHERE user_id = ?1", params![user_id])?;
+    Ok(tokens)
+}
+
+/// Deletes every session of a user except `keep_token`, returning the revoked tokens.
+pub fn revoke_other_sessions(conn: &Connection, user_id: i32, keep_token: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT token FROM auth_tokens WHERE user_id = ?1 AND token != ?2")?;
+    let tokens = stmt.query_map(params![user_id, keep_token], |row| row.get(0))?
+                     .collect::<Result<Vec<String>>>()?;
+
+    conn.execute(
+        "DELETE FROM auth_tokens WHERE user_id = ?1 AND token != ?2",
+        params![user_id, keep_token],
+    )?;
+    Ok(tokens)
+}
+
+/// Removes every token that has gone unused for longer than `ttl_secs`.
+pub fn delete_expired_auth_tokens(conn: &Connection, ttl_secs: u64) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM auth_tokens WHERE COALESCE(last_used_at, created_at) < datetime('now', ?1)",
+        params![format!("-{} seconds", ttl_secs)],
+    )
+}
+
+
+// --- User Functions ---
+
+/// Creates an account with a password already hashed with `registration::hash_password`.
+pub fn create_user(conn: &Connection, username: &str, password_hash: &str, role: Option<&str>) -> Result<()> {
+    let role = if let Some(r) = role {
+        r
+    } else {
+        let user_count: i64 = conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
+        if user_count == 0 { "admin" } else { "normal" }
+    };
+
+    conn.execute(
+        "INSERT INTO users (username, password_hash, role) VALUES (?1, ?2, ?3)",
+        params![username, password_hash, role],
+    )?;
+
+    Ok(())
+}
+
+/// The outcome of a self-service registration.
+pub enum RegisterOutcome {
+    Created,
+    UsernameTaken,
+    InvalidInvite,
+    /// The account was to be the first, but another one was registered meanwhile.
+    NotFirst,
+}
+
+/// Returns true once at least one account exists.
+pub fn has_users(conn: &Connection) -> Result<bool> {
+    conn.query_row("SELECT EXISTS(SELECT 1 FROM users)", [], |row| row.get(0))
+}
+
+/// Returns true if the name is taken, ignoring case so look-alike accounts cannot be made.
+pub fn username_taken(conn: &Connection, username: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE username = ?1 COLLATE NOCASE)",
+        params![username],
+        |row| row.get(0),
+    )
+}
+
+/// Creates an account from the `register` message, redeeming the invite code in the same
+/// transaction. The first account on a fresh server becomes the admin. With `first`, the
+/// account is only created if there are still no others, since it skipped the usual checks.
+pub fn register_user(
+    conn: &Connection,
+    username: &str,
+    password_hash: &str,
+    invite_code: Option<&str>,
+    first: bool,
+) -> Result<RegisterOutcome> {
+    // Take the write lock up front, so no other registration can slip in between the
+    // checks below and the insert.
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+
+    let has_users: bool = tx.query_row("SELECT EXISTS(SELECT 1 FROM users)", [], |row| row.get(0))?;
+    if first && has_users {
+        return Ok(RegisterOutcome::NotFirst);
+    }
+
+    let taken: bool = tx.query_row(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE username = ?1 COLLATE NOCASE)",
+        params![username],
+        |row| row.get(0),
+    )?;
+    if taken {
+        return Ok(RegisterOutcome::UsernameTaken);
+    }
+
+    if let Some(code) = invite_code {
+        let redeemed = tx.execute(
+            "UPDATE invite_codes SET uses = uses + 1
+             WHERE code = ?1
+               AND (max_uses IS NULL OR uses < max_uses)
+               AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)",
+            params![code],
+        )?;
+        if redeemed == 0 {
+            return Ok(RegisterOutcome::InvalidInvite);
+        }
+    }
+
+    let role = if has_users { "normal" } else { "admin" };
+    tx.execute(
+        "INSERT INTO users (username, password_hash, role) VALUES (?1, ?2, ?3)",
+        params![username, password_hash, role],
+    )?;
+    tx.commit()?;
+
+    Ok(RegisterOutcome::Created)
+}
+
+pub fn get_user_by_username(conn: &Connection, username: &str) -> Result<User> {
+    conn.query_row(
+        "SELECT id, username, password_hash, role FROM users WHERE username = ?1",
+        params![username],
+        |row| {
+            Ok(User {
+                _id: row.get(0)?,
+                username: row.get(1)?,
+                password_hash: row.get(2)?,
+                role: row.get(3)?,
+            })
+        },
+    )
+}
+
+pub fn get_user_by_id(conn: &Connection, user_id: i32) -> Result<User> {
+    conn.query_row(
+        "SELECT id, username, password_hash, role FROM users WHERE id = ?1",
+        params![user_id],
+        |row| {
+            Ok(User {
+                _id: row.get(0)?,
+                username: row.get(1)?,
+                password_hash: row.get(2)?,
+                role: row.get(3)?,
+            })
+        },
+    )
+}
+
+/// Stores a new password, already hashed with `registration::hash_password`, for a user.
+pub fn set_user_password(conn: &Connection, user_id: i32, password_hash: &str) -> Result<usize> {
+    conn.execute(
+        "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+        params![password_hash, user_id],
+    )
+}
+
+/// Returns whether the user shares read receipts. Unknown users count as sharing.
+pub fn get_read_receipts_enabled(conn: &Connection, user_id: i32) -> Result<bool> {
+    conn.query_row("SELECT read_receipts_enabled FROM users WHERE id = ?1", params![user_id], |row| row.get(0))
+        .optional()
+        .map(|enabled| enabled.unwrap_or(true))
+}
+
+/// Turns the user's read receipts on or off.
+pub fn set_read_receipts_enabled(conn: &Connection, user_id: i32, enabled: bool) -> Result<usize> {
+    conn.execute(
+        "UPDATE users SET read_receipts_enabled = ?1 WHERE id = ?2",
+        params![enabled, user_id],
+    )
+}
+
+/// Retrieves all users from the database.
+pub fn get_all_users(conn: &Connection) -> Result<Vec<User>> {
+    let mut stmt = conn.prepare("SELECT id, username, password_hash, role FROM users")?;
+    let user_iter = stmt.query_map([], |row| {
+        Ok(User {
+            _id: row.get(0)?,
+            username: row.get(1)?,
+            password_hash: row.get(2)?,
+            role: row.get(3)?,
+        })
+    })?;
+    user_iter.collect::<Result<Vec<User>>>()
+}
+
+/// Deletes a user from the database by their ID.
+pub fn delete_user(conn: &Connection, user_id: i32) -> Result<usize> {
+    conn.execute("DELETE FROM users WHERE id = ?1", params![user_id])
+}
+
+// --- Invite Code Functions ---
+
+/// Mints a new invite code. `max_uses` of None allows unlimited registrations.
+pub fn create_invite_code(
+    conn: &Connection,
+    created_by: i32,
+    max_uses: Option<u32>,
+    expires_in_secs: Option<u64>,
+) -> Result<String> {
+    let code = Uuid::new_v4().simple().to_string()[..12].to_string();
+    conn.execute(
+        "INSERT INTO invite_codes (code, created_by, expires_at, max_uses)
+         VALUES (?1, ?2, CASE WHEN ?3 IS NULL THEN NULL ELSE datetime('now', ?3) END, ?4)",
+        params![code, created_by, expires_in_secs.map(|s| format!("+{} seconds", s)), max_uses],
+    )?;
+    Ok(code)
+}
+
+/// Lists every invite code, newest first, including used-up and expired ones.
+pub fn get_invite_codes(conn: &Connection) -> Result<Vec<InviteCode>> {
+    let mut stmt = conn.prepare(
+        "SELECT i.code, u.username, i.created_at, i.expires_at, i.max_uses, i.uses
+         FROM invite_codes i
+         LEFT JOIN users u ON u.id = i.created_by
+         ORDER BY i.created_at DESC, i.rowid DESC",
+    )?;
+    let invites = stmt.query_map([], |row| {
+        Ok(InviteCode {
+            code: row.get(0)?,
+            created_by: row.get(1)?,
+            created_at: row.get(2)?,
+            expires_at: row.get(3)?,
+            max_uses: row.get(4)?,
+            uses: row.get(5)?,
+        })
+    })?;
+    invites.collect()
+}
+
+pub fn revoke_invite_code(conn: &Connection, code: &str) -> Result<usize> {
+    conn.execute("DELETE FROM invite_codes WHERE code = ?1", params![code])
+}
+
+/// Updates a user's role in the database.
+#[allow(dead_code)]
+pub fn set_user_role(conn: &Connection, username: &str, role: &str) -> Result<usize> {
+    conn.execute(
+        "UPDATE users SET role = ?1 WHERE username = ?2",
+        params![role, username],
+    )
+}
+
+/// Retrieves all rooms and their participants for the admin panel.
+pub fn get_all_rooms(conn: &Connection) -> Result<Vec<AdminRoomInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, is_private, created_at FROM rooms ORDER BY created_at DESC",
+    )?;
+    let room_iter = stmt.query_map([], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+
+    let mut rooms_info = Vec::new();
+    for room_result in room_iter {
+        let (id, name, is_private, created_at): (i64, Option<String>, bool, String) = room_result?;
+
+        let mut participant_stmt = conn.prepare(
+            "SELECT u.username FROM users u JOIN room_participants rp ON u.id = rp.user_id WHERE rp.room_id = ?1",
+        )?;
+        let participant_iter = participant_stmt.query_map(params![id], |row| row.get(0))?;
+        let participants = participant_iter.collect::<Result<Vec<String>>>()?;
+
+        rooms_info.push(AdminRoomInfo {
+            id,
+            name,
+            is_private,
+            created_at,
+            participants,
+        });
+    }
+
+    Ok(rooms_info)
+}
+
+/// Deletes a room from the database by its ID.
+pub fn delete_room(conn: &Connection, room_id: i64) -> Result<usize> {
+    conn.execute("DELETE FROM rooms WHERE id = ?1", params![room_id])
+}
+
+// --- Message Functions ---
+
+// Longest quote of a message in reply and chat list previews.
+const PREVIEW_CHARS: usize = 100;
+
+fn preview_text(content: &str) -> String {
+    let mut preview: String = content.chars().take(PREVIEW_CHARS).collect();
+    if preview.len() < content.len() {
+        preview.push('…');
+    }
+    preview
+}
+
+// Selects messages as `m`, with the message they reply to as `parent`.
+const MESSAGE_SELECT: &str =
+    "SELECT m.id, m.room_id, m.sender_username, m.content, m.timestamp, m.edited_at, m.deleted_at, m.reply_to,
+            parent.sender_username, parent.content, parent.deleted_at, m.sender_id
+     FROM messages m
+     LEFT JOIN messages parent ON parent.id = m.reply_to";
+
+fn message_from_row(row: &rusqlite::Row) -> Result<ChatMessage> {
+    let parent_sender: Option<String> = row.get(8)?;
+    let reply_preview = match parent_sender {
+        Some(sender_username) => {
+            let content: String = row.get(9)?;
+            let deleted_at: Option<String> = row.get(10)?;
+            Some(ReplyPreview { sender_username, content: preview_text(&content), deleted: deleted_at.is_some() })
+        }
+        None => None,
+    };
+    Ok(ChatMessage {
+        id: row.get(0)?,
+        room_id: row.get(1)?,
+        sender_id: row.get(11)?,
+        sender_username: row.get(2)?,
+        content: row.get(3)?,
+        timestamp: row.get(4)?,
+        edited_at: row.get(5)?,
+        deleted_at: row.get(6)?,
+        reply_to: row.get(7)?,
+        reply_preview,
+        reactions: Vec::new(),
+        attachments: Vec::new(),
+    })
+}
+
+/// Stores a message together with the sender's unsent uploads listed in `attachment_ids`.
+pub fn create_message(conn: &mut Connection, room_id: i64, sender: &User, content: &str, reply_to: Option<i32>, attachment_ids: &[i64]) -> Result<ChatMessage> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO messages (room_id, sender_id, sender_username, content, reply_to) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![room_id, sender._id, sender.username, content, reply_to],
+    )?;
+    let last_id = tx.last_insert_rowid();
+    for attachment_id in attachment_ids {
+        let attached = tx.execute(
+            "UPDATE attachments SET message_id = ?1 WHERE id = ?2 AND uploader_id = ?3 AND message_id IS NULL",
+            params![last_id, attachment_id, sender._id],
+        )?;
+        if attached == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+    }
+
+    let mut message = tx.query_row(
+        &format!("{} WHERE m.id = ?1", MESSAGE_SELECT),
+        params![last_id],
+        message_from_row,
+    )?;
+    tx.commit()?;
+    attach_files(conn, std::slice::from_mut(&mut message))?;
+    Ok(message)
+}
+
+pub fn get_message(conn: &Connection, message_id: i32) -> Result<Option<ChatMessage>> {
+    conn.query_row(
+        &format!("{} WHERE m.id = ?1", MESSAGE_SELECT),
+        params![message_id],
+        message_from_row,
+    )
+    .optional()
+}
+
+/// Returns up to `limit` messages older than `before_id` (or the newest ones if None),
+/// oldest first, and whether there are more messages before them.
+pub fn get_messages_page(conn: &Connection, room_id: i64, before_id: Option<i32>, limit: u32) -> Result<(Vec<ChatMessage>, bool)> {
+    let mut stmt = conn.prepare(&format!(
+        "{}
+         WHERE m.room_id = ?1 AND (?2 IS NULL OR m.id < ?2)
+         ORDER BY m.id DESC
+         LIMIT ?3",
+        MESSAGE_SELECT
+    ))?;
+    // Fetch one extra row to learn whether another page exists.
+    let msg_iter = stmt.query_map(params![room_id, before_id, limit + 1], message_from_row)?;
+    let mut messages = msg_iter.collect::<Result<Vec<ChatMessage>>>()?;
+
+    let has_more = messages.len() > limit as usize;
+    messages.truncate(limit as usize);
+    messages.reverse();
+    Ok((messages, has_more))
+}
+
+/// Returns every message that replies to `root_id`, directly or through other replies,
+/// oldest first.
+pub fn get_thread_replies(conn: &Connection, root_id: i32) -> Result<Vec<ChatMessage>> {
+    let mut stmt = conn.prepare(&format!(
+        "WITH RECURSIVE thread(id) AS (
+             SELECT id FROM messages WHERE reply_to = ?1
+             UNION
+             SELECT messages.id FROM messages JOIN thread ON messages.reply_to = thread.id
+         )
+         {}
+         WHERE m.id IN (SELECT id FROM thread)
+         ORDER BY m.id",
+        MESSAGE_SELECT
+    ))?;
+    let msg_iter = stmt.query_map(params![root_id], message_from_row)?;
+    msg_iter.collect()
+}
+
+/// Calls `f` with every message of a room, oldest first, reading one row at a time.
+pub fn for_each_message_in_room<E: From<rusqlite::Error>>(
+    conn: &Connection,
+    room_id: i64,
+    mut f: impl FnMut(ChatMessage) -> std::result::Result<(), E>,
+) -> std::result::Result<(), E> {
+    let mut stmt = conn.prepare(&format!("{} WHERE m.room_id = ?1 ORDER BY m.id", MESSAGE_SELECT))?;
+    for message in stmt.query_map(params![room_id], message_from_row)? {
+        f(message?)?;
+    }
+    Ok(())
+}
+
+/// Replaces a message's content, keeping the previous version in `message_edits`.
+pub fn edit_message(conn: &mut Connection, message_id: i32, content: &str) -> Result<ChatMessage> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO message_edits (message_id, old_content) SELECT id, content FROM messages WHERE id = ?1",
+        params![message_id],
+    )?;
+    tx.execute(
+        "UPDATE messages SET content = ?1, edited_at = CURRENT_TIMESTAMP WHERE id = ?2",
+        params![content, message_id],
+    )?;
+    let message = tx.query_row(
+        &format!("{} WHERE m.id = ?1", MESSAGE_SELECT),
+        params![message_id],
+        message_from_row,
+    )?;
+    tx.commit()?;
+    Ok(message)
+}
+
+/// Turns a message into a tombstone. Its content and edit history are discarded, the
+/// row stays so the conversation keeps its shape.
+pub fn delete_message(conn: &mut Connection, message_id: i32) -> Result<ChatMessage> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM message_edits WHERE message_id = ?1", params![message_id])?;
+    tx.execute("DELETE FROM message_reactions WHERE message_id = ?1", params![message_id])?;
+    tx.execute("DELETE FROM mentions WHERE message_id = ?1", params![message_id])?;
+    tx.execute("DELETE FROM attachments WHERE message_id = ?1", params![message_id])?;
+    tx.execute(
+        "UPDATE messages SET content = '', deleted_at = CURRENT_TIMESTAMP WHERE id = ?1",
+        params![message_id],
+    )?;
+    let message = tx.query_row(
+        &format!("{} WHERE m.id = ?1", MESSAGE_SELECT),
+        params![message_id],
+        message_from_row,
+    )?;
+    tx.commit()?;
+    Ok(message)
+}
+
+/// Returns the previous versions of a message, oldest first.
+pub fn get_message_edits(conn: &Connection, message_id: i32) -> Result<Vec<MessageEdit>> {
+    let mut stmt = conn.prepare(
+        "SELECT old_content, edited_at FROM message_edits WHERE message_id = ?1 ORDER BY id",
+    )?;
+    let edit_iter = stmt.query_map(params![message_id], |row| {
+        Ok(MessageEdit {
+            content: row.get(0)?,
+            edited_at: row.get(1)?,
+        })
+    })?;
+    edit_iter.collect()
+}
+
+/// Filters for a message search. Dates are anything SQLite's `datetime()` accepts.
+pub struct MessageSearch<'a> {
+    pub query: &'a str,
+    pub room_id: Option<i64>,
+    pub sender: Option<&'a str>,
+    pub after: Option<&'a str>,
+    pub before: Option<&'a str>,
+    pub before_id: Option<i32>,
+    pub limit: u32,
+}
+
+/// Turns free text into an FTS5 query: every word is quoted so operators in user input
+/// are matched literally, and the last word is treated as a prefix. Returns None if
+/// there is nothing to search for.
+pub fn fts_query(text: &str) -> Option<String> {
+    let mut terms: Vec<String> = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    terms.last_mut()?.push('*');
+    Some(terms.join(" "))
+}
+
+/// Returns whether SQLite can interpret `value` as a date/time.
+pub fn is_valid_datetime(conn: &Connection, value: &str) -> Result<bool> {
+    conn.query_row("SELECT datetime(?1) IS NOT NULL", params![value], |row| row.get(0))
+}
+
+/// Searches messages in rooms `user_id` belongs to, newest first. Returns the results
+/// and whether older matches exist.
+pub fn search_messages(conn: &Connection, user_id: i32, search: &MessageSearch) -> Result<(Vec<SearchResult>, bool)> {
+    let Some(match_expr) = fts_query(search.query) else {
+        return Ok((Vec::new(), false));
+    };
+    // \x02 and \x03 mark the matched terms so they survive HTML escaping below.
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.room_id, m.sender_username, m.timestamp,
+                snippet(messages_fts, 0, char(2), char(3), '…', 16)
+         FROM messages_fts
+         JOIN messages m ON m.id = messages_fts.rowid
+         JOIN room_participants rp ON rp.room_id = m.room_id AND rp.user_id = ?1
+         WHERE messages_fts MATCH ?2
+           AND (?3 IS NULL OR m.room_id = ?3)
+           AND (?4 IS NULL OR m.sender_username = ?4 COLLATE NOCASE)
+           AND (?5 IS NULL OR m.timestamp >= datetime(?5))
+           AND (?6 IS NULL OR m.timestamp < datetime(?6))
+           AND (?7 IS NULL OR m.id < ?7)
+         ORDER BY m.id DESC
+         LIMIT ?8",
+    )?;
+    let result_iter = stmt.query_map(
+        params![
+            user_id,
+            match_expr,
+            search.room_id,
+            search.sender,
+            search.after,
+            search.before,
+            search.before_id,
+            search.limit + 1
+        ],
+        |row| {
+            let raw: String = row.get(4)?;
+            Ok(SearchResult {
+                id: row.get(0)?,
+                room_id: row.get(1)?,
+                sender_username: row.get(2)?,
+                timestamp: row.get(3)?,
+                snippet: highlight_snippet(&raw),
+            })
+        },
+    )?;
+    let mut results = result_iter.collect::<Result<Vec<SearchResult>>>()?;
+
+    let has_more = results.len() > search.limit as usize;
+    results.truncate(search.limit as usize);
+    Ok((results, has_more))
+}
+
+fn highlight_snippet(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len() + 16);
+    for c in raw.chars() {
+        match c {
+            '\u{2}' => out.push_str("<mark>"),
+            '\u{3}' => out.push_str("</mark>"),
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// --- Reaction Functions ---
+
+/// Adds a reaction. Reacting twice with the same emoji has no further effect.
+pub fn add_reaction(conn: &Connection, message_id: i32, user_id: i32, emoji: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji) VALUES (?1, ?2, ?3)",
+        params![message_id, user_id, emoji],
+    )?;
     Ok(())
 }
 
-// --- Auth Token Functions ---
-
-pub fn create_auth_token(conn: &Connection, user_id: i32) -> Result<String> {
-    let token = Uuid::new_v4().to_string();
+pub fn remove_reaction(conn: &Connection, message_id: i32, user_id: i32, emoji: &str) -> Result<()> {
     conn.execute(
-        "INSERT INTO auth_tokens (token, user_id) VALUES (?1, ?2)",
-        params![token, user_id],
+        "DELETE FROM message_reactions WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3",
+        params![message_id, user_id, emoji],
     )?;
-    Ok(token)
+    Ok(())
 }
 
-pub fn get_user_by_token(conn: &Connection, token: &str) -> Result<User> {
-    conn.query_row(
-        "SELECT u.id, u.username, u.password_hash, u.role \n         FROM users u JOIN auth_tokens t ON u.id = t.user_id \n         WHERE t.token = ?1",
-        params![token],
-        |row| {
-            Ok(User {
-                _id: row.get(0)?,
-                username: row.get(1)?,
-                password_hash: row.get(2)?,
-                role: row.get(3)?,
-            })
-        },
-    )
+/// Returns who reacted to a message with what, as (emoji, user_id) in the order the
+/// reactions were added.
+pub fn get_reactions(conn: &Connection, message_id: i32) -> Result<Vec<(String, i32)>> {
+    let mut stmt = conn.prepare(
+        "SELECT emoji, user_id FROM message_reactions WHERE message_id = ?1 ORDER BY created_at, rowid",
+    )?;
+    let reaction_iter = stmt.query_map(params![message_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    reaction_iter.collect()
 }
 
+/// Groups a message's reactions by emoji, keeping the order in which each emoji was first used.
+pub fn summarize_reactions(reactions: &[(String, i32)], viewer_id: i32) -> Vec<ReactionSummary> {
+    let mut summaries: Vec<ReactionSummary> = Vec::new();
+    for (emoji, user_id) in reactions {
+        let index = match summaries.iter().position(|s| s.emoji == *emoji) {
+            Some(index) => index,
+            None => {
+                summaries.push(ReactionSummary { emoji: emoji.clone(), count: 0, reacted: false });
+                summaries.len() - 1
+            }
+        };
+        summaries[index].count += 1;
+        summaries[index].reacted |= *user_id == viewer_id;
+    }
+    summaries
+}
 
-// --- User Functions ---
+/// Fills in the reactions of already loaded messages as `viewer_id` sees them.
+pub fn attach_reactions(conn: &Connection, messages: &mut [ChatMessage], viewer_id: i32) -> Result<()> {
+    for message in messages.iter_mut() {
+        message.reactions = summarize_reactions(&get_reactions(conn, message.id)?, viewer_id);
+    }
+    Ok(())
+}
 
-pub fn create_user(conn: &Connection, username: &str, password: &str, role: Option<&str>) -> Result<()> {
-    let role = if let Some(r) = role {
-        r
-    } else {
-        let user_count: i64 = conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
-        if user_count == 0 { "admin" } else { "normal" }
-    };
+/// Returns who reacted to a message with what, as (emoji, username) in the order the
+/// reactions were added.
+pub fn get_reaction_usernames(conn: &Connection, message_id: i32) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.emoji, u.username FROM message_reactions r JOIN users u ON u.id = r.user_id
+         WHERE r.message_id = ?1 ORDER BY r.created_at, r.rowid",
+    )?;
+    let reaction_iter = stmt.query_map(params![message_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    reaction_iter.collect()
+}
 
-    let password_hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)
-        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+// --- Attachment Functions ---
 
+/// Records an upload whose content, and thumbnail if it is an image, is already stored under its SHA-256.
+pub fn create_attachment(conn: &Connection, sha256: &str, size: i64, mime_type: &str, filename: &str, uploader_id: i32, details: &MediaDetails) -> Result<Attachment> {
     conn.execute(
-        "INSERT INTO users (username, password_hash, role) VALUES (?1, ?2, ?3)",
-        params![username, password_hash, role],
+        "INSERT INTO attachments (sha256, size, mime_type, filename, uploader_id, width, height, thumbnail_mime, duration_ms)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![sha256, size, mime_type, filename, uploader_id, details.width, details.height, details.thumbnail_mime, details.duration_ms],
     )?;
+    let id = conn.last_insert_rowid();
+    Ok(Attachment {
+        id,
+        filename: filename.to_string(),
+        mime_type: mime_type.to_string(),
+        size,
+        width: details.width,
+        height: details.height,
+        thumbnail_url: details.thumbnail_mime.map(|_| thumbnail_url(id)),
+        duration_ms: details.duration_ms,
+    })
+}
 
-    Ok(())
+fn thumbnail_url(attachment_id: i64) -> String {
+    format!("/attachments/{}/thumbnail", attachment_id)
 }
 
-pub fn get_user_by_username(conn: &Connection, username: &str) -> Result<User> {
+// Selects the columns read by `attachment_from_row` from `attachments` as `a`.
+const ATTACHMENT_COLUMNS: &str = "a.id, a.filename, a.mime_type, a.size, a.width, a.height, a.thumbnail_mime, a.duration_ms";
+
+fn attachment_from_row(row: &rusqlite::Row) -> Result<Attachment> {
+    let id = row.get(0)?;
+    let thumbnail_mime: Option<String> = row.get(6)?;
+    Ok(Attachment {
+        id,
+        filename: row.get(1)?,
+        mime_type: row.get(2)?,
+        size: row.get(3)?,
+        width: row.get(4)?,
+        height: row.get(5)?,
+        thumbnail_url: thumbnail_mime.map(|_| thumbnail_url(id)),
+        duration_ms: row.get(7)?,
+    })
+}
+
+/// Looks up an attachment together with the room of the message it was sent with.
+pub fn get_stored_attachment(conn: &Connection, attachment_id: i64) -> Result<Option<StoredAttachment>> {
     conn.query_row(
-        "SELECT id, username, password_hash, role FROM users WHERE username = ?1",
-        params![username],
+        &format!(
+            "SELECT {}, a.sha256, a.uploader_id, m.room_id, m.deleted_at IS NOT NULL
+             FROM attachments a
+             LEFT JOIN messages m ON m.id = a.message_id
+             WHERE a.id = ?1",
+            ATTACHMENT_COLUMNS
+        ),
+        params![attachment_id],
         |row| {
-            Ok(User {
-                _id: row.get(0)?,
-                username: row.get(1)?,
-                password_hash: row.get(2)?,
-                role: row.get(3)?,
+            Ok(StoredAttachment {
+                attachment: attachment_from_row(row)?,
+                thumbnail_mime: row.get(6)?,
+                sha256: row.get(8)?,
+                uploader_id: row.get(9)?,
+                room_id: row.get(10)?,
+                message_deleted: row.get::<_, Option<bool>>(11)?.unwrap_or(false),
             })
         },
     )
+    .optional()
 }
 
-/// Retrieves all users from the database.
-pub fn get_all_users(conn: &Connection) -> Result<Vec<User>> {
-    let mut stmt = conn.prepare("SELECT id, username, password_hash, role FROM users")?;
-    let user_iter = stmt.query_map([], |row| {
-        Ok(User {
-            _id: row.get(0)?,
-            username: row.get(1)?,
-            password_hash: row.get(2)?,
-            role: row.get(3)?,
-        })
-    })?;
-    Ok(user_iter.collect::<Result<Vec<User>>>()?) 
-}
-
-/// Deletes a user from the database by their ID.
-pub fn delete_user(conn: &Connection, user_id: i32) -> Result<usize> {
-    conn.execute("DELETE FROM users WHERE id = ?1", params![user_id])
+/// Fills in the attachments of already loaded messages. Deleted messages keep none.
+pub fn attach_files(conn: &Connection, messages: &mut [ChatMessage]) -> Result<()> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM attachments a WHERE a.message_id = ?1 ORDER BY a.id",
+        ATTACHMENT_COLUMNS
+    ))?;
+    for message in messages.iter_mut().filter(|m| m.deleted_at.is_none()) {
+        message.attachments = stmt.query_map(params![message.id], attachment_from_row)?.collect::<Result<_>>()?;
+    }
+    Ok(())
 }
 
-/// Updates a user's role in the database.
-pub fn set_user_role(conn: &Connection, username: &str, role: &str) -> Result<usize> {
+/// Removes uploads that were not sent within `unsent_ttl_secs`, and any left on deleted
+/// messages. Their files stay until `get_attachment_hashes` no longer lists them.
+pub fn delete_orphaned_attachments(conn: &Connection, unsent_ttl_secs: u64) -> Result<usize> {
     conn.execute(
-        "UPDATE users SET role = ?1 WHERE username = ?2",
-        params![role, username],
+        "DELETE FROM attachments
+         WHERE (message_id IS NULL AND created_at < datetime('now', ?1))
+            OR message_id IN (SELECT id FROM messages WHERE deleted_at IS NOT NULL)",
+        params![format!("-{} seconds", unsent_ttl_secs)],
     )
 }
 
-/// Retrieves all rooms and their participants for the admin panel.
-pub fn get_all_rooms(conn: &Connection) -> Result<Vec<AdminRoomInfo>> {
+/// Returns the content hashes of every stored attachment, which name their files.
+pub fn get_attachment_hashes(conn: &Connection) -> Result<HashSet<String>> {
+    let mut stmt = conn.prepare("SELECT DISTINCT sha256 FROM attachments")?;
+    let hashes = stmt.query_map([], |row| row.get(0))?.collect();
+    hashes
+}
+
+// --- Mention Functions ---
+
+/// Records a mention for every named user who takes part in the room, except the sender.
+/// Returns the new mentions as (mention id, user id).
+pub fn record_mentions(conn: &Connection, message_id: i32, room_id: i64, sender_id: i32, usernames: &[String]) -> Result<Vec<(i64, i32)>> {
+    let mut recorded = Vec::new();
+    for username in usernames {
+        let user_id: Option<i32> = conn.query_row(
+            "SELECT u.id FROM users u
+             JOIN room_participants rp ON rp.user_id = u.id AND rp.room_id = ?2
+             WHERE u.username = ?1 COLLATE NOCASE AND u.id != ?3",
+            params![username, room_id, sender_id],
+            |row| row.get(0),
+        ).optional()?;
+        let Some(user_id) = user_id else { continue };
+
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO mentions (message_id, user_id) VALUES (?1, ?2)",
+            params![message_id, user_id],
+        )?;
+        if inserted > 0 {
+            recorded.push((conn.last_insert_rowid(), user_id));
+        }
+    }
+    Ok(recorded)
+}
+
+/// Returns the user's unread mentions in rooms they still take part in, newest first.
+pub fn get_unread_mentions(conn: &Connection, user_id: i32) -> Result<Vec<MentionInfo>> {
     let mut stmt = conn.prepare(
-        "SELECT id, name, is_private, created_at FROM rooms ORDER BY created_at DESC",
+        "SELECT mn.id, m.id, m.room_id, m.sender_username, m.content, m.timestamp
+         FROM mentions mn
+         JOIN messages m ON m.id = mn.message_id
+         JOIN room_participants rp ON rp.room_id = m.room_id AND rp.user_id = mn.user_id
+         WHERE mn.user_id = ?1 AND mn.read_at IS NULL
+         ORDER BY mn.id DESC",
     )?;
-    let room_iter = stmt.query_map([], |row| {
-        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    let mention_iter = stmt.query_map(params![user_id], |row| {
+        Ok(MentionInfo {
+            id: row.get(0)?,
+            message_id: row.get(1)?,
+            room_id: row.get(2)?,
+            from_username: row.get(3)?,
+            content: row.get(4)?,
+            timestamp: row.get(5)?,
+        })
     })?;
+    mention_iter.collect()
+}
 
-    let mut rooms_info = Vec::new();
-    for room_result in room_iter {
-        let (id, name, is_private, created_at): (i64, Option<String>, bool, String) = room_result?;
+/// Marks the given mentions of the user as read, or all of them if `ids` is None.
+pub fn mark_mentions_read(conn: &Connection, user_id: i32, ids: Option<&[i64]>) -> Result<()> {
+    match ids {
+        Some(ids) => {
+            for id in ids {
+                conn.execute(
+                    "UPDATE mentions SET read_at = CURRENT_TIMESTAMP WHERE id = ?1 AND user_id = ?2 AND read_at IS NULL",
+                    params![id, user_id],
+                )?;
+            }
+        }
+        None => {
+            conn.execute(
+                "UPDATE mentions SET read_at = CURRENT_TIMESTAMP WHERE user_id = ?1 AND read_at IS NULL",
+                params![user_id],
+            )?;
+        }
+    }
+    Ok(())
+}
 
-        let mut participant_stmt = conn.prepare(
-            "SELECT u.username FROM users u JOIN room_participants rp ON u.id = rp.user_id WHERE rp.room_id = ?1",
-        )?;
-        let participant_iter = participant_stmt.query_map(params![id], |row| row.get(0))?;
-        let participants = participant_iter.collect::<Result<Vec<String>>>()?;
+// --- Room & Friendship Functions ---
 
-        rooms_info.push(AdminRoomInfo {
-            id,
-            name,
-            is_private,
-            created_at,
-            participants,
-        });
-    }
+/// Gets all rooms for a given user, including a potential custom name and all participants.
+pub fn get_user_rooms(conn: &Connection, user_id: i32) -> Result<Vec<RoomInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.id\n         FROM rooms r\n         JOIN room_participants rp ON r.id = rp.room_id\n         WHERE rp.user_id = ?1 ORDER BY r.created_at DESC"
+    )?;
+    let room_ids = stmt.query_map(params![user_id], |row| row.get(0))?.collect::<Result<Vec<i64>>>()?;
 
+    let mut rooms_info = Vec::new();
+    for room_id in room_ids {
+        rooms_info.extend(get_room_info(conn, room_id, user_id)?);
+    }
     Ok(rooms_info)
 }
 
-/// Deletes a room from the database by its ID.
-pub fn delete_room(conn: &Connection, room_id: i64) -> Result<usize> {
-    conn.execute("DELETE FROM rooms WHERE id = ?1", params![room_id])
+/// Gets a room as shown in the user's chat list, or None if they don't take part in it.
+pub fn get_room_info(conn: &Connection, room_id: i64, user_id: i32) -> Result<Option<RoomInfo>> {
+    let room = conn.query_row(
+        "SELECT r.name, r.is_group, NOT r.is_private, r.topic
+         FROM rooms r
+         JOIN room_participants rp ON rp.room_id = r.id AND rp.user_id = ?2
+         WHERE r.id = ?1",
+        params![room_id, user_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    ).optional()?;
+    let Some((name, is_group, is_public, topic)) = room else { return Ok(None) };
+
+    let mut p_stmt = conn.prepare(
+        "SELECT u.username, rp.role FROM users u JOIN room_participants rp ON u.id = rp.user_id WHERE rp.room_id = ?1 ORDER BY u.username"
+    )?;
+    let members = p_stmt.query_map(params![room_id], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<Vec<(String, RoomRole)>>>()?;
+    let owner = members.iter().find(|(_, role)| *role == RoomRole::Owner).map(|(username, _)| username.clone());
+    let moderators = members.iter().filter(|(_, role)| *role == RoomRole::Moderator).map(|(username, _)| username.clone()).collect();
+    let participants = members.into_iter().map(|(username, _)| username).collect();
+    let (unread_count, last_message) = get_room_unread(conn, room_id, user_id)?;
+    Ok(Some(RoomInfo { room_id, name, is_group, is_public, topic, participants, owner, moderators, unread_count, last_message }))
 }
 
+/// Gets a room with all of its participants and their roles, regardless of who asks.
+pub fn get_room_archive_info(conn: &Connection, room_id: i64) -> Result<Option<RoomArchiveInfo>> {
+    let room = conn.query_row(
+        "SELECT name, is_group, NOT is_private, topic, created_at, (SELECT COUNT(*) FROM messages WHERE room_id = rooms.id)
+         FROM rooms WHERE id = ?1",
+        params![room_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)),
+    ).optional()?;
+    let Some((name, is_group, is_public, topic, created_at, message_count)) = room else { return Ok(None) };
 
+    let mut p_stmt = conn.prepare(
+        "SELECT u.username, rp.role FROM users u JOIN room_participants rp ON u.id = rp.user_id WHERE rp.room_id = ?1 ORDER BY u.username"
+    )?;
+    let participants = p_stmt
+        .query_map(params![room_id], |row| Ok(RoomMember { username: row.get(0)?, role: row.get(1)? }))?
+        .collect::<Result<Vec<RoomMember>>>()?;
+    Ok(Some(RoomArchiveInfo { room_id, name, is_group, is_public, topic, created_at, participants, message_count }))
+}
 
-// --- Message Functions ---
+/// Returns the current time in the format timestamps are stored in (UTC).
+pub fn current_timestamp(conn: &Connection) -> Result<String> {
+    conn.query_row("SELECT datetime('now')", [], |row| row.get(0))
+}
 
-pub fn create_message(conn: &Connection, room_id: i64, sender_username: &str, content: &str) -> Result<ChatMessage> {
-    conn.execute(
-        "INSERT INTO messages (room_id, sender_username, content) VALUES (?1, ?2, ?3)",
-        params![room_id, sender_username, content],
+/// Returns how many messages from others the user has not read in the room, and the
+/// room's latest message.
+pub fn get_room_unread(conn: &Connection, room_id: i64, user_id: i32) -> Result<(u32, Option<LastMessagePreview>)> {
+    let unread_count = conn.query_row(
+        "SELECT COUNT(*) FROM messages m
+         JOIN room_participants rp ON rp.room_id = m.room_id AND rp.user_id = ?2
+         WHERE m.room_id = ?1 AND m.id > rp.last_read_message_id
+           AND m.deleted_at IS NULL AND m.sender_id IS NOT ?2",
+        params![room_id, user_id],
+        |row| row.get(0),
     )?;
-
-    let last_id = conn.last_insert_rowid();
-    conn.query_row(
-        "SELECT id, room_id, sender_username, content, timestamp FROM messages WHERE id = ?1",
-        params![last_id],
+    let last_message = conn.query_row(
+        "SELECT id, sender_username, content, timestamp, deleted_at FROM messages
+         WHERE room_id = ?1 ORDER BY id DESC LIMIT 1",
+        params![room_id],
         |row| {
-            Ok(ChatMessage {
+            let content: String = row.get(2)?;
+            let deleted_at: Option<String> = row.get(4)?;
+            Ok(LastMessagePreview {
                 id: row.get(0)?,
-                room_id: row.get(1)?,
-                sender_username: row.get(2)?,
-                content: row.get(3)?,
-                timestamp: row.get(4)?,
+                sender_username: row.get(1)?,
+                content: preview_text(&content),
+                timestamp: row.get(3)?,
+                deleted: deleted_at.is_some(),
             })
         },
+    ).optional()?;
+    Ok((unread_count, last_message))
+}
+
+/// A user's read marker in a room after `mark_room_read`.
+pub struct ReadMarker {
+    pub message_id: i32,
+    pub advanced: bool, // False if the marker was already there
+}
+
+/// Moves the user's read marker in the room forward to `up_to`, or to the latest
+/// message if None. Returns None if the user does not take part in the room.
+pub fn mark_room_read(conn: &Connection, room_id: i64, user_id: i32, up_to: Option<i32>) -> Result<Option<ReadMarker>> {
+    let previous: Option<i32> = conn.query_row(
+        "SELECT last_read_message_id FROM room_participants WHERE room_id = ?1 AND user_id = ?2",
+        params![room_id, user_id],
+        |row| row.get(0),
+    ).optional()?;
+    let Some(previous) = previous else { return Ok(None) };
+
+    let message_id: i32 = conn.query_row(
+        "UPDATE room_participants SET last_read_message_id = MAX(
+             last_read_message_id,
+             COALESCE(MIN(?3, latest.id), latest.id)
+         )
+         FROM (SELECT COALESCE(MAX(id), 0) AS id FROM messages WHERE room_id = ?1) AS latest
+         WHERE room_id = ?1 AND user_id = ?2
+         RETURNING last_read_message_id",
+        params![room_id, user_id, up_to],
+        |row| row.get(0),
+    )?;
+    Ok(Some(ReadMarker { message_id, advanced: message_id > previous }))
+}
+
+/// Returns the read markers of the room's participants other than `user_id`, as
+/// (user id, username, last read message id).
+pub fn get_other_read_markers(conn: &Connection, room_id: i64, user_id: i32) -> Result<Vec<(i32, String, i32)>> {
+    let mut stmt = conn.prepare(
+        "SELECT u.id, u.username, rp.last_read_message_id
+         FROM room_participants rp JOIN users u ON u.id = rp.user_id
+         WHERE rp.room_id = ?1 AND rp.user_id != ?2",
+    )?;
+    let marker_iter = stmt.query_map(params![room_id, user_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    marker_iter.collect()
+}
+
+/// Returns whether the room is a one-on-one chat.
+pub fn is_one_on_one_room(conn: &Connection, room_id: i64) -> Result<bool> {
+    conn.query_row("SELECT NOT is_group FROM rooms WHERE id = ?1", params![room_id], |row| row.get(0))
+        .optional()
+        .map(|one_on_one| one_on_one.unwrap_or(false))
+}
+
+/// Returns whether the room is a group chat.
+pub fn is_group_room(conn: &Connection, room_id: i64) -> Result<bool> {
+    conn.query_row("SELECT is_group FROM rooms WHERE id = ?1", params![room_id], |row| row.get(0))
+        .optional()
+        .map(|is_group| is_group.unwrap_or(false))
+}
+
+/// Returns the user's role in the room, or None if they don't take part in it.
+pub fn get_room_role(conn: &Connection, room_id: i64, user_id: i32) -> Result<Option<RoomRole>> {
+    conn.query_row(
+        "SELECT role FROM room_participants WHERE room_id = ?1 AND user_id = ?2",
+        params![room_id, user_id],
+        |row| row.get(0),
+    ).optional()
+}
+
+/// Changes a participant's role. Returns false if they don't take part in the room.
+pub fn set_room_role(conn: &Connection, room_id: i64, user_id: i32, role: RoomRole) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE room_participants SET role = ?1 WHERE room_id = ?2 AND user_id = ?3",
+        params![role, room_id, user_id],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Hands the room over to another participant. The previous owner stays on as moderator.
+pub fn transfer_room_ownership(conn: &mut Connection, room_id: i64, from_user_id: i32, to_user_id: i32) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "UPDATE room_participants SET role = ?1 WHERE room_id = ?2 AND user_id = ?3",
+        params![RoomRole::Moderator, room_id, from_user_id],
+    )?;
+    tx.execute(
+        "UPDATE room_participants SET role = ?1 WHERE room_id = ?2 AND user_id = ?3",
+        params![RoomRole::Owner, room_id, to_user_id],
+    )?;
+    tx.commit()
+}
+
+/// Returns whether the room is a public group that anyone can join.
+pub fn is_public_room(conn: &Connection, room_id: i64) -> Result<bool> {
+    conn.query_row(
+        "SELECT is_group AND NOT is_private FROM rooms WHERE id = ?1",
+        params![room_id],
+        |row| row.get(0),
     )
+    .optional()
+    .map(|is_public| is_public.unwrap_or(false))
 }
 
-pub fn get_messages_for_room(conn: &Connection, room_id: i64) -> Result<Vec<ChatMessage>> {
-    let mut stmt = conn.prepare("SELECT id, room_id, sender_username, content, timestamp FROM messages WHERE room_id = ?1 ORDER BY timestamp ASC")?;
-    let msg_iter = stmt.query_map(params![room_id], |row| {
-        Ok(ChatMessage {
-            id: row.get(0)?,
-            room_id: row.get(1)?,
-            sender_username: row.get(2)?,
-            content: row.get(3)?,
-            timestamp: row.get(4)?,
+/// Lists public groups whose name or topic contains `query`, biggest first.
+pub fn list_public_rooms(conn: &Connection, user_id: i32, query: Option<&str>, limit: u32) -> Result<Vec<PublicRoomInfo>> {
+    // Match the query literally, not as a LIKE pattern.
+    let pattern = query.map(|q| format!("%{}%", q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")));
+    let mut stmt = conn.prepare(
+        "SELECT r.id, r.name, r.topic, COUNT(rp.user_id),
+                EXISTS(SELECT 1 FROM room_participants WHERE room_id = r.id AND user_id = ?1)
+         FROM rooms r
+         JOIN room_participants rp ON rp.room_id = r.id
+         WHERE r.is_group = TRUE AND r.is_private = FALSE
+           AND (?2 IS NULL OR r.name LIKE ?2 ESCAPE '\\' OR r.topic LIKE ?2 ESCAPE '\\')
+         GROUP BY r.id
+         ORDER BY COUNT(rp.user_id) DESC, r.id DESC
+         LIMIT ?3",
+    )?;
+    let room_iter = stmt.query_map(params![user_id, pattern, limit], |row| {
+        Ok(PublicRoomInfo {
+            room_id: row.get(0)?,
+            name: row.get(1)?,
+            topic: row.get(2)?,
+            member_count: row.get(3)?,
+            joined: row.get(4)?,
         })
     })?;
-    Ok(msg_iter.collect::<Result<Vec<ChatMessage>>>()?) 
+    room_iter.collect()
 }
 
-// --- Room & Friendship Functions ---
+/// Makes a group public, so it is listed and anyone can join, or invite-only again.
+pub fn set_room_public(conn: &Connection, room_id: i64, public: bool) -> Result<usize> {
+    conn.execute("UPDATE rooms SET is_private = ?1 WHERE id = ?2", params![!public, room_id])
+}
 
-/// Gets all rooms for a given user, including a potential custom name and all participants.
-pub fn get_user_rooms(conn: &Connection, user_id: i32) -> Result<Vec<RoomInfo>> {
-    let mut stmt = conn.prepare(
-        "SELECT r.id, r.name\n         FROM rooms r\n         JOIN room_participants rp ON r.id = rp.room_id\n         WHERE rp.user_id = ?1 ORDER BY r.created_at DESC"
+/// Creates a group room owned by its creator, with the given members as participants.
+pub fn create_group_room(conn: &mut Connection, name: &str, public: bool, creator_id: i32, member_ids: &[i32]) -> Result<i64> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO rooms (name, is_private, is_group, created_by) VALUES (?1, ?2, TRUE, ?3)",
+        params![name, !public, creator_id],
+    )?;
+    let room_id = tx.last_insert_rowid();
+    tx.execute(
+        "INSERT INTO room_participants (room_id, user_id, role) VALUES (?1, ?2, ?3)",
+        params![room_id, creator_id, RoomRole::Owner],
     )?;
-    let room_iter = stmt.query_map(params![user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    for user_id in member_ids {
+        tx.execute(
+            "INSERT OR IGNORE INTO room_participants (room_id, user_id) VALUES (?1, ?2)",
+            params![room_id, user_id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(room_id)
+}
 
-    let mut rooms_info = Vec::new();
-    for room_result in room_iter {
-        if let Ok((room_id, name)) = room_result {
-            let mut p_stmt = conn.prepare(
-                "SELECT u.username FROM users u JOIN room_participants rp ON u.id = rp.user_id WHERE rp.room_id = ?1 ORDER BY u.username"
-            )?;
-            let participants = p_stmt.query_map(params![room_id], |row| row.get(0))?.collect::<Result<Vec<String>>>()?;
-            rooms_info.push(RoomInfo { room_id, name, participants });
-        }
+pub fn rename_room(conn: &Connection, room_id: i64, name: &str) -> Result<usize> {
+    conn.execute("UPDATE rooms SET name = ?1 WHERE id = ?2", params![name, room_id])
+}
+
+/// Sets the room's topic, or clears it with None.
+pub fn set_room_topic(conn: &Connection, room_id: i64, topic: Option<&str>) -> Result<usize> {
+    conn.execute("UPDATE rooms SET topic = ?1 WHERE id = ?2", params![topic, room_id])
+}
+
+/// Adds a user to a room. Earlier messages don't count as unread for them. Returns
+/// false if they already take part in it.
+pub fn add_room_participant(conn: &Connection, room_id: i64, user_id: i32) -> Result<bool> {
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO room_participants (room_id, user_id, last_read_message_id)
+         VALUES (?1, ?2, (SELECT COALESCE(MAX(id), 0) FROM messages WHERE room_id = ?1))",
+        params![room_id, user_id],
+    )?;
+    Ok(added > 0)
+}
+
+/// Removes a user from a group room. If they owned it, the longest-standing moderator
+/// takes over, or the longest-standing member if there is none. A room nobody is left
+/// in is deleted. Returns false if the user did not take part in the room.
+pub fn remove_room_participant(conn: &mut Connection, room_id: i64, user_id: i32) -> Result<bool> {
+    let tx = conn.transaction()?;
+    let removed = tx.execute(
+        "DELETE FROM room_participants WHERE room_id = ?1 AND user_id = ?2",
+        params![room_id, user_id],
+    )?;
+    if removed > 0 {
+        tx.execute(
+            "UPDATE room_participants SET role = 'owner'
+             WHERE rowid = (SELECT rowid FROM room_participants WHERE room_id = ?1
+                            ORDER BY role = 'moderator' DESC, rowid LIMIT 1)
+               AND NOT EXISTS (SELECT 1 FROM room_participants WHERE room_id = ?1 AND role = 'owner')",
+            params![room_id],
+        )?;
+        tx.execute(
+            "DELETE FROM rooms WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM room_participants WHERE room_id = ?1)",
+            params![room_id],
+        )?;
     }
-    Ok(rooms_info)
+    tx.commit()?;
+    Ok(removed > 0)
+}
+
+/// Returns the ids of all users taking part in the room.
+pub fn get_room_participant_ids(conn: &Connection, room_id: i64) -> Result<Vec<i32>> {
+    let mut stmt = conn.prepare("SELECT user_id FROM room_participants WHERE room_id = ?1")?;
+    let id_iter = stmt.query_map(params![room_id], |row| row.get(0))?;
+    id_iter.collect()
+}
+
+/// Returns whether the user is a participant of the room.
+pub fn is_room_participant(conn: &Connection, room_id: i64, user_id: i32) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM room_participants WHERE room_id = ?1 AND user_id = ?2)",
+        params![room_id, user_id],
+        |row| row.get(0),
+    )
 }
 
 /// Finds a private room between two users, or creates one if it doesn't exist.
@@ -312,7 +1691,7 @@ pub fn get_or_create_private_room(conn: &mut Connection, user1_id: i32, user2_id
     let tx = conn.transaction()?;
 
     let room_id: Option<i64> = tx.query_row(
-        "SELECT rp1.room_id\n         FROM room_participants rp1\n         JOIN room_participants rp2 ON rp1.room_id = rp2.room_id\n         JOIN rooms r ON rp1.room_id = r.id\n         WHERE rp1.user_id = ?1 AND rp2.user_id = ?2 AND r.is_private = TRUE",
+        "SELECT rp1.room_id\n         FROM room_participants rp1\n         JOIN room_participants rp2 ON rp1.room_id = rp2.room_id\n         JOIN rooms r ON rp1.room_id = r.id\n         WHERE rp1.user_id = ?1 AND rp2.user_id = ?2 AND r.is_group = FALSE",
         params![user1_id, user2_id],
         |row| row.get(0),
     ).optional()?;
@@ -379,7 +1758,7 @@ pub fn get_friend_requests(conn: &Connection, user_id: i32) -> Result<Vec<Friend
             timestamp: row.get(5)?,
         })
     })?;
-    Ok(req_iter.collect::<Result<Vec<FriendRequestInfo>>>()?) 
+    req_iter.collect::<Result<Vec<FriendRequestInfo>>>()
 }
 
 pub fn accept_friend_request(conn: &mut Connection, request_id: i32) -> Result<Option<i32>> {
@@ -406,7 +1785,7 @@ pub fn accept_friend_request(conn: &mut Connection, request_id: i32) -> Result<O
 /// Separate helper to work within an existing transaction
 fn get_or_create_private_room_in_tx(tx: &rusqlite::Transaction, user1_id: i32, user2_id: i32) -> Result<i64> {
     let room_id: Option<i64> = tx.query_row(
-        "SELECT rp1.room_id\n         FROM room_participants rp1\n         JOIN room_participants rp2 ON rp1.room_id = rp2.room_id\n         JOIN rooms r ON rp1.room_id = r.id\n         WHERE rp1.user_id = ?1 AND rp2.user_id = ?2 AND r.is_private = TRUE",
+        "SELECT rp1.room_id\n         FROM room_participants rp1\n         JOIN room_participants rp2 ON rp1.room_id = rp2.room_id\n         JOIN rooms r ON rp1.room_id = r.id\n         WHERE rp1.user_id = ?1 AND rp2.user_id = ?2 AND r.is_group = FALSE",
         params![user1_id, user2_id],
         |row| row.get(0),
     ).optional()?;
@@ -482,12 +1861,23 @@ pub fn get_friends(conn: &Connection, user_id: i32) -> Result<Vec<User>> {
     user_iter.collect::<Result<Vec<User>>>()
 }
 
+/// Returns whether two users have accepted each other as friends.
+pub fn are_friends(conn: &Connection, user1_id: i32, user2_id: i32) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM friend_requests
+         WHERE ((from_user_id = ?1 AND to_user_id = ?2) OR (from_user_id = ?2 AND to_user_id = ?1))
+           AND status = 'accepted')",
+        params![user1_id, user2_id],
+        |row| row.get(0),
+    )
+}
+
 pub fn delete_friend(conn: &mut Connection, user1_id: i32, user2_id: i32) -> Result<()> {
     let tx = conn.transaction()?;
 
     // 1. Find the private room ID between the two users
     let room_id: Option<i64> = tx.query_row(
-        "SELECT rp1.room_id\n         FROM room_participants rp1\n         JOIN room_participants rp2 ON rp1.room_id = rp2.room_id\n         JOIN rooms r ON rp1.room_id = r.id\n         WHERE rp1.user_id = ?1 AND rp2.user_id = ?2 AND r.is_private = TRUE",
+        "SELECT rp1.room_id\n         FROM room_participants rp1\n         JOIN room_participants rp2 ON rp1.room_id = rp2.room_id\n         JOIN rooms r ON rp1.room_id = r.id\n         WHERE rp1.user_id = ?1 AND rp2.user_id = ?2 AND r.is_group = FALSE",
         params![user1_id, user2_id],
         |row| row.get(0),
     ).optional()?;
@@ -508,3 +1898,236 @@ pub fn delete_friend(conn: &mut Connection, user1_id: i32, user2_id: i32) -> Res
     tx.commit()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh in-memory database with the full schema.
+    fn test_connection() -> Connection {
+        let pool = r2d2::Pool::builder().max_size(1).build(with_foreign_keys(SqliteConnectionManager::memory())).unwrap();
+        let conn = pool.get().unwrap();
+        create_schema(&conn).unwrap();
+        conn
+    }
+
+    /// A database file that several pooled connections share, removed again when dropped.
+    struct TestPool {
+        pool: Pool,
+        path: std::path::PathBuf,
+    }
+
+    impl TestPool {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("simple_talk_db_{}.db", Uuid::new_v4()));
+            let manager = with_foreign_keys(SqliteConnectionManager::file(&path));
+            let pool = r2d2::Pool::builder().max_size(2).build(manager).unwrap();
+            create_schema(&pool.get().unwrap()).unwrap();
+            TestPool { pool, path }
+        }
+    }
+
+    impl Drop for TestPool {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn add_user(conn: &Connection, username: &str) -> User {
+        create_user(conn, username, "not a real hash", Some("normal")).unwrap();
+        get_user_by_username(conn, username).unwrap()
+    }
+
+    #[test]
+    fn mentions_are_recorded_for_participants_other_than_the_sender() {
+        let mut conn = test_connection();
+        let [alice, bob, carol, dave] = ["alice", "bob", "carol", "dave"].map(|name| add_user(&conn, name));
+        let room_id = create_group_room(&mut conn, "Team", false, alice._id, &[bob._id, carol._id]).unwrap();
+        let message = create_message(&mut conn, room_id, &alice, "@alice @Bob @carol @dave", None, &[]).unwrap();
+
+        let names: Vec<String> = ["alice", "Bob", "carol", "dave"].map(String::from).to_vec();
+        let recorded = record_mentions(&conn, message.id, room_id, alice._id, &names).unwrap();
+        let users: Vec<i32> = recorded.iter().map(|(_, user_id)| *user_id).collect();
+        assert_eq!(users, [bob._id, carol._id]);
+        assert!(get_unread_mentions(&conn, dave._id).unwrap().is_empty());
+        // Recording the same message again adds nothing.
+        assert!(record_mentions(&conn, message.id, room_id, alice._id, &names).unwrap().is_empty());
+    }
+
+    #[test]
+    fn mentions_are_hidden_once_the_user_leaves_the_room() {
+        let mut conn = test_connection();
+        let [alice, bob, carol] = ["alice", "bob", "carol"].map(|name| add_user(&conn, name));
+        let room_id = create_group_room(&mut conn, "Team", false, alice._id, &[bob._id, carol._id]).unwrap();
+        let message = create_message(&mut conn, room_id, &alice, "@bob @carol look", None, &[]).unwrap();
+        record_mentions(&conn, message.id, room_id, alice._id, &["bob".to_string(), "carol".to_string()]).unwrap();
+        assert_eq!(get_unread_mentions(&conn, bob._id).unwrap().len(), 1);
+
+        remove_room_participant(&mut conn, room_id, bob._id).unwrap();
+        edit_message(&mut conn, message.id, "@bob @carol edited after bob left").unwrap();
+        assert!(get_unread_mentions(&conn, bob._id).unwrap().is_empty());
+        assert_eq!(get_unread_mentions(&conn, carol._id).unwrap()[0].content, "@bob @carol edited after bob left");
+    }
+
+    #[test]
+    fn messages_stay_tied_to_the_sender_account_not_the_name() {
+        let db = TestPool::new();
+        let mut conn = db.pool.get().unwrap();
+        let [alice, bob] = ["alice", "bob"].map(|name| add_user(&conn, name));
+        let room_id = create_group_room(&mut conn, "Team", false, alice._id, &[bob._id]).unwrap();
+        let message = create_message(&mut conn, room_id, &bob, "hello", None, &[]).unwrap();
+        assert_eq!(get_message(&conn, message.id).unwrap().unwrap().sender_id, Some(bob._id));
+
+        // Deleted on another pooled connection, with the same name and id reused afterwards.
+        delete_user(&db.pool.get().unwrap(), bob._id).unwrap();
+        let impostor = add_user(&conn, "bob");
+        assert_eq!(impostor._id, bob._id);
+        let message = get_message(&conn, message.id).unwrap().unwrap();
+        assert_eq!(message.sender_username, "bob");
+        assert_eq!(message.sender_id, None);
+    }
+
+    #[test]
+    fn orphaned_attachments_are_removed() {
+        let mut conn = test_connection();
+        let [alice, bob] = ["alice", "bob"].map(|name| add_user(&conn, name));
+        let room_id = create_group_room(&mut conn, "Team", false, alice._id, &[bob._id]).unwrap();
+        let upload = |sha256: &str| create_attachment(&conn, sha256, 4, "text/plain", "a.txt", alice._id, &MediaDetails::default()).unwrap().id;
+        let [sent, deleted, fresh, stale] = ["sent", "deleted", "fresh", "stale"].map(upload);
+        create_message(&mut conn, room_id, &alice, "kept", None, &[sent]).unwrap();
+        let message = create_message(&mut conn, room_id, &alice, "gone", None, &[deleted]).unwrap();
+        conn.execute("UPDATE attachments SET created_at = datetime('now', '-2 days') WHERE id IN (?1, ?2, ?3)", params![sent, deleted, stale]).unwrap();
+
+        delete_message(&mut conn, message.id).unwrap();
+        assert!(get_stored_attachment(&conn, deleted).unwrap().is_none());
+        assert_eq!(delete_orphaned_attachments(&conn, 24 * 3600).unwrap(), 1);
+        assert!(get_stored_attachment(&conn, stale).unwrap().is_none());
+        assert!(get_stored_attachment(&conn, fresh).unwrap().is_some());
+        assert_eq!(get_attachment_hashes(&conn).unwrap(), HashSet::from(["sent".to_string(), "fresh".to_string()]));
+    }
+
+
+    #[test]
+    fn only_one_account_is_registered_as_the_first() {
+        let conn = test_connection();
+        assert!(matches!(register_user(&conn, "alice", "hash", None, true), Ok(RegisterOutcome::Created)));
+        assert_eq!(get_user_by_username(&conn, "alice").unwrap().role, "admin");
+
+        // A second registration that also saw an empty server must not skip the invite.
+        assert!(matches!(register_user(&conn, "bob", "hash", None, true), Ok(RegisterOutcome::NotFirst)));
+        assert!(get_user_by_username(&conn, "bob").is_err());
+        assert!(matches!(register_user(&conn, "bob", "hash", Some("nope"), false), Ok(RegisterOutcome::InvalidInvite)));
+        assert!(matches!(register_user(&conn, "Alice", "hash", None, false), Ok(RegisterOutcome::UsernameTaken)));
+        assert!(matches!(register_user(&conn, "bob", "hash", None, false), Ok(RegisterOutcome::Created)));
+        assert_eq!(get_user_by_username(&conn, "bob").unwrap().role, "normal");
+    }
+
+    #[test]
+    fn search_input_is_quoted_word_by_word() {
+        assert_eq!(fts_query("deploy fri").as_deref(), Some(r#""deploy" "fri"*"#));
+        assert_eq!(fts_query(r#"say "hi" NEAR -x"#).as_deref(), Some(r#""say" """hi""" "NEAR" "-x"*"#));
+        assert_eq!(fts_query("  \t "), None);
+    }
+
+    #[test]
+    fn search_operators_in_user_input_are_matched_literally() {
+        let mut conn = test_connection();
+        let [alice, bob] = ["alice", "bob"].map(|name| add_user(&conn, name));
+        let room_id = create_group_room(&mut conn, "Team", false, alice._id, &[bob._id]).unwrap();
+        let contents = [
+            "cats and dogs",
+            "cats do not like dogs",
+            "cats only",
+            "star power",
+            "starry power",
+            "a well known fact",
+            "we are nearly there",
+            r#"she said "hi" twice"#,
+        ];
+        for content in contents {
+            create_message(&mut conn, room_id, &alice, content, None, &[]).unwrap();
+        }
+        let search = |query: &str| -> Vec<&str> {
+            let search = MessageSearch { query, room_id: None, sender: None, after: None, before: None, before_id: None, limit: 50 };
+            let (results, _) = search_messages(&conn, bob._id, &search).unwrap();
+            let mut found: Vec<&str> = results.iter().map(|r| contents[r.id as usize - 1]).collect();
+            found.sort();
+            found
+        };
+
+        // As operators these would exclude, combine or require proximity; here they are just words.
+        assert_eq!(search("cats NOT dogs"), ["cats do not like dogs"]);
+        assert!(search("cats OR dogs").is_empty());
+        assert!(search("NEAR(cats dogs)").is_empty());
+        assert_eq!(search("NEAR"), ["we are nearly there"]);
+        assert_eq!(search("NEAR("), ["we are nearly there"]);
+        // Only the last word is a prefix, whether or not the user typed a `*`.
+        assert_eq!(search("star* power"), ["star power"]);
+        assert_eq!(search("sta"), ["star power", "starry power"]);
+        // Hyphens and quotes split words instead of negating or opening a phrase.
+        assert_eq!(search("well-known"), ["a well known fact"]);
+        assert_eq!(search("cats -dogs"), ["cats and dogs", "cats do not like dogs"]);
+        assert_eq!(search(r#""hi"#), [r#"she said "hi" twice"#]);
+        // Input made only of syntax finds nothing rather than failing.
+        for query in ["\"", "*", "-", "\"\" * -", "(", ":"] {
+            assert!(search(query).is_empty(), "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn deleting_a_user_on_any_pooled_connection_cascades() {
+        let db = TestPool::new();
+        let mut conn = db.pool.get().unwrap();
+        let [alice, bob] = ["alice", "bob"].map(|name| add_user(&conn, name));
+        let room_id = create_group_room(&mut conn, "Team", false, alice._id, &[bob._id]).unwrap();
+        let token = create_auth_token(&conn, bob._id, "127.0.0.1", None).unwrap();
+
+        // Holding `conn` makes the pool hand out a second connection.
+        assert_eq!(delete_user(&db.pool.get().unwrap(), bob._id).unwrap(), 1);
+        assert_eq!(get_room_participant_ids(&conn, room_id).unwrap(), [alice._id]);
+        let tokens: i64 = conn.query_row("SELECT COUNT(*) FROM auth_tokens", [], |row| row.get(0)).unwrap();
+        assert_eq!(tokens, 0);
+        // The next account may get the same id, but not the old session.
+        assert_eq!(add_user(&conn, "carol")._id, bob._id);
+        assert!(matches!(get_user_by_token(&conn, &token, 3600).unwrap(), TokenLookup::Invalid));
+    }
+
+    #[test]
+    fn a_group_left_by_its_last_member_is_deleted_with_its_history() {
+        let db = TestPool::new();
+        let mut conn = db.pool.get().unwrap();
+        let [alice, bob] = ["alice", "bob"].map(|name| add_user(&conn, name));
+        let room_id = create_group_room(&mut conn, "Team", false, alice._id, &[bob._id]).unwrap();
+        let attachment = create_attachment(&conn, "notes", 4, "text/plain", "a.txt", alice._id, &MediaDetails::default()).unwrap();
+        let message = create_message(&mut conn, room_id, &alice, "notes", None, &[attachment.id]).unwrap();
+        add_reaction(&conn, message.id, bob._id, "👍").unwrap();
+
+        // Both leave on another pooled connection than the one that created everything.
+        let mut other = db.pool.get().unwrap();
+        assert!(remove_room_participant(&mut other, room_id, alice._id).unwrap());
+        assert!(remove_room_participant(&mut other, room_id, bob._id).unwrap());
+        for table in ["rooms", "room_participants", "messages", "message_reactions", "attachments"] {
+            let rows: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap();
+            assert_eq!(rows, 0, "{} rows were left behind", table);
+        }
+        assert!(get_attachment_hashes(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unread_counts_skip_only_the_users_own_messages() {
+        let mut conn = test_connection();
+        let [alice, bob] = ["alice", "bob"].map(|name| add_user(&conn, name));
+        let room_id = create_group_room(&mut conn, "Team", false, alice._id, &[bob._id]).unwrap();
+        create_message(&mut conn, room_id, &bob, "from the first bob", None, &[]).unwrap();
+        create_message(&mut conn, room_id, &alice, "from alice", None, &[]).unwrap();
+        assert_eq!(get_room_unread(&conn, room_id, alice._id).unwrap().0, 1);
+        assert_eq!(get_room_unread(&conn, room_id, bob._id).unwrap().0, 1);
+
+        // A new account under the same name has not written the old one's messages.
+        delete_user(&conn, bob._id).unwrap();
+        let bob = add_user(&conn, "bob");
+        add_room_participant(&conn, room_id, bob._id).unwrap();
+        conn.execute("UPDATE room_participants SET last_read_message_id = 0 WHERE user_id = ?1", params![bob._id]).unwrap();
+        assert_eq!(get_room_unread(&conn, room_id, bob._id).unwrap().0, 2);
+    }
+}
@@ -0,0 +1,515 @@
+use crate::db;
+use crate::protocol::ErrorCode;
+use crate::uploads::{authenticate_download, encode_filename, error_response};
+use crate::AppState;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// The file types a room's history can be exported as.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON object per line: the export, then each room followed by its messages.
+    Jsonl,
+    /// A single static page with its styles inlined, readable without the server.
+    Html,
+    Text,
+}
+
+impl ExportFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Html => "html",
+            ExportFormat::Text => "text",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Html => "html",
+            ExportFormat::Text => "txt",
+        }
+    }
+
+    fn mime_type(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/x-ndjson; charset=utf-8",
+            ExportFormat::Html => "text/html; charset=utf-8",
+            ExportFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+/// Where a participant downloads the history of one room.
+pub fn room_export_url(room_id: i64, format: ExportFormat) -> String {
+    format!("/rooms/{}/export?format={}", room_id, format.as_str())
+}
+
+/// Where an admin downloads the history of every room.
+pub fn all_rooms_export_url(format: ExportFormat) -> String {
+    format!("/admin/export?format={}", format.as_str())
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    format: ExportFormat,
+}
+
+/// Who reacted to a message with one emoji.
+#[derive(Serialize)]
+struct ExportedReaction {
+    emoji: String,
+    users: Vec<String>,
+}
+
+/// Loads who reacted to a message, grouped by emoji.
+fn load_reactions(conn: &db::Connection, message_id: i32) -> rusqlite::Result<Vec<ExportedReaction>> {
+    let mut reactions: Vec<ExportedReaction> = Vec::new();
+    for (emoji, username) in db::get_reaction_usernames(conn, message_id)? {
+        match reactions.iter_mut().find(|r| r.emoji == emoji) {
+            Some(reaction) => reaction.users.push(username),
+            None => reactions.push(ExportedReaction { emoji, users: vec![username] }),
+        }
+    }
+    Ok(reactions)
+}
+
+/// Group name, or the participants of a one-on-one chat.
+fn room_title(room: &db::RoomArchiveInfo) -> String {
+    match &room.name {
+        Some(name) => name.clone(),
+        None => {
+            let usernames: Vec<&str> = room.participants.iter().map(|p| p.username.as_str()).collect();
+            format!("Chat between {}", usernames.join(" and "))
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonlLine<'a> {
+    Export { exported_by: &'a str, exported_at: &'a str, room_count: usize },
+    Room(&'a db::RoomArchiveInfo),
+    Message {
+        id: i32,
+        room_id: i64,
+        sender_username: &'a str,
+        content: &'a str, // Empty once the message is deleted
+        timestamp: &'a str,
+        edited_at: Option<&'a str>,
+        deleted_at: Option<&'a str>,
+        reply_to: Option<i32>,
+        attachments: &'a [db::Attachment],
+        reactions: &'a [ExportedReaction],
+    },
+}
+
+fn format_size(bytes: i64) -> String {
+    match bytes {
+        b if b < 1024 => format!("{} B", b),
+        b if b < 1024 * 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+fn describe_attachment(attachment: &db::Attachment) -> String {
+    match attachment.duration_ms {
+        Some(ms) => format!("{} (voice note, {}:{:02})", attachment.filename, ms / 60000, ms / 1000 % 60),
+        None => format!("{} ({}, {})", attachment.filename, attachment.mime_type, format_size(attachment.size)),
+    }
+}
+
+fn describe_participants(room: &db::RoomArchiveInfo) -> String {
+    let names: Vec<String> = room
+        .participants
+        .iter()
+        .map(|p| match p.role {
+            db::RoomRole::Member => p.username.clone(),
+            role => format!("{} ({})", p.username, role.as_str()),
+        })
+        .collect();
+    names.join(", ")
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+const HTML_STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:50em;margin:2em auto;padding:0 1em;color:#222}
+header,section>dl{color:#555}
+section{border-top:1px solid #ccc;margin-top:2em}
+dt{font-weight:bold;float:left;clear:left;width:8em}
+dd{margin-left:8em}
+ol{list-style:none;padding:0}
+li{padding:.5em 0;border-bottom:1px solid #eee}
+li:target{background:#ffd}
+.meta{font-size:.85em;color:#666}
+.sender{font-weight:bold;color:#222}
+.content{white-space:pre-wrap;overflow-wrap:anywhere;margin:.25em 0}
+.deleted{font-style:italic;color:#888}
+.extra{font-size:.85em;color:#555;margin:.1em 0}";
+
+// Rendered output is sent on once this much has built up.
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Why an export stopped before it was complete.
+enum ExportError {
+    Database(rusqlite::Error),
+    /// The download was cancelled.
+    Disconnected,
+}
+
+impl From<rusqlite::Error> for ExportError {
+    fn from(e: rusqlite::Error) -> Self {
+        ExportError::Database(e)
+    }
+}
+
+/// Renders an export piece by piece and sends it on in chunks, so only about one chunk
+/// of it is held in memory at a time.
+struct ExportWriter {
+    format: ExportFormat,
+    exported_by: String,
+    /// Where the export was downloaded from, so attachment links work from a saved file.
+    server_url: String,
+    out: String,
+    sent_any: bool,
+    tx: mpsc::Sender<std::io::Result<Bytes>>,
+}
+
+impl ExportWriter {
+    fn flush(&mut self) -> Result<(), ExportError> {
+        if self.out.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.out));
+        self.tx.blocking_send(Ok(chunk)).map_err(|_| ExportError::Disconnected)?;
+        self.sent_any = true;
+        Ok(())
+    }
+
+    fn flush_if_full(&mut self) -> Result<(), ExportError> {
+        if self.out.len() >= CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn attachment_url(&self, attachment: &db::Attachment) -> String {
+        format!("{}/attachments/{}", self.server_url, attachment.id)
+    }
+
+    fn push_jsonl(&mut self, line: &JsonlLine) {
+        // Serializing plain structs to a string cannot fail.
+        self.out.push_str(&serde_json::to_string(line).unwrap());
+        self.out.push('\n');
+    }
+
+    // Writing to a String cannot fail, so the results of `write!` below are ignored.
+
+    fn start(&mut self, rooms: &[db::RoomArchiveInfo], exported_at: &str) {
+        match self.format {
+            ExportFormat::Jsonl => {
+                let exported_by = self.exported_by.clone();
+                self.push_jsonl(&JsonlLine::Export { exported_by: &exported_by, exported_at, room_count: rooms.len() });
+            }
+            ExportFormat::Html => {
+                let title = match rooms {
+                    [room] => room_title(room),
+                    _ => "Chat history".to_string(),
+                };
+                let _ = write!(
+                    self.out,
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>{}</h1>\n<header>Exported by {} at {} UTC.</header>\n",
+                    escape_html(&title),
+                    HTML_STYLE,
+                    escape_html(&title),
+                    escape_html(&self.exported_by),
+                    escape_html(exported_at)
+                );
+            }
+            ExportFormat::Text => {
+                let _ = writeln!(self.out, "Chat history exported by {} at {} UTC.", self.exported_by, exported_at);
+            }
+        }
+    }
+
+    fn room(&mut self, room: &db::RoomArchiveInfo) {
+        let out = &mut self.out;
+        match self.format {
+            ExportFormat::Jsonl => self.push_jsonl(&JsonlLine::Room(room)),
+            ExportFormat::Html => {
+                // Message ids are unique across rooms, so they make stable anchors for replies.
+                let _ = write!(out, "<section id=\"room-{}\">\n<h2>{}</h2>\n<dl>\n", room.room_id, escape_html(&room_title(room)));
+                if let Some(topic) = &room.topic {
+                    let _ = writeln!(out, "<dt>Topic</dt><dd>{}</dd>", escape_html(topic));
+                }
+                let _ = writeln!(out, "<dt>Created</dt><dd>{} UTC</dd>", escape_html(&room.created_at));
+                let _ = writeln!(out, "<dt>Participants</dt><dd>{}</dd>", escape_html(&describe_participants(room)));
+                let _ = write!(out, "<dt>Messages</dt><dd>{}</dd>\n</dl>\n<ol>\n", room.message_count);
+            }
+            ExportFormat::Text => {
+                let _ = writeln!(out, "\n=== {} (room {}) ===", room_title(room), room.room_id);
+                if let Some(topic) = &room.topic {
+                    let _ = writeln!(out, "Topic: {}", topic);
+                }
+                let _ = writeln!(out, "Created: {} UTC", room.created_at);
+                let _ = writeln!(out, "Participants: {}", describe_participants(room));
+                let _ = writeln!(out, "Messages: {}\n", room.message_count);
+            }
+        }
+    }
+
+    fn message(&mut self, message: &db::ChatMessage, reactions: &[ExportedReaction]) {
+        match self.format {
+            ExportFormat::Jsonl => self.push_jsonl(&JsonlLine::Message {
+                id: message.id,
+                room_id: message.room_id,
+                sender_username: &message.sender_username,
+                content: &message.content,
+                timestamp: &message.timestamp,
+                edited_at: message.edited_at.as_deref(),
+                deleted_at: message.deleted_at.as_deref(),
+                reply_to: message.reply_to,
+                attachments: &message.attachments,
+                reactions,
+            }),
+            ExportFormat::Html => self.message_html(message, reactions),
+            ExportFormat::Text => self.message_text(message, reactions),
+        }
+    }
+
+    fn message_text(&mut self, message: &db::ChatMessage, reactions: &[ExportedReaction]) {
+        let urls: Vec<String> = message.attachments.iter().map(|a| self.attachment_url(a)).collect();
+        let out = &mut self.out;
+        let _ = write!(out, "[{}] #{} {}", message.timestamp, message.id, message.sender_username);
+        if let Some(reply_to) = message.reply_to {
+            let _ = write!(out, " (reply to #{})", reply_to);
+        }
+        if let Some(deleted_at) = &message.deleted_at {
+            let _ = writeln!(out, ": [deleted at {}]", deleted_at);
+            return;
+        }
+        // Continuation lines are indented so every message starts at the line's beginning.
+        let _ = writeln!(out, ": {}", message.content.replace('\n', "\n    "));
+        if let Some(edited_at) = &message.edited_at {
+            let _ = writeln!(out, "    (edited at {})", edited_at);
+        }
+        for (attachment, url) in message.attachments.iter().zip(&urls) {
+            let _ = writeln!(out, "    Attachment #{}: {} {}", attachment.id, describe_attachment(attachment), url);
+        }
+        for reaction in reactions {
+            let _ = writeln!(out, "    Reaction {}: {}", reaction.emoji, reaction.users.join(", "));
+        }
+    }
+
+    fn message_html(&mut self, message: &db::ChatMessage, reactions: &[ExportedReaction]) {
+        let urls: Vec<String> = message.attachments.iter().map(|a| self.attachment_url(a)).collect();
+        let out = &mut self.out;
+        let _ = write!(
+            out,
+            "<li id=\"m{}\"><div class=\"meta\"><span class=\"sender\">{}</span> {}",
+            message.id,
+            escape_html(&message.sender_username),
+            escape_html(&message.timestamp)
+        );
+        if let Some(reply_to) = message.reply_to {
+            let _ = write!(out, " · reply to <a href=\"#m{}\">#{}</a>", reply_to, reply_to);
+        }
+        if let Some(edited_at) = &message.edited_at {
+            let _ = write!(out, " · edited {}", escape_html(edited_at));
+        }
+        out.push_str("</div>\n");
+
+        if let Some(deleted_at) = &message.deleted_at {
+            let _ = writeln!(out, "<div class=\"content deleted\">Deleted at {}</div></li>", escape_html(deleted_at));
+            return;
+        }
+        if !message.content.is_empty() {
+            let _ = writeln!(out, "<div class=\"content\">{}</div>", escape_html(&message.content));
+        }
+        for (attachment, url) in message.attachments.iter().zip(&urls) {
+            let _ = writeln!(
+                out,
+                "<div class=\"extra\">Attachment #{}: <a href=\"{}\">{}</a></div>",
+                attachment.id,
+                escape_html(url),
+                escape_html(&describe_attachment(attachment))
+            );
+        }
+        for reaction in reactions {
+            let _ = writeln!(
+                out,
+                "<div class=\"extra\">{} {}</div>",
+                escape_html(&reaction.emoji),
+                escape_html(&reaction.users.join(", "))
+            );
+        }
+        out.push_str("</li>\n");
+    }
+
+    fn end_room(&mut self) {
+        if self.format == ExportFormat::Html {
+            self.out.push_str("</ol>\n</section>\n");
+        }
+    }
+
+    fn end(&mut self) {
+        if self.format == ExportFormat::Html {
+            self.out.push_str("</body>\n</html>\n");
+        }
+    }
+}
+
+/// Writes the rooms, skipping any that were deleted meanwhile, one message at a time.
+fn write_rooms(conn: &db::Connection, room_ids: &[i64], writer: &mut ExportWriter) -> Result<(), ExportError> {
+    let mut rooms = Vec::with_capacity(room_ids.len());
+    for &room_id in room_ids {
+        rooms.extend(db::get_room_archive_info(conn, room_id)?);
+    }
+    writer.start(&rooms, &db::current_timestamp(conn)?);
+    writer.flush()?;
+
+    for room in &rooms {
+        writer.room(room);
+        db::for_each_message_in_room(conn, room.room_id, |mut message| {
+            db::attach_files(conn, std::slice::from_mut(&mut message))?;
+            let reactions = load_reactions(conn, message.id)?;
+            writer.message(&message, &reactions);
+            writer.flush_if_full()
+        })?;
+        writer.end_room();
+    }
+    writer.end();
+    writer.flush()
+}
+
+/// Reads everything from one snapshot, so room message counts match the messages written.
+fn write_export(state: &AppState, room_ids: &[i64], writer: &mut ExportWriter) -> Result<(), ExportError> {
+    let conn = state.db_pool.get().unwrap();
+    conn.execute_batch("BEGIN")?;
+    let written = write_rooms(&conn, room_ids, writer);
+    // Nothing was changed, so the snapshot is simply let go.
+    if let Err(e) = conn.execute_batch("ROLLBACK") {
+        tracing::error!("Failed to end the export snapshot: {}", e);
+    }
+    written
+}
+
+/// The address the request was sent to, for links back to the server. Empty, giving
+/// relative links, if the `Host` header is missing or unusual.
+fn server_url(headers: &HeaderMap) -> String {
+    let host = headers.get(header::HOST).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c)) {
+        format!("http://{}", host)
+    } else {
+        String::new()
+    }
+}
+
+/// Streams the export as a download while it is built off the async workers.
+async fn export_response(state: Arc<AppState>, user: db::User, room_ids: Vec<i64>, format: ExportFormat, filename: String, server_url: String) -> Response {
+    let (tx, mut rx) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let room_count = room_ids.len();
+        let mut writer = ExportWriter { format, exported_by: user.username, server_url, out: String::new(), sent_any: false, tx };
+        match write_export(&state, &room_ids, &mut writer) {
+            Ok(()) => tracing::info!("User '{}' exported {} room(s) as {}.", writer.exported_by, room_count, format.as_str()),
+            Err(ExportError::Disconnected) => tracing::info!("User '{}' cancelled an export.", writer.exported_by),
+            Err(ExportError::Database(e)) => {
+                tracing::error!("Failed to export rooms: {}", e);
+                if writer.sent_any {
+                    // Break off the download, so it is not mistaken for a complete file.
+                    let _ = writer.tx.blocking_send(Err(std::io::Error::other("The export failed.")));
+                }
+            }
+        }
+    });
+    // The start of the export is sent once the rooms are loaded; without it, the export failed.
+    let Some(first) = rx.recv().await else {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Failed to export the chat history.");
+    };
+    let rest = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|chunk| (chunk, rx)) });
+
+    let disposition = format!("attachment; filename*=UTF-8''{}", encode_filename(&filename));
+    let mut response = Body::from_stream(stream::once(async { first }).chain(rest)).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(format.mime_type()));
+    if let Ok(value) = HeaderValue::from_str(&disposition) {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static("default-src 'none'; style-src 'unsafe-inline'; sandbox"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+/// `GET /rooms/:id/export?format=...`, the whole history of a room the user takes part in.
+pub async fn export_room(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(room_id): Path<i64>,
+    Query(params): Query<ExportParams>,
+) -> Response {
+    let user = match authenticate_download(&state, &headers) {
+        Ok(user) => user,
+        Err((status, code, text)) => return error_response(status, code, text),
+    };
+    let conn = state.db_pool.get().unwrap();
+    if !db::is_room_participant(&conn, room_id, user._id).unwrap_or(false) {
+        tracing::warn!("User '{}' was refused an export of room {}.", user.username, room_id);
+        return error_response(StatusCode::FORBIDDEN, ErrorCode::Forbidden, "You are not a member of this room.");
+    }
+    drop(conn);
+    let filename = format!("room-{}.{}", room_id, params.format.extension());
+    export_response(state, user, vec![room_id], params.format, filename, server_url(&headers)).await
+}
+
+/// `GET /admin/export?format=...`, the history of every room on the server, for admins.
+pub async fn export_all_rooms(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<ExportParams>,
+) -> Response {
+    let user = match authenticate_download(&state, &headers) {
+        Ok(user) => user,
+        Err((status, code, text)) => return error_response(status, code, text),
+    };
+    if user.role != "admin" {
+        tracing::warn!("User '{}' was refused an export of all rooms.", user.username);
+        return error_response(StatusCode::FORBIDDEN, ErrorCode::Forbidden, "Only admins can export every room.");
+    }
+    let conn = state.db_pool.get().unwrap();
+    let mut room_ids: Vec<i64> = match db::get_all_rooms(&conn) {
+        Ok(rooms) => rooms.into_iter().map(|room| room.id).collect(),
+        Err(e) => {
+            tracing::error!("Failed to list rooms for export: {}", e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Failed to export the chat history.");
+        }
+    };
+    drop(conn);
+    // Oldest first, as in the rooms' own histories.
+    room_ids.sort_unstable();
+    let filename = format!("all-rooms.{}", params.format.extension());
+    export_response(state, user, room_ids, params.format, filename, server_url(&headers)).await
+}
@@ -1,26 +1,54 @@
-use crate::{db, send_ws_message_to, AppState, Client, InvitationPayload, JoinRoomPayload, QuickChatPayload, RoomId, WsRequestMessage, ChatMessagePayload, SendFriendRequestPayload, RespondToFriendRequestPayload, AdminCreateUserPayload, AdminDeleteUserPayload, AdminDeleteRoomPayload, DeleteFriendPayload, AdminChangePortPayload, load_config};
+use crate::protocol::{
+    ClientMessage, ErrorCode, ExportReadyPayload, FailurePayload, FriendInfo, FriendRequestSentPayload,
+    FriendRequestUpdatePayload, InvitationPayload, JoinOkPayload, MessageAckPayload, MessageDeletedPayload, MessageEditedPayload, MessageEditsPayload, MessagePage, ReactionPayload, ReactionUpdatedPayload, ReadReceiptPayload, ReadReceiptsSettingPayload, RoomUnreadPayload, RoomUpdatedPayload, SearchResultsPayload, ServerMessage, ThreadPayload, TypingUpdatePayload, VoiceChatInvitationPayload,
+};
+use crate::{db, exports, load_config, registration, send_ws_message_to, send_ws_message_to_user, user_senders, AppState, Client, ConnId, Reply, Room, RoomId};
 use axum::extract::ws::Message;
 use rusqlite::params;
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use std::fs;
 
+// Messages sent on join, and the default and largest page for `load_older_messages`.
+const HISTORY_PAGE_SIZE: u32 = 50;
+const HISTORY_MAX_PAGE_SIZE: u32 = 200;
+// Default and largest number of results for `search_messages`.
+const SEARCH_PAGE_SIZE: u32 = 50;
+const SEARCH_MAX_PAGE_SIZE: u32 = 100;
+// Users notified per message, so a pasted member list cannot flood everyone.
+const MAX_MENTIONS_PER_MESSAGE: usize = 20;
+// Long enough for flags, skin tones and joined emoji sequences.
+const MAX_EMOJI_BYTES: usize = 32;
+// Longest group name, in characters.
+const MAX_ROOM_NAME_CHARS: usize = 64;
+// Most participants a group chat can have, including its creator.
+const MAX_GROUP_MEMBERS: usize = 100;
+// Most files sent with one message.
+const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+// Default and largest number of rooms for `list_public_rooms`.
+const PUBLIC_ROOMS_PAGE_SIZE: u32 = 50;
+const PUBLIC_ROOMS_MAX_PAGE_SIZE: u32 = 100;
+// Longest room topic, in characters.
+const MAX_ROOM_TOPIC_CHARS: usize = 256;
+// A typing indicator ends on its own this long after the last `typing_start`.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+// A connection announces that it started typing at most this often.
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+
 // --- Standalone Handlers (called from main) ---
 
 /// Fetches all rooms for the user and sends it to them.
 pub async fn handle_get_user_rooms(
     state: Arc<AppState>,
     user: &db::User,
-    own_tx: &mpsc::UnboundedSender<Message>,
+    reply: &Reply<'_>,
 ) {
     let conn = state.db_pool.get().unwrap();
     match db::get_user_rooms(&conn, user._id) {
         Ok(rooms) => {
-            let resp = crate::WsResponseMessage {
-                r#type: "chat_list".to_string(),
-                payload: serde_json::json!(rooms),
-            };
-            let _ = own_tx.send(Message::Text(serde_json::to_string(&resp).unwrap()));
+            reply.send(ServerMessage::ChatList(rooms)).await;
         }
         Err(e) => {
             tracing::error!("Failed to get user rooms: {}", e);
@@ -28,20 +56,33 @@ pub async fn handle_get_user_rooms(
     }
 }
 
+/// Fetches the user's unread mentions and sends them to them.
+pub async fn handle_get_mentions(
+    state: Arc<AppState>,
+    user: &db::User,
+    reply: &Reply<'_>,
+) {
+    let conn = state.db_pool.get().unwrap();
+    match db::get_unread_mentions(&conn, user._id) {
+        Ok(mentions) => {
+            reply.send(ServerMessage::Mentions(mentions)).await;
+        }
+        Err(e) => {
+            tracing::error!("Failed to get mentions: {}", e);
+        }
+    }
+}
+
 /// Fetches all pending friend requests for the user and sends it to them.
 pub async fn handle_get_friend_requests(
     state: Arc<AppState>,
     user: &db::User,
-    own_tx: &mpsc::UnboundedSender<Message>,
+    reply: &Reply<'_>,
 ) {
     let conn = state.db_pool.get().unwrap();
     match db::get_friend_requests(&conn, user._id) {
         Ok(requests) => {
-            let resp = crate::WsResponseMessage {
-                r#type: "friend_requests".to_string(),
-                payload: serde_json::json!(requests),
-            };
-            let _ = own_tx.send(Message::Text(serde_json::to_string(&resp).unwrap()));
+            reply.send(ServerMessage::FriendRequests(requests)).await;
         }
         Err(e) => {
             tracing::error!("Failed to get friend requests: {}", e);
@@ -53,26 +94,24 @@ pub async fn handle_get_friend_requests(
 pub async fn handle_get_friend_list(
     state: Arc<AppState>,
     user: &db::User,
-    own_tx: &mpsc::UnboundedSender<Message>,
+    reply: &Reply<'_>,
 ) {
     let conn = state.db_pool.get().unwrap();
     match db::get_friends(&conn, user._id) {
         Ok(friends) => {
-            let online_users = state.online_users.lock().unwrap();
-            let friend_list: Vec<crate::FriendInfo> = friends
-                .into_iter()
-                .map(|f| crate::FriendInfo {
-                    id: f._id,
-                    username: f.username,
-                    is_online: online_users.contains_key(&f._id),
-                })
-                .collect();
-
-            let resp = crate::WsResponseMessage {
-                r#type: "friend_list".to_string(),
-                payload: serde_json::json!(friend_list),
+            let friend_list: Vec<FriendInfo> = {
+                let online_users = state.online_users.lock().unwrap();
+                friends
+                    .into_iter()
+                    .map(|f| FriendInfo {
+                        id: f._id,
+                        username: f.username,
+                        is_online: online_users.contains_key(&f._id),
+                    })
+                    .collect()
             };
-            let _ = own_tx.send(Message::Text(serde_json::to_string(&resp).unwrap()));
+
+            reply.send(ServerMessage::FriendList(friend_list)).await;
         }
         Err(e) => {
             tracing::error!("Failed to get friend list: {}", e);
@@ -80,383 +119,1688 @@ pub async fn handle_get_friend_list(
     }
 }
 
+/// Fetches the user's active sessions and sends them to them.
+pub async fn handle_get_sessions(
+    state: Arc<AppState>,
+    user: &db::User,
+    token: &str,
+    reply: &Reply<'_>,
+) {
+    let conn = state.db_pool.get().unwrap();
+    match db::get_user_sessions(&conn, user._id, token) {
+        Ok(sessions) => {
+            reply.send(ServerMessage::SessionList(sessions)).await;
+        }
+        Err(e) => {
+            tracing::error!("Failed to get sessions: {}", e);
+        }
+    }
+}
+
+/// Tells every live connection using one of `tokens` that its session is gone, then closes it.
+pub async fn close_sessions(state: &AppState, tokens: &[String]) {
+    let senders: Vec<_> = {
+        let online_users = state.online_users.lock().unwrap();
+        online_users
+            .values()
+            .flat_map(|connections| connections.values())
+            .filter(|c| tokens.contains(&c.token))
+            .map(|c| c.sender.clone())
+            .collect()
+    };
+
+    for tx in senders {
+        send_ws_message_to(&tx, ServerMessage::SessionRevoked {}).await;
+        let _ = tx.send(Message::Close(None));
+    }
+}
+
+/// Takes a user out of every room in memory and closes all of their live connections.
+async fn close_user_connections(state: &AppState, user_id: i32) {
+    let room_ids: Vec<RoomId> = {
+        let rooms = state.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .filter(|(_, room)| room.clients.values().any(|c| c.user_id == user_id))
+            .map(|(room_id, _)| *room_id)
+            .collect()
+    };
+    for room_id in room_ids {
+        remove_user_from_room_in_memory(state, room_id, user_id);
+    }
+
+    let senders: Vec<_> = {
+        let online_users = state.online_users.lock().unwrap();
+        online_users
+            .get(&user_id)
+            .map(|connections| connections.values().map(|c| c.sender.clone()).collect())
+            .unwrap_or_default()
+    };
+    for tx in senders {
+        send_ws_message_to(&tx, ServerMessage::SessionRevoked {}).await;
+        let _ = tx.send(Message::Close(None));
+    }
+}
+
+/// Re-sends the friend list to every online friend after the user came online or went offline.
+pub async fn notify_friends_of_presence(state: Arc<AppState>, user: &db::User) {
+    let friends = {
+        let conn = state.db_pool.get().unwrap();
+        match db::get_friends(&conn, user._id) {
+            Ok(friends) => friends,
+            Err(e) => {
+                tracing::error!("Failed to get friends for presence update: {}", e);
+                return;
+            }
+        }
+    };
+
+    for friend in friends {
+        for friend_tx in user_senders(&state, friend._id) {
+            handle_get_friend_list(state.clone(), &friend, &Reply::push(&friend_tx)).await;
+        }
+    }
+}
+
+fn too_many_attempts(wait: Duration) -> String {
+    format!("Too many failed attempts. Try again in {} seconds.", wait.as_secs() + 1)
+}
+
+/// Rejects users who do not take part in the room.
+fn check_room_access(conn: &db::Connection, room_id: RoomId, user_id: i32) -> Result<(), (ErrorCode, &'static str)> {
+    match db::is_room_participant(conn, room_id, user_id) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((ErrorCode::Forbidden, "You are not a member of this room.")),
+        Err(e) => {
+            tracing::error!("Failed to check membership of room {}: {}", room_id, e);
+            Err((ErrorCode::Internal, "Failed to check room access."))
+        }
+    }
+}
+
+/// Puts a connection into a room in memory, leaving the room it was in before.
+pub fn join_room_in_memory(
+    state: &AppState,
+    user: &db::User,
+    conn_id: ConnId,
+    room_id: RoomId,
+    own_tx: &mpsc::UnboundedSender<Message>,
+) {
+    leave_room_in_memory(state, conn_id);
+    let client = Client {
+        user_id: user._id,
+        username: user.username.clone(),
+        sender: own_tx.clone(),
+        typing_until: None,
+        typing_announced: None,
+    };
+    state.rooms.lock().unwrap().entry(room_id).or_default().clients.insert(conn_id, client);
+}
+
+/// Removes a connection from the in-memory rooms, dropping rooms that become empty.
+pub fn leave_room_in_memory(state: &AppState, conn_id: ConnId) {
+    let mut rooms = state.rooms.lock().unwrap();
+    rooms.retain(|room_id, room| {
+        if let Some(client) = room.clients.remove(&conn_id) {
+            tracing::info!("Removed connection {} from room '{}' in memory.", conn_id, room_id);
+            if client.typing_until.is_some() {
+                announce_typing(room, *room_id, &client, false);
+            }
+        }
+        if room.clients.is_empty() {
+            tracing::info!("Room '{}' is now empty and has been removed from memory.", room_id);
+            return false;
+        }
+        true
+    });
+}
+
+/// Tells the room's other users that `typist` started or stopped typing.
+fn announce_typing(room: &Room, room_id: RoomId, typist: &Client, typing: bool) {
+    let update = TypingUpdatePayload { room_id, username: typist.username.clone(), typing };
+    let frame = ServerMessage::Typing(update).to_frame();
+    for client in room.clients.values().filter(|c| c.user_id != typist.user_id) {
+        let _ = client.sender.send(frame.clone());
+    }
+}
+
+/// Marks the connection as typing until `TYPING_TIMEOUT` passes without another
+/// `typing_start`. Only the first start is announced, and no more often than
+/// `TYPING_THROTTLE`, so repeated starts and stops cannot flood the room.
+fn start_typing(state: &Arc<AppState>, conn_id: ConnId, room_id: RoomId) {
+    let now = Instant::now();
+    let mut rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get_mut(&room_id) else { return };
+    let Some(client) = room.clients.get_mut(&conn_id) else { return };
+
+    let already_typing = client.typing_until.is_some();
+    if !already_typing && client.typing_announced.is_some_and(|at| now - at < TYPING_THROTTLE) {
+        return;
+    }
+    client.typing_until = Some(now + TYPING_TIMEOUT);
+    if already_typing {
+        return;
+    }
+    client.typing_announced = Some(now);
+
+    let room = &rooms[&room_id];
+    announce_typing(room, room_id, &room.clients[&conn_id], true);
+    tokio::spawn(expire_typing(state.clone(), conn_id, room_id));
+}
+
+/// Clears the connection's typing state, telling the room if it was typing. With
+/// `announce` false the others are expected to clear it themselves, e.g. when the
+/// user's message arrives.
+fn stop_typing(state: &AppState, conn_id: ConnId, room_id: RoomId, announce: bool) {
+    let mut rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get_mut(&room_id) else { return };
+    let Some(client) = room.clients.get_mut(&conn_id) else { return };
+    if client.typing_until.take().is_some() && announce {
+        announce_typing(room, room_id, &room.clients[&conn_id], false);
+    }
+}
+
+/// Ends a typing indicator once its deadline passes, so a client that goes away
+/// mid-typing does not leave it on.
+async fn expire_typing(state: Arc<AppState>, conn_id: ConnId, room_id: RoomId) {
+    let mut deadline = Instant::now() + TYPING_TIMEOUT;
+    loop {
+        tokio::time::sleep_until(deadline.into()).await;
+        let typing_until = state
+            .rooms
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .and_then(|room| room.clients.get(&conn_id))
+            .and_then(|client| client.typing_until);
+        match typing_until {
+            Some(until) if until > Instant::now() => deadline = until,
+            Some(_) => return stop_typing(&state, conn_id, room_id, true),
+            None => return,
+        }
+    }
+}
+
+/// Sends a frame to every connection that has the room open, except `skip`.
+fn broadcast_to_room(state: &AppState, room_id: RoomId, frame: &Message, skip: ConnId) {
+    let rooms = state.rooms.lock().unwrap();
+    if let Some(room) = rooms.get(&room_id) {
+        for (id, client) in &room.clients {
+            if *id != skip {
+                let _ = client.sender.send(frame.clone());
+            }
+        }
+    }
+}
+
+/// Looks up a message that has not been deleted, or the error to reply with.
+fn find_live_message(conn: &db::Connection, message_id: i32) -> Result<db::ChatMessage, (ErrorCode, &'static str)> {
+    match db::get_message(conn, message_id) {
+        Ok(Some(message)) if message.deleted_at.is_none() => Ok(message),
+        Ok(_) => Err((ErrorCode::NotFound, "Message not found.")),
+        Err(e) => {
+            tracing::error!("Failed to load message {}: {}", message_id, e);
+            Err((ErrorCode::Internal, "Failed to load the message."))
+        }
+    }
+}
+
+/// Checks that every attachment is one of the user's uploads that has not been sent yet.
+fn check_attachments(conn: &db::Connection, user: &db::User, attachment_ids: &[i64]) -> Result<(), (ErrorCode, &'static str)> {
+    if attachment_ids.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return Err((ErrorCode::InvalidPayload, "Too many attachments in one message."));
+    }
+    for (index, attachment_id) in attachment_ids.iter().enumerate() {
+        if attachment_ids[..index].contains(attachment_id) {
+            return Err((ErrorCode::InvalidPayload, "The same attachment is listed twice."));
+        }
+        match db::get_stored_attachment(conn, *attachment_id) {
+            Ok(Some(stored)) if stored.uploader_id == user._id => {
+                if stored.room_id.is_some() {
+                    return Err((ErrorCode::InvalidPayload, "This attachment was already sent."));
+                }
+            }
+            Ok(_) => return Err((ErrorCode::NotFound, "Attachment not found.")),
+            Err(e) => {
+                tracing::error!("Failed to load attachment {}: {}", attachment_id, e);
+                return Err((ErrorCode::Internal, "Failed to load the attachment."));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the distinct usernames written as `@username` in a message. An `@` inside a
+/// word, as in an email address, does not count.
+fn mentioned_usernames(content: &str) -> Vec<String> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.';
+    let mut names: Vec<String> = Vec::new();
+    let mut prev = None;
+    for (i, c) in content.char_indices() {
+        if c == '@' && !prev.is_some_and(|p: char| p.is_alphanumeric()) {
+            let rest = &content[i + 1..];
+            let end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+            // Trailing dots and dashes are punctuation, as in "thanks @bob."
+            let name = rest[..end].trim_end_matches(['.', '-']);
+            if name.len() >= registration::USERNAME_MIN_LEN
+                && name.len() <= registration::USERNAME_MAX_LEN
+                && !names.iter().any(|n| n.eq_ignore_ascii_case(name))
+            {
+                names.push(name.to_string());
+            }
+        }
+        prev = Some(c);
+    }
+    names.truncate(MAX_MENTIONS_PER_MESSAGE);
+    names
+}
+
+/// Records the room participants named in a new message and returns the notification
+/// for each of them, keyed by user id.
+fn record_mentions(conn: &db::Connection, message: &db::ChatMessage, sender_id: i32) -> Vec<(i32, db::MentionInfo)> {
+    let names = mentioned_usernames(&message.content);
+    if names.is_empty() {
+        return Vec::new();
+    }
+    match db::record_mentions(conn, message.id, message.room_id, sender_id, &names) {
+        Ok(recorded) => recorded
+            .into_iter()
+            .map(|(mention_id, user_id)| {
+                let mention = db::MentionInfo {
+                    id: mention_id,
+                    message_id: message.id,
+                    room_id: message.room_id,
+                    from_username: message.sender_username.clone(),
+                    content: message.content.clone(),
+                    timestamp: message.timestamp.clone(),
+                };
+                (user_id, mention)
+            })
+            .collect(),
+        Err(e) => {
+            tracing::error!("Failed to record mentions in message {}: {}", message.id, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Sends each participant's unread count for the room to their connections that don't
+/// have it open. Connections inside the room see new messages as they arrive.
+fn push_room_unread(state: &AppState, conn: &db::Connection, room_id: RoomId) {
+    let participants = match db::get_room_participant_ids(conn, room_id) {
+        Ok(participants) => participants,
+        Err(e) => {
+            tracing::error!("Failed to get participants of room {}: {}", room_id, e);
+            return;
+        }
+    };
+    let in_room: HashSet<ConnId> = state
+        .rooms
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .map(|room| room.clients.keys().copied().collect())
+        .unwrap_or_default();
+
+    for user_id in participants {
+        let senders: Vec<_> = match state.online_users.lock().unwrap().get(&user_id) {
+            Some(connections) => connections
+                .iter()
+                .filter(|(id, _)| !in_room.contains(id))
+                .map(|(_, c)| c.sender.clone())
+                .collect(),
+            None => continue,
+        };
+        if senders.is_empty() {
+            continue;
+        }
+        match db::get_room_unread(conn, room_id, user_id) {
+            Ok((unread_count, last_message)) => {
+                let frame = ServerMessage::RoomUnread(RoomUnreadPayload { room_id, unread_count, last_message }).to_frame();
+                for sender in senders {
+                    let _ = sender.send(frame.clone());
+                }
+            }
+            Err(e) => tracing::error!("Failed to count unread messages in room {}: {}", room_id, e),
+        }
+    }
+}
+
+/// Tells the other participant of a one-on-one room how far the user has read, if the
+/// marker moved. Receipts are only exchanged between users who both have them on.
+fn push_read_receipt(state: &AppState, conn: &db::Connection, room_id: RoomId, user: &db::User, marker: &db::ReadMarker) {
+    if !marker.advanced
+        || !db::is_one_on_one_room(conn, room_id).unwrap_or(false)
+        || !db::get_read_receipts_enabled(conn, user._id).unwrap_or(true)
+    {
+        return;
+    }
+    let others = match db::get_other_read_markers(conn, room_id, user._id) {
+        Ok(others) => others,
+        Err(e) => {
+            tracing::error!("Failed to get read markers of room {}: {}", room_id, e);
+            return;
+        }
+    };
+
+    let receipt = ReadReceiptPayload { room_id, user: user.username.clone(), message_id: marker.message_id };
+    let frame = ServerMessage::ReadReceipt(receipt).to_frame();
+    for (user_id, _, _) in others {
+        if db::get_read_receipts_enabled(conn, user_id).unwrap_or(true) {
+            for sender in user_senders(state, user_id) {
+                let _ = sender.send(frame.clone());
+            }
+        }
+    }
+}
+
+/// The other participants' current read markers in a one-on-one room, sent after its
+/// history so the user sees what has already been read.
+fn current_read_receipts(conn: &db::Connection, room_id: RoomId, user: &db::User) -> Vec<ServerMessage> {
+    if !db::is_one_on_one_room(conn, room_id).unwrap_or(false) || !db::get_read_receipts_enabled(conn, user._id).unwrap_or(true) {
+        return Vec::new();
+    }
+    db::get_other_read_markers(conn, room_id, user._id)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(user_id, _, message_id)| *message_id > 0 && db::get_read_receipts_enabled(conn, *user_id).unwrap_or(true))
+        .map(|(_, username, message_id)| ServerMessage::ReadReceipt(ReadReceiptPayload { room_id, user: username, message_id }))
+        .collect()
+}
+
+/// Adds or removes one of the user's reactions and sends the new totals to everyone
+/// who has the room open.
+async fn handle_reaction(state: &AppState, user: &db::User, conn_id: ConnId, p: ReactionPayload, add: bool, reply: &Reply<'_>) {
+    // Plain words are not reactions; every emoji has at least one non-ASCII character.
+    if p.emoji.is_ascii() || p.emoji.len() > MAX_EMOJI_BYTES || p.emoji.chars().any(char::is_whitespace) {
+        return reply.error(ErrorCode::InvalidPayload, "A reaction must be a single emoji.").await;
+    }
+
+    let conn = state.db_pool.get().unwrap();
+    let message = match find_live_message(&conn, p.message_id) {
+        Ok(message) => message,
+        Err((code, text)) => return reply.error(code, text).await,
+    };
+    if !db::is_room_participant(&conn, message.room_id, user._id).unwrap_or(false) {
+        return reply.error(ErrorCode::NotFound, "Message not found.").await;
+    }
+
+    let result = if add {
+        db::add_reaction(&conn, message.id, user._id, &p.emoji)
+    } else {
+        db::remove_reaction(&conn, message.id, user._id, &p.emoji)
+    };
+    let reactions = match result.and_then(|()| db::get_reactions(&conn, message.id)) {
+        Ok(reactions) => reactions,
+        Err(e) => {
+            tracing::error!("Failed to update reactions of message {}: {}", message.id, e);
+            return reply.error(ErrorCode::Internal, "Failed to update the reaction.").await;
+        }
+    };
+
+    let update_for = |viewer_id| ReactionUpdatedPayload {
+        message_id: message.id,
+        room_id: message.room_id,
+        reactions: db::summarize_reactions(&reactions, viewer_id),
+    };
+    {
+        // Each viewer sees whether they reacted themselves, so everyone gets their own copy.
+        let rooms = state.rooms.lock().unwrap();
+        if let Some(room) = rooms.get(&message.room_id) {
+            for (id, client) in &room.clients {
+                if *id != conn_id {
+                    let _ = client.sender.send(ServerMessage::ReactionUpdated(update_for(client.user_id)).to_frame());
+                }
+            }
+        }
+    }
+    reply.send(ServerMessage::ReactionUpdated(update_for(user._id))).await;
+}
+
+/// Loads one page of a room's history with reactions as `viewer_id` sees them, logging failures.
+fn load_message_page(conn: &db::Connection, room_id: RoomId, before_id: Option<i32>, limit: u32, viewer_id: i32) -> Option<MessagePage> {
+    let page = db::get_messages_page(conn, room_id, before_id, limit).and_then(|(mut messages, has_more)| {
+        db::attach_reactions(conn, &mut messages, viewer_id)?;
+        db::attach_files(conn, &mut messages)?;
+        Ok((messages, has_more))
+    });
+    match page {
+        Ok((messages, has_more)) => Some(MessagePage { room_id, messages, has_more }),
+        Err(e) => {
+            tracing::error!("Failed to load messages for room {}: {}", room_id, e);
+            None
+        }
+    }
+}
+
+/// Checks a group name and returns it without surrounding whitespace.
+fn validate_room_name(name: &str) -> Result<&str, &'static str> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_ROOM_NAME_CHARS {
+        return Err("Group names must be 1 to 64 characters long.");
+    }
+    Ok(name)
+}
+
+/// Looks up a group chat the user takes part in and returns their role in it.
+fn find_group(conn: &db::Connection, room_id: RoomId, user_id: i32) -> Result<db::RoomRole, (ErrorCode, &'static str)> {
+    let found = db::get_room_role(conn, room_id, user_id)
+        .and_then(|role| Ok((role, db::is_group_room(conn, room_id)?)));
+    match found {
+        Ok((Some(role), true)) => Ok(role),
+        Ok((Some(_), false)) => Err((ErrorCode::Forbidden, "Only group chats can be changed.")),
+        Ok((None, _)) => Err((ErrorCode::NotFound, "Room not found.")),
+        Err(e) => {
+            tracing::error!("Failed to load room {}: {}", room_id, e);
+            Err((ErrorCode::Internal, "Failed to load the room."))
+        }
+    }
+}
+
+/// Whether the user is a moderator or the owner of the group, who may delete anyone's
+/// messages in it.
+fn moderates_group(conn: &db::Connection, room_id: RoomId, user_id: i32) -> bool {
+    matches!(find_group(conn, room_id, user_id), Ok(role) if role >= db::RoomRole::Moderator)
+}
+
+/// Resolves a participant of the room other than `user` by username, with their role.
+fn find_other_participant(conn: &db::Connection, room_id: RoomId, user: &db::User, username: &str) -> Result<(db::User, db::RoomRole), (ErrorCode, &'static str)> {
+    let target = db::get_user_by_username(conn, username).ok();
+    let role = target.as_ref().and_then(|t| db::get_room_role(conn, room_id, t._id).ok().flatten());
+    match (target, role) {
+        (Some(target), _) if target._id == user._id => Err((ErrorCode::InvalidPayload, "You cannot do this to yourself.")),
+        (Some(target), Some(role)) => Ok((target, role)),
+        _ => Err((ErrorCode::NotFound, "That user is not in this group.")),
+    }
+}
+
+/// Resolves the usernames of friends to add to a group, skipping the user themselves
+/// and duplicates.
+fn find_invitees(conn: &db::Connection, user: &db::User, usernames: &[String]) -> Result<Vec<i32>, (ErrorCode, String)> {
+    let mut ids = Vec::new();
+    for username in usernames {
+        let friend = match db::get_user_by_username(conn, username) {
+            Ok(friend) if db::are_friends(conn, user._id, friend._id).unwrap_or(false) => friend,
+            Ok(friend) if friend._id == user._id => continue,
+            _ => return Err((ErrorCode::InvalidPayload, format!("'{}' is not one of your friends.", username))),
+        };
+        if !ids.contains(&friend._id) {
+            ids.push(friend._id);
+        }
+    }
+    Ok(ids)
+}
+
+/// Builds the `room_updated` each of the users should see for the room.
+fn room_updates(conn: &db::Connection, room_id: RoomId, user_ids: &[i32]) -> Vec<(i32, ServerMessage)> {
+    user_ids
+        .iter()
+        .filter_map(|&user_id| match db::get_room_info(conn, room_id, user_id) {
+            Ok(room) => Some((user_id, ServerMessage::RoomUpdated(RoomUpdatedPayload { room_id, room }))),
+            Err(e) => {
+                tracing::error!("Failed to load room {} for user {}: {}", room_id, user_id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Sends room updates to every connection of their users. The requesting connection
+/// gets its copy as the reply.
+async fn send_room_updates(state: &AppState, user: &db::User, updates: Vec<(i32, ServerMessage)>, reply: &Reply<'_>) {
+    for (user_id, update) in updates {
+        let frame = update.to_frame();
+        for sender in user_senders(state, user_id) {
+            if !sender.same_channel(reply.tx) {
+                let _ = sender.send(frame.clone());
+            }
+        }
+        if user_id == user._id {
+            reply.send(update).await;
+        }
+    }
+}
+
+/// Takes every connection of a user who is no longer a participant out of the room.
+fn remove_user_from_room_in_memory(state: &AppState, room_id: RoomId, user_id: i32) {
+    let mut rooms = state.rooms.lock().unwrap();
+    let Some(room) = rooms.get_mut(&room_id) else { return };
+    let conn_ids: Vec<ConnId> = room.clients.iter().filter(|(_, c)| c.user_id == user_id).map(|(id, _)| *id).collect();
+    for conn_id in conn_ids {
+        if let Some(client) = room.clients.remove(&conn_id) {
+            if client.typing_until.is_some() {
+                announce_typing(room, room_id, &client, false);
+            }
+        }
+    }
+    if room.clients.is_empty() {
+        rooms.remove(&room_id);
+    }
+}
+
+/// The authenticated connection a message arrived on.
+#[derive(Clone, Copy)]
+pub struct Origin<'a> {
+    pub token: &'a str,
+    pub ip: &'a str,
+    pub conn_id: ConnId,
+}
 
 /// Handles all incoming text-based WebSocket messages.
 pub async fn handle_message(
-    req: WsRequestMessage,
+    msg: ClientMessage,
     state: Arc<AppState>,
     user: &db::User,
+    origin: Origin<'_>,
     current_room_id: &mut Option<RoomId>,
-    own_tx: &mpsc::UnboundedSender<Message>,
+    reply: &Reply<'_>,
 ) {
-    match req.r#type.as_str() {
-        // --- Room Management ---
-        "join_room" => {
-            if let Ok(p) = serde_json::from_value::<JoinRoomPayload>(req.payload.clone()) {
-                // 1. Add user to the in-memory room struct
-                let client = Client { sender: own_tx.clone() };
-                state.rooms.lock().unwrap().entry(p.room_id).or_default().clients.insert(user._id, client);
-                *current_room_id = Some(p.room_id);
+    let Origin { token, ip, conn_id } = origin;
+    if msg.requires_admin() && user.role != "admin" {
+        tracing::warn!("User '{}' attempted an admin command without permission.", user.username);
+        reply.error(ErrorCode::Forbidden, "This command requires an admin account.").await;
+        return;
+    }
 
-                // 2. Acknowledge join and send message history
-                send_ws_message_to(own_tx, "join_ok", &serde_json::json!({ "roomId": p.room_id })).await;
-                tracing::info!("User '{}' joined room '{}'", user.username, p.room_id);
+    if let Some((room_id, required)) = msg.required_room_role() {
+        let role = {
+            let conn = state.db_pool.get().unwrap();
+            db::get_room_role(&conn, room_id, user._id)
+        };
+        match role {
+            Ok(Some(role)) if role >= required => {}
+            Ok(Some(_)) => {
+                let text = format!("This needs the {} role in the room.", required.as_str());
+                return reply.error(ErrorCode::Forbidden, &text).await;
+            }
+            Ok(None) => return reply.error(ErrorCode::NotFound, "Room not found.").await,
+            Err(e) => {
+                tracing::error!("Failed to check room role: {}", e);
+                return reply.error(ErrorCode::Internal, "Failed to check your permissions.").await;
+            }
+        }
+    }
+
+    match msg {
+        // --- Authentication ---
+        ClientMessage::Register(_) | ClientMessage::Login(_) | ClientMessage::AuthWithToken(_) => {
+            reply.error(ErrorCode::AlreadyAuthenticated, "This connection is already authenticated.").await;
+        }
 
+        // --- Session ---
+        ClientMessage::Logout {} => {
+            let conn = state.db_pool.get().unwrap();
+            if let Err(e) = db::delete_auth_token(&conn, token) {
+                tracing::error!("Failed to delete auth token on logout: {}", e);
+            }
+            tracing::info!("User '{}' logged out.", user.username);
+            reply.send(ServerMessage::LogoutOk {}).await;
+            // Other tabs share the same token, so they are signed out too.
+            close_sessions(&state, &[token.to_string()]).await;
+        }
+        ClientMessage::ListSessions {} => {
+            handle_get_sessions(state.clone(), user, token, reply).await;
+        }
+        ClientMessage::RevokeSession(p) => {
+            let conn = state.db_pool.get().unwrap();
+            match db::revoke_session(&conn, user._id, p.session_id) {
+                Ok(Some(revoked)) => {
+                    tracing::info!("User '{}' revoked session {}.", user.username, p.session_id);
+                    let is_current = revoked == token;
+                    close_sessions(&state, &[revoked]).await;
+                    if !is_current {
+                        handle_get_sessions(state.clone(), user, token, reply).await;
+                    }
+                }
+                Ok(None) => {
+                    reply.send(ServerMessage::SessionError(FailurePayload::new("Session not found."))).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to revoke session: {}", e);
+                    reply.send(ServerMessage::SessionError(FailurePayload::new(e.to_string()))).await;
+                }
+            }
+        }
+        ClientMessage::RevokeAllOtherSessions {} => {
+            let conn = state.db_pool.get().unwrap();
+            match db::revoke_other_sessions(&conn, user._id, token) {
+                Ok(revoked) => {
+                    tracing::info!("User '{}' revoked {} other sessions.", user.username, revoked.len());
+                    close_sessions(&state, &revoked).await;
+                    handle_get_sessions(state.clone(), user, token, reply).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to revoke other sessions: {}", e);
+                    reply.send(ServerMessage::SessionError(FailurePayload::new(e.to_string()))).await;
+                }
+            }
+        }
+        ClientMessage::ChangePassword(p) => {
+            // Guessing the current password from a stolen session counts against the same
+            // limits as guessing it at login.
+            if let Some(wait) = state.login_limiter.check(&user.username, ip) {
+                return reply.send(ServerMessage::ChangePasswordFail(FailurePayload::new(too_many_attempts(wait)))).await;
+            }
+            // Re-read the hash so a password changed from another device is honoured.
+            let current_hash = {
                 let conn = state.db_pool.get().unwrap();
-                if let Ok(messages) = db::get_messages_for_room(&conn, p.room_id) {
-                    let resp = crate::WsResponseMessage {
-                        r#type: "message_history".to_string(),
-                        payload: serde_json::json!(messages),
-                    };
-                    let _ = own_tx.send(Message::Text(serde_json::to_string(&resp).unwrap()));
+                db::get_user_by_id(&conn, user._id).map(|u| u.password_hash).ok()
+            };
+            if !registration::verify_password(p.old_password, current_hash).await {
+                tracing::warn!("Failed password change for '{}' from {}.", user.username, ip);
+                let message = match state.login_limiter.record_failure(&user.username, ip) {
+                    Some(wait) => too_many_attempts(wait),
+                    None => "Current password is incorrect.".to_string(),
+                };
+                return reply.send(ServerMessage::ChangePasswordFail(FailurePayload::new(message))).await;
+            }
+            state.login_limiter.record_success(&user.username);
+            if let Err(e) = registration::validate_password(&p.new_password) {
+                reply.send(ServerMessage::ChangePasswordFail(FailurePayload::new(e))).await;
+                return;
+            }
+
+            let result = match registration::hash_password(p.new_password).await {
+                Ok(hash) => db::set_user_password(&state.db_pool.get().unwrap(), user._id, &hash).map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) => {
+                    tracing::info!("User '{}' changed their password.", user.username);
+                    let revoked = db::revoke_other_sessions(&state.db_pool.get().unwrap(), user._id, token).unwrap_or_default();
+                    close_sessions(&state, &revoked).await;
+                    reply.send(ServerMessage::ChangePasswordOk {}).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to change password: {}", e);
+                    reply.send(ServerMessage::ChangePasswordFail(FailurePayload::new(e))).await;
+                }
+            }
+        }
+
+        ClientMessage::SetReadReceipts(p) => {
+            let conn = state.db_pool.get().unwrap();
+            match db::set_read_receipts_enabled(&conn, user._id, p.enabled) {
+                Ok(_) => reply.send(ServerMessage::ReadReceiptsSetting(ReadReceiptsSettingPayload { enabled: p.enabled })).await,
+                Err(e) => {
+                    tracing::error!("Failed to change read receipt setting: {}", e);
+                    reply.error(ErrorCode::Internal, "Failed to change the setting.").await;
+                }
+            }
+        }
+
+        // --- Room Management ---
+        ClientMessage::JoinRoom(p) => {
+            // Only members may join, except that joining a public group makes the user a member of it.
+            let conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = check_room_access(&conn, p.room_id, user._id) {
+                if code != ErrorCode::Forbidden || !db::is_public_room(&conn, p.room_id).unwrap_or(false) {
+                    tracing::warn!("User '{}' was refused access to room {}.", user.username, p.room_id);
+                    return reply.error(code, text).await;
+                }
+                if db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default().len() >= MAX_GROUP_MEMBERS {
+                    return reply.error(ErrorCode::Forbidden, "This group is full.").await;
                 }
+                if let Err(e) = db::add_room_participant(&conn, p.room_id, user._id) {
+                    tracing::error!("Failed to add user to room {}: {}", p.room_id, e);
+                    return reply.error(ErrorCode::Internal, "Failed to join the group.").await;
+                }
+                tracing::info!("User '{}' joined public room {}.", user.username, p.room_id);
+                let participants = db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default();
+                let updates = room_updates(&conn, p.room_id, &participants);
+                send_room_updates(&state, user, updates, reply).await;
+            }
+
+            // 1. Add user to the in-memory room struct
+            join_room_in_memory(&state, user, conn_id, p.room_id, reply.tx);
+            *current_room_id = Some(p.room_id);
+
+            // 2. Acknowledge join and send message history
+            reply.send(ServerMessage::JoinOk(JoinOkPayload { room_id: p.room_id })).await;
+            tracing::info!("User '{}' joined room '{}'", user.username, p.room_id);
+
+            if let Some(page) = load_message_page(&conn, p.room_id, None, HISTORY_PAGE_SIZE, user._id) {
+                reply.send(ServerMessage::MessageHistory(page)).await;
+            }
+            for receipt in current_read_receipts(&conn, p.room_id, user) {
+                reply.send(receipt).await;
+            }
+        }
+        ClientMessage::LoadOlderMessages(p) => {
+            if *current_room_id != Some(p.room_id) {
+                reply.error(ErrorCode::NotInRoom, "Join the room before loading its history.").await;
+                return;
+            }
+
+            let limit = p.limit.unwrap_or(HISTORY_PAGE_SIZE).clamp(1, HISTORY_MAX_PAGE_SIZE);
+            let conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = check_room_access(&conn, p.room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+            match load_message_page(&conn, p.room_id, Some(p.before_id), limit, user._id) {
+                Some(page) => reply.send(ServerMessage::OlderMessages(page)).await,
+                None => reply.error(ErrorCode::Internal, "Failed to load messages.").await,
             }
         }
 
         // --- Chatting ---
-        "send_chat_message" => {
-            if let (Some(room_id), Ok(p)) = (current_room_id, serde_json::from_value::<ChatMessagePayload>(req.payload.clone())) {
-                if *room_id != p.room_id { return; } // Ensure user is sending to their current room
+        ClientMessage::SendChatMessage(p) => {
+            // Ensure user is sending to their current room
+            let Some(room_id) = current_room_id.filter(|id| *id == p.room_id) else {
+                reply.error(ErrorCode::NotInRoom, "Join the room before sending messages to it.").await;
+                return;
+            };
 
-                let conn = state.db_pool.get().unwrap();
-                if let Ok(message) = db::create_message(&conn, *room_id, &user.username, &p.content) {
-                    let rooms = state.rooms.lock().unwrap();
-                    if let Some(room) = rooms.get(room_id) {
-                        let resp = crate::WsResponseMessage {
-                            r#type: "new_chat_message".to_string(),
-                            payload: serde_json::json!(message),
-                        };
-                        let resp_text = serde_json::to_string(&resp).unwrap();
-                        // Broadcast to all clients in the room
-                        for client in room.clients.values() {
-                            let _ = client.sender.send(Message::Text(resp_text.clone()));
+            if p.content.trim().is_empty() && p.attachments.is_empty() {
+                return reply.error(ErrorCode::InvalidPayload, "A message needs text or an attachment.").await;
+            }
+
+            // Kicked users keep the room open until they switch, so check the membership itself.
+            let mut conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = check_room_access(&conn, room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+            if let Err((code, text)) = check_attachments(&conn, user, &p.attachments) {
+                return reply.error(code, text).await;
+            }
+            if let Some(reply_to) = p.reply_to {
+                match find_live_message(&conn, reply_to) {
+                    Ok(parent) if parent.room_id == room_id => {}
+                    Ok(_) => return reply.error(ErrorCode::InvalidPayload, "Replies must be in the same room as the message they answer.").await,
+                    Err((code, text)) => return reply.error(code, text).await,
+                }
+            }
+
+            match db::create_message(&mut conn, room_id, user, &p.content, p.reply_to, &p.attachments) {
+                Ok(message) => {
+                    stop_typing(&state, conn_id, room_id, false);
+                    // Ack first so the sender can match its pending message before the broadcast arrives.
+                    let ack = MessageAckPayload { id: message.id, room_id, timestamp: message.timestamp.clone() };
+                    reply.send(ServerMessage::MessageAck(ack)).await;
+
+                    // Sending implies having read everything before it.
+                    match db::mark_room_read(&conn, room_id, user._id, Some(message.id)) {
+                        Ok(Some(marker)) => push_read_receipt(&state, &conn, room_id, user, &marker),
+                        Ok(None) => {}
+                        Err(e) => tracing::error!("Failed to update read marker: {}", e),
+                    }
+                    let mentions = record_mentions(&conn, &message, user._id);
+                    let frame = ServerMessage::NewChatMessage(message).to_frame();
+                    {
+                        let rooms = state.rooms.lock().unwrap();
+                        if let Some(room) = rooms.get(&room_id) {
+                            // Broadcast to all clients in the room
+                            for client in room.clients.values() {
+                                let _ = client.sender.send(frame.clone());
+                            }
                         }
                     }
+                    push_room_unread(&state, &conn, room_id);
+                    // Mentioned users hear about it wherever they are.
+                    for (user_id, mention) in mentions {
+                        send_ws_message_to_user(&state, user_id, ServerMessage::Mention(mention)).await;
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to store chat message: {}", e);
+                    reply.error(ErrorCode::Internal, "Failed to send the message.").await;
+                }
+            }
+        }
+        ClientMessage::TypingStart(p) => {
+            if *current_room_id != Some(p.room_id) {
+                return reply.error(ErrorCode::NotInRoom, "Join the room before typing in it.").await;
+            }
+            start_typing(&state, conn_id, p.room_id);
+            reply.send(ServerMessage::Ok {}).await;
+        }
+        ClientMessage::TypingStop(p) => {
+            if *current_room_id != Some(p.room_id) {
+                return reply.error(ErrorCode::NotInRoom, "Join the room before typing in it.").await;
+            }
+            stop_typing(&state, conn_id, p.room_id, true);
+            reply.send(ServerMessage::Ok {}).await;
+        }
+        ClientMessage::EditMessage(p) => {
+            if p.content.trim().is_empty() {
+                reply.error(ErrorCode::InvalidPayload, "Message must not be empty.").await;
+                return;
+            }
+
+            let mut conn = state.db_pool.get().unwrap();
+            let message = match find_live_message(&conn, p.message_id) {
+                Ok(message) => message,
+                Err((code, text)) => return reply.error(code, text).await,
+            };
+            if let Err((code, text)) = check_room_access(&conn, message.room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+            if message.sender_id != Some(user._id) {
+                reply.error(ErrorCode::Forbidden, "You can only edit your own messages.").await;
+                return;
+            }
+
+            match db::edit_message(&mut conn, message.id, &p.content) {
+                Ok(edited) => {
+                    let event = ServerMessage::MessageEdited(MessageEditedPayload {
+                        id: edited.id,
+                        room_id: edited.room_id,
+                        content: edited.content,
+                        edited_at: edited.edited_at,
+                    });
+                    broadcast_to_room(&state, message.room_id, &event.to_frame(), conn_id);
+                    reply.send(event).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to edit message {}: {}", message.id, e);
+                    reply.error(ErrorCode::Internal, "Failed to edit the message.").await;
+                }
+            }
+        }
+        ClientMessage::DeleteMessage(p) => {
+            let mut conn = state.db_pool.get().unwrap();
+            let message = match find_live_message(&conn, p.message_id) {
+                Ok(message) => message,
+                Err((code, text)) => return reply.error(code, text).await,
+            };
+            if user.role != "admin" {
+                if let Err((code, text)) = check_room_access(&conn, message.room_id, user._id) {
+                    return reply.error(code, text).await;
+                }
+            }
+            if message.sender_id != Some(user._id) && user.role != "admin" && !moderates_group(&conn, message.room_id, user._id) {
+                reply.error(ErrorCode::Forbidden, "You can only delete your own messages.").await;
+                return;
+            }
+
+            match db::delete_message(&mut conn, message.id) {
+                Ok(deleted) => {
+                    tracing::info!("User '{}' deleted message {} in room {}.", user.username, deleted.id, deleted.room_id);
+                    let event = ServerMessage::MessageDeleted(MessageDeletedPayload {
+                        id: deleted.id,
+                        room_id: deleted.room_id,
+                        deleted_at: deleted.deleted_at,
+                    });
+                    broadcast_to_room(&state, message.room_id, &event.to_frame(), conn_id);
+                    reply.send(event).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to delete message {}: {}", message.id, e);
+                    reply.error(ErrorCode::Internal, "Failed to delete the message.").await;
+                }
+            }
+        }
+        ClientMessage::GetMessageEdits(p) => {
+            let conn = state.db_pool.get().unwrap();
+            let message = match find_live_message(&conn, p.message_id) {
+                Ok(message) => message,
+                Err((code, text)) => return reply.error(code, text).await,
+            };
+            // Outsiders get the same answer as for a missing message.
+            if user.role != "admin" && !db::is_room_participant(&conn, message.room_id, user._id).unwrap_or(false) {
+                reply.error(ErrorCode::NotFound, "Message not found.").await;
+                return;
+            }
+
+            match db::get_message_edits(&conn, message.id) {
+                Ok(edits) => reply.send(ServerMessage::MessageEdits(MessageEditsPayload { message_id: message.id, edits })).await,
+                Err(e) => {
+                    tracing::error!("Failed to load edits of message {}: {}", message.id, e);
+                    reply.error(ErrorCode::Internal, "Failed to load the edit history.").await;
+                }
+            }
+        }
+        ClientMessage::GetThread(p) => {
+            let conn = state.db_pool.get().unwrap();
+            // Deleted roots still anchor their thread, so look them up as they are.
+            let root = match db::get_message(&conn, p.message_id) {
+                Ok(Some(root)) => root,
+                Ok(None) => return reply.error(ErrorCode::NotFound, "Message not found.").await,
+                Err(e) => {
+                    tracing::error!("Failed to load message {}: {}", p.message_id, e);
+                    return reply.error(ErrorCode::Internal, "Failed to load the thread.").await;
+                }
+            };
+            if user.role != "admin" && !db::is_room_participant(&conn, root.room_id, user._id).unwrap_or(false) {
+                return reply.error(ErrorCode::NotFound, "Message not found.").await;
+            }
+
+            let thread = db::get_thread_replies(&conn, root.id).and_then(|mut replies| {
+                let mut root = root;
+                db::attach_reactions(&conn, std::slice::from_mut(&mut root), user._id)?;
+                db::attach_reactions(&conn, &mut replies, user._id)?;
+                db::attach_files(&conn, std::slice::from_mut(&mut root))?;
+                db::attach_files(&conn, &mut replies)?;
+                Ok(ThreadPayload { root, replies })
+            });
+            match thread {
+                Ok(thread) => reply.send(ServerMessage::Thread(thread)).await,
+                Err(e) => {
+                    tracing::error!("Failed to load thread of message {}: {}", p.message_id, e);
+                    reply.error(ErrorCode::Internal, "Failed to load the thread.").await;
+                }
+            }
+        }
+        ClientMessage::AddReaction(p) => handle_reaction(&state, user, conn_id, p, true, reply).await,
+        ClientMessage::RemoveReaction(p) => handle_reaction(&state, user, conn_id, p, false, reply).await,
+        ClientMessage::GetMentions {} => {
+            handle_get_mentions(state.clone(), user, reply).await;
+        }
+        ClientMessage::MarkMentionsRead(p) => {
+            let conn = state.db_pool.get().unwrap();
+            if let Err(e) = db::mark_mentions_read(&conn, user._id, p.ids.as_deref()) {
+                tracing::error!("Failed to mark mentions as read: {}", e);
+                return reply.error(ErrorCode::Internal, "Failed to update mentions.").await;
+            }
+            handle_get_mentions(state.clone(), user, reply).await;
+        }
+        ClientMessage::SearchMessages(p) => {
+            if db::fts_query(&p.query).is_none() {
+                reply.error(ErrorCode::InvalidPayload, "Search query must not be empty.").await;
+                return;
+            }
+
+            let conn = state.db_pool.get().unwrap();
+            for date in [&p.after, &p.before].into_iter().flatten() {
+                if !db::is_valid_datetime(&conn, date).unwrap_or(false) {
+                    reply.error(ErrorCode::InvalidPayload, &format!("'{}' is not a valid date.", date)).await;
+                    return;
+                }
+            }
+
+            let search = db::MessageSearch {
+                query: &p.query,
+                room_id: p.room_id,
+                sender: p.sender.as_deref().filter(|s| !s.is_empty()),
+                after: p.after.as_deref(),
+                before: p.before.as_deref(),
+                before_id: p.before_id,
+                limit: p.limit.unwrap_or(SEARCH_PAGE_SIZE).clamp(1, SEARCH_MAX_PAGE_SIZE),
+            };
+            match db::search_messages(&conn, user._id, &search) {
+                Ok((results, has_more)) => {
+                    reply.send(ServerMessage::SearchResults(SearchResultsPayload { query: p.query, results, has_more })).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to search messages: {}", e);
+                    reply.error(ErrorCode::Internal, "Search failed.").await;
                 }
             }
         }
 
+        ClientMessage::ExportRoom(p) => {
+            let access = check_room_access(&state.db_pool.get().unwrap(), p.room_id, user._id);
+            if let Err((code, text)) = access {
+                return reply.error(code, text).await;
+            }
+            let url = exports::room_export_url(p.room_id, p.format);
+            reply.send(ServerMessage::ExportReady(ExportReadyPayload { room_id: Some(p.room_id), format: p.format, url })).await;
+        }
+
         // --- Friend & Chat Creation ---
-        "get_chat_list" => {
-            handle_get_user_rooms(state.clone(), user, own_tx).await;
-        }
-        "get_friend_list" => {
-            handle_get_friend_list(state.clone(), user, own_tx).await;
-        }
-        "quick_chat_with_friend" => {
-            if let Ok(p) = serde_json::from_value::<QuickChatPayload>(req.payload.clone()) {
-                let mut conn = state.db_pool.get().unwrap();
-                match db::get_or_create_private_room(&mut conn, user._id, p.friend_id) {
-                    Ok(room_id) => {
-                        let friend_is_online;
-                        let friend_tx = {
-                            let online_users = state.online_users.lock().unwrap();
-                            friend_is_online = online_users.contains_key(&p.friend_id);
-                            online_users.get(&p.friend_id).cloned()
-                        };
-
-                        // Always take the user to the room.
-                        // 1. Add user to the in-memory room struct
-                        let client = Client { sender: own_tx.clone() };
-                        state.rooms.lock().unwrap().entry(room_id).or_default().clients.insert(user._id, client);
-                        *current_room_id = Some(room_id);
-
-                        // 2. Acknowledge join and send message history
-                        send_ws_message_to(own_tx, "join_ok", &serde_json::json!({ "roomId": room_id })).await;
-                        if let Ok(messages) = db::get_messages_for_room(&conn, room_id) {
-                             let resp = crate::WsResponseMessage {
-                                r#type: "message_history".to_string(),
-                                payload: serde_json::json!(messages),
-                            };
-                            let _ = own_tx.send(Message::Text(serde_json::to_string(&resp).unwrap()));
+        ClientMessage::GetChatList {} => {
+            handle_get_user_rooms(state.clone(), user, reply).await;
+        }
+        ClientMessage::MarkRead(p) => {
+            let conn = state.db_pool.get().unwrap();
+            match db::mark_room_read(&conn, p.room_id, user._id, p.message_id) {
+                Ok(Some(marker)) => push_read_receipt(&state, &conn, p.room_id, user, &marker),
+                Ok(None) => return reply.error(ErrorCode::NotFound, "Room not found.").await,
+                Err(e) => {
+                    tracing::error!("Failed to update read marker: {}", e);
+                    return reply.error(ErrorCode::Internal, "Failed to mark the room as read.").await;
+                }
+            }
+            match db::get_room_unread(&conn, p.room_id, user._id) {
+                Ok((unread_count, last_message)) => {
+                    let update = ServerMessage::RoomUnread(RoomUnreadPayload { room_id: p.room_id, unread_count, last_message });
+                    // The user's other devices clear their counters as well.
+                    let frame = update.to_frame();
+                    for sender in user_senders(&state, user._id) {
+                        if !sender.same_channel(reply.tx) {
+                            let _ = sender.send(frame.clone());
                         }
+                    }
+                    reply.send(update).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to count unread messages in room {}: {}", p.room_id, e);
+                    reply.error(ErrorCode::Internal, "Failed to mark the room as read.").await;
+                }
+            }
+        }
+        ClientMessage::GetFriendList {} => {
+            handle_get_friend_list(state.clone(), user, reply).await;
+        }
+        ClientMessage::GetFriendRequests {} => {
+            handle_get_friend_requests(state.clone(), user, reply).await;
+        }
+        ClientMessage::QuickChatWithFriend(p) => {
+            let mut conn = state.db_pool.get().unwrap();
+            if !db::are_friends(&conn, user._id, p.friend_id).unwrap_or(false) {
+                return reply.error(ErrorCode::Forbidden, "You can only chat with your friends.").await;
+            }
+            match db::get_or_create_private_room(&mut conn, user._id, p.friend_id) {
+                Ok(room_id) => {
+                    // Always take the user to the room.
+                    // 1. Add user to the in-memory room struct
+                    join_room_in_memory(&state, user, conn_id, room_id, reply.tx);
+                    *current_room_id = Some(room_id);
 
-                        // If friend is online, invite them.
-                        if friend_is_online {
-                            if let Some(friend_tx) = friend_tx {
-                                let invitation = InvitationPayload {
-                                    from_username: user.username.clone(),
-                                    room_id,
-                                };
-                                send_ws_message_to(&friend_tx, "invitation", invitation).await;
-                            }
-                        }
+                    // 2. Acknowledge join and send message history
+                    reply.send(ServerMessage::JoinOk(JoinOkPayload { room_id })).await;
+                    if let Some(page) = load_message_page(&conn, room_id, None, HISTORY_PAGE_SIZE, user._id) {
+                        reply.send(ServerMessage::MessageHistory(page)).await;
                     }
-                    Err(e) => {
-                        tracing::error!("Failed to get or create private room: {}", e);
+                    for receipt in current_read_receipts(&conn, room_id, user) {
+                        reply.send(receipt).await;
                     }
+
+                    // If friend is online, invite them on every device.
+                    let invitation = InvitationPayload {
+                        from_username: user.username.clone(),
+                        room_id,
+                    };
+                    send_ws_message_to_user(&state, p.friend_id, ServerMessage::Invitation(invitation)).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to get or create private room: {}", e);
+                    reply.error(ErrorCode::Internal, "Failed to open the chat.").await;
                 }
             }
         }
-        "send_friend_request" => {
-            if let Ok(p) = serde_json::from_value::<SendFriendRequestPayload>(req.payload.clone()) {
-                let conn = state.db_pool.get().unwrap();
-                let mut op_success = false;
-                let mut info_to_send: Option<db::FriendRequestInfo> = None;
-
-                if let Ok(target_user) = db::get_user_by_username(&conn, &p.username) {
-                    if target_user._id != user._id {
-                        match db::send_friend_request(&conn, user._id, target_user._id) {
-                            Ok(info) => {
-                                op_success = true;
-                                info_to_send = Some(info);
-                            },
-                            Err(e) => {
-                                tracing::error!("DB error in send_friend_request: {}", e);
-                                op_success = false;
-                            }
+        ClientMessage::SendFriendRequest(p) => {
+            let conn = state.db_pool.get().unwrap();
+            let mut op_success = false;
+            let mut info_to_send: Option<db::FriendRequestInfo> = None;
+
+            if let Ok(target_user) = db::get_user_by_username(&conn, &p.username) {
+                if target_user._id != user._id {
+                    match db::send_friend_request(&conn, user._id, target_user._id) {
+                        Ok(info) => {
+                            op_success = true;
+                            info_to_send = Some(info);
+                        },
+                        Err(e) => {
+                            tracing::error!("DB error in send_friend_request: {}", e);
+                            op_success = false;
                         }
                     }
                 }
+            }
 
-                if op_success {
-                    if let Some(info) = info_to_send {
-                        match info.status.as_str() {
-                            "pending" => {
-                                send_ws_message_to(own_tx, "friend_request_sent", &serde_json::json!({ "username": p.username })).await;
-
-                                let friend_tx = {
-                                    let online_users_map = state.online_users.lock().unwrap();
-                                    online_users_map.get(&info.to_user_id).cloned()
-                                };
+            if op_success {
+                if let Some(info) = info_to_send {
+                    match info.status.as_str() {
+                        "pending" => {
+                            reply.send(ServerMessage::FriendRequestSent(FriendRequestSentPayload { username: p.username })).await;
 
-                                if let Some(friend_tx) = friend_tx {
-                                    send_ws_message_to(&friend_tx, "new_friend_request", info).await;
-                                }
-                            }
-                            "accepted" => {
-                                send_ws_message_to(own_tx, "friend_request_fail", &serde_json::json!({ "error": "You are already friends with this user." })).await;
-                            }
-                            _ => {
-                                send_ws_message_to(own_tx, "friend_request_fail", &serde_json::json!({ "error": "Cannot send friend request at this time." })).await;
-                            }
+                            send_ws_message_to_user(&state, info.to_user_id, ServerMessage::NewFriendRequest(info)).await;
+                        }
+                        "accepted" => {
+                            reply.send(ServerMessage::FriendRequestFail(FailurePayload::new("You are already friends with this user."))).await;
+                        }
+                        _ => {
+                            reply.send(ServerMessage::FriendRequestFail(FailurePayload::new("Cannot send friend request at this time."))).await;
                         }
                     }
-                } else {
-                    send_ws_message_to(own_tx, "friend_request_fail", &serde_json::json!({ "error": "User not found, or you cannot send a request to yourself." })).await;
-                }
-            }
-        }
-        "respond_to_friend_request" => {
-            if let Ok(p) = serde_json::from_value::<RespondToFriendRequestPayload>(req.payload.clone()) {
-                let mut conn = state.db_pool.get().unwrap();
-                if p.accept {
-                    match db::accept_friend_request(&mut conn, p.request_id) {
-                        Ok(Some(sender_id)) => {
-                            let sender_username: String = conn.query_row("SELECT username FROM users WHERE id = ?1", params![sender_id], |r| r.get(0)).unwrap_or_default();
-                            // --- Notify self (the acceptor) ---
-                            send_ws_message_to(own_tx, "friend_request_accepted", &serde_json::json!({ "from_username": sender_username })).await;
-                            handle_get_user_rooms(state.clone(), user, own_tx).await;
-                            handle_get_friend_requests(state.clone(), user, own_tx).await;
-                            handle_get_friend_list(state.clone(), user, own_tx).await;
-
-                            // --- Notify the original sender ---
-                            let sender_tx = { state.online_users.lock().unwrap().get(&sender_id).cloned() };
-                            if let Some(s_tx) = sender_tx {
-                                let sender_user: db::User = conn.query_row("SELECT id, username, password_hash, role FROM users WHERE id = ?1", params![sender_id], |row| Ok(db::User { _id: row.get(0)?, username: row.get(1)?, password_hash: row.get(2)?, role: row.get(3)? })).unwrap();
-                                tracing::info!("Notifying original sender '{}' of accepted request.", sender_user.username);
-                                send_ws_message_to(&s_tx, "friend_request_accepted", &serde_json::json!({ "from_username": user.username })).await;
-                                handle_get_user_rooms(state.clone(), &sender_user, &s_tx).await;
-                                handle_get_friend_list(state.clone(), &sender_user, &s_tx).await;
+                }
+            } else {
+                reply.send(ServerMessage::FriendRequestFail(FailurePayload::new("User not found, or you cannot send a request to yourself."))).await;
+            }
+        }
+        ClientMessage::RespondToFriendRequest(p) => {
+            let mut conn = state.db_pool.get().unwrap();
+            if p.accept {
+                match db::accept_friend_request(&mut conn, p.request_id) {
+                    Ok(Some(sender_id)) => {
+                        let sender_username: String = conn.query_row("SELECT username FROM users WHERE id = ?1", params![sender_id], |r| r.get(0)).unwrap_or_default();
+                        // --- Notify self (the acceptor) ---
+                        reply.send(ServerMessage::FriendRequestAccepted(FriendRequestUpdatePayload { from_username: sender_username })).await;
+                        for tx in user_senders(&state, user._id) {
+                            handle_get_user_rooms(state.clone(), user, &Reply::push(&tx)).await;
+                            handle_get_friend_requests(state.clone(), user, &Reply::push(&tx)).await;
+                            handle_get_friend_list(state.clone(), user, &Reply::push(&tx)).await;
+                        }
+
+                        // --- Notify the original sender on every device ---
+                        let sender_txs = user_senders(&state, sender_id);
+                        if !sender_txs.is_empty() {
+                            let sender_user: db::User = conn.query_row("SELECT id, username, password_hash, role FROM users WHERE id = ?1", params![sender_id], |row| Ok(db::User { _id: row.get(0)?, username: row.get(1)?, password_hash: row.get(2)?, role: row.get(3)? })).unwrap();
+                            tracing::info!("Notifying original sender '{}' of accepted request.", sender_user.username);
+                            for s_tx in sender_txs {
+                                send_ws_message_to(&s_tx, ServerMessage::FriendRequestAccepted(FriendRequestUpdatePayload { from_username: user.username.clone() })).await;
+                                handle_get_user_rooms(state.clone(), &sender_user, &Reply::push(&s_tx)).await;
+                                handle_get_friend_list(state.clone(), &sender_user, &Reply::push(&s_tx)).await;
                             }
                         }
-                        Ok(None) => { tracing::error!("accept_friend_request completed but returned no sender_id"); }
-                        Err(e) => { send_ws_message_to(own_tx, "friend_request_fail", &serde_json::json!({ "error": e.to_string() })).await; }
                     }
-                } else {
-                    match db::reject_friend_request(&conn, p.request_id) {
-                        Ok(Some(sender_id)) => {
-                            let sender_username: String = conn.query_row("SELECT username FROM users WHERE id = ?1", params![sender_id], |r| r.get(0)).unwrap_or_default();
-                            send_ws_message_to(own_tx, "friend_request_rejected", &serde_json::json!({ "from_username": sender_username })).await;
-                            handle_get_friend_requests(state.clone(), user, own_tx).await; // Refresh the list
-
-                            let sender_tx = { state.online_users.lock().unwrap().get(&sender_id).cloned() };
-                            if let Some(s_tx) = sender_tx {
-                                send_ws_message_to(&s_tx, "friend_request_rejected", &serde_json::json!({ "from_username": user.username })).await;
-                            }
+                    Ok(None) => { tracing::error!("accept_friend_request completed but returned no sender_id"); }
+                    Err(e) => { reply.send(ServerMessage::FriendRequestFail(FailurePayload::new(e.to_string()))).await; }
+                }
+            } else {
+                match db::reject_friend_request(&conn, p.request_id) {
+                    Ok(Some(sender_id)) => {
+                        let sender_username: String = conn.query_row("SELECT username FROM users WHERE id = ?1", params![sender_id], |r| r.get(0)).unwrap_or_default();
+                        reply.send(ServerMessage::FriendRequestRejected(FriendRequestUpdatePayload { from_username: sender_username })).await;
+                        for tx in user_senders(&state, user._id) {
+                            handle_get_friend_requests(state.clone(), user, &Reply::push(&tx)).await; // Refresh the list
                         }
-                        Ok(None) => {}
-                        Err(e) => { send_ws_message_to(own_tx, "friend_request_fail", &serde_json::json!({ "error": e.to_string() })).await; }
+
+                        send_ws_message_to_user(&state, sender_id, ServerMessage::FriendRequestRejected(FriendRequestUpdatePayload { from_username: user.username.clone() })).await;
                     }
+                    Ok(None) => {}
+                    Err(e) => { reply.send(ServerMessage::FriendRequestFail(FailurePayload::new(e.to_string()))).await; }
                 }
             }
         }
-        "delete_friend" => {
-            if let Ok(p) = serde_json::from_value::<DeleteFriendPayload>(req.payload.clone()) {
-                let mut conn = state.db_pool.get().unwrap();
-                match db::delete_friend(&mut conn, user._id, p.friend_id) {
-                    Ok(_) => {
-                        // Notify self
-                        handle_get_friend_list(state.clone(), user, own_tx).await;
-                        handle_get_user_rooms(state.clone(), user, own_tx).await; // Also refresh chats
-
-                        // Notify the other user if they are online
-                        let friend_tx = { state.online_users.lock().unwrap().get(&p.friend_id).cloned() };
-                        if let Some(friend_tx) = friend_tx {
-                            let other_user: Option<db::User> = conn.query_row(
-                                "SELECT id, username, password_hash, role FROM users WHERE id = ?1",
-                                params![p.friend_id],
-                                |row| Ok(db::User { _id: row.get(0)?, username: row.get(1)?, password_hash: row.get(2)?, role: row.get(3)? })
-                            ).ok();
-                            
-                            if let Some(ou) = other_user {
-                                handle_get_friend_list(state.clone(), &ou, &friend_tx).await;
-                                handle_get_user_rooms(state.clone(), &ou, &friend_tx).await;
+        ClientMessage::DeleteFriend(p) => {
+            let mut conn = state.db_pool.get().unwrap();
+            match db::delete_friend(&mut conn, user._id, p.friend_id) {
+                Ok(_) => {
+                    // Notify self on every device
+                    for tx in user_senders(&state, user._id) {
+                        handle_get_friend_list(state.clone(), user, &Reply::push(&tx)).await;
+                        handle_get_user_rooms(state.clone(), user, &Reply::push(&tx)).await; // Also refresh chats
+                    }
+
+                    // Notify the other user if they are online
+                    let friend_txs = user_senders(&state, p.friend_id);
+                    if !friend_txs.is_empty() {
+                        let other_user: Option<db::User> = conn.query_row(
+                            "SELECT id, username, password_hash, role FROM users WHERE id = ?1",
+                            params![p.friend_id],
+                            |row| Ok(db::User { _id: row.get(0)?, username: row.get(1)?, password_hash: row.get(2)?, role: row.get(3)? })
+                        ).ok();
+                        
+                        if let Some(ou) = other_user {
+                            for friend_tx in friend_txs {
+                                handle_get_friend_list(state.clone(), &ou, &Reply::push(&friend_tx)).await;
+                                handle_get_user_rooms(state.clone(), &ou, &Reply::push(&friend_tx)).await;
                             }
                         }
                     }
-                    Err(e) => {
-                        tracing::error!("Failed to delete friend: {}", e);
-                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to delete friend: {}", e);
+                    reply.error(ErrorCode::Internal, "Failed to remove the friend.").await;
                 }
             }
         }
 
-        "request_voice_chat" => {
-            if let Some(ref room_id) = current_room_id {
-                let invitation = crate::VoiceChatInvitationPayload {
-                    from_username: user.username.clone(),
-                };
+        ClientMessage::RequestVoiceChat {} => {
+            let Some(room_id) = *current_room_id else {
+                return reply.error(ErrorCode::NotInRoom, "Join the room before starting a voice chat.").await;
+            };
+            if let Err((code, text)) = check_room_access(&state.db_pool.get().unwrap(), room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+            let invitation = VoiceChatInvitationPayload {
+                from_username: user.username.clone(),
+            };
 
-                let peer_txs: Vec<_> = { // Scope for locks
-                    let rooms = state.rooms.lock().unwrap();
-                    if let Some(room) = rooms.get(room_id) {
-                        let online_users = state.online_users.lock().unwrap();
-                        room.clients.iter()
-                            .filter_map(|(peer_id, _)| {
-                                if *peer_id != user._id {
-                                    online_users.get(peer_id).cloned()
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect()
-                    } else {
-                        Vec::new()
+            let peer_txs: Vec<_> = { // Scope for locks
+                let rooms = state.rooms.lock().unwrap();
+                if let Some(room) = rooms.get(&room_id) {
+                    room.clients.values()
+                        .filter(|client| client.user_id != user._id)
+                        .map(|client| client.sender.clone())
+                        .collect()
+                } else {
+                    Vec::new()
+                }
+            };
+
+            for peer_tx in peer_txs {
+                tracing::info!("Sending voice chat invitation");
+                send_ws_message_to(&peer_tx, ServerMessage::VoiceChatInvitation(invitation.clone())).await;
+            }
+            reply.send(ServerMessage::Ok {}).await;
+        }
+
+        // --- Group Chats ---
+        ClientMessage::CreateGroup(p) => {
+            let name = match validate_room_name(&p.name) {
+                Ok(name) => name,
+                Err(text) => return reply.error(ErrorCode::InvalidPayload, text).await,
+            };
+            let mut conn = state.db_pool.get().unwrap();
+            let member_ids = match find_invitees(&conn, user, &p.members) {
+                Ok(ids) if ids.len() < MAX_GROUP_MEMBERS => ids,
+                Ok(_) => return reply.error(ErrorCode::InvalidPayload, "Too many members for one group.").await,
+                Err((code, text)) => return reply.error(code, &text).await,
+            };
+
+            match db::create_group_room(&mut conn, name, p.public, user._id, &member_ids) {
+                Ok(room_id) => {
+                    tracing::info!("User '{}' created group room {}.", user.username, room_id);
+                    let mut user_ids = member_ids;
+                    user_ids.push(user._id);
+                    let updates = room_updates(&conn, room_id, &user_ids);
+                    send_room_updates(&state, user, updates, reply).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to create group room: {}", e);
+                    reply.error(ErrorCode::Internal, "Failed to create the group.").await;
+                }
+            }
+        }
+        ClientMessage::RenameRoom(p) => {
+            let name = match validate_room_name(&p.name) {
+                Ok(name) => name,
+                Err(text) => return reply.error(ErrorCode::InvalidPayload, text).await,
+            };
+            let conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = find_group(&conn, p.room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+
+            if let Err(e) = db::rename_room(&conn, p.room_id, name) {
+                tracing::error!("Failed to rename room {}: {}", p.room_id, e);
+                return reply.error(ErrorCode::Internal, "Failed to rename the group.").await;
+            }
+            let participants = db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default();
+            let updates = room_updates(&conn, p.room_id, &participants);
+            send_room_updates(&state, user, updates, reply).await;
+        }
+        ClientMessage::InviteToRoom(p) => {
+            let conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = find_group(&conn, p.room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+            let invitee_id = match find_invitees(&conn, user, std::slice::from_ref(&p.username)) {
+                Ok(ids) if !ids.is_empty() => ids[0],
+                Ok(_) => return reply.error(ErrorCode::InvalidPayload, "You are already in this group.").await,
+                Err((code, text)) => return reply.error(code, &text).await,
+            };
+            if db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default().len() >= MAX_GROUP_MEMBERS {
+                return reply.error(ErrorCode::InvalidPayload, "This group is full.").await;
+            }
+
+            match db::add_room_participant(&conn, p.room_id, invitee_id) {
+                Ok(true) => {
+                    tracing::info!("User '{}' added '{}' to room {}.", user.username, p.username, p.room_id);
+                    let participants = db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default();
+                    let updates = room_updates(&conn, p.room_id, &participants);
+                    send_room_updates(&state, user, updates, reply).await;
+                }
+                Ok(false) => reply.error(ErrorCode::InvalidPayload, "That user is already in this group.").await,
+                Err(e) => {
+                    tracing::error!("Failed to add user to room {}: {}", p.room_id, e);
+                    reply.error(ErrorCode::Internal, "Failed to invite the user.").await;
+                }
+            }
+        }
+        ClientMessage::KickFromRoom(p) => {
+            let mut conn = state.db_pool.get().unwrap();
+            let role = match find_group(&conn, p.room_id, user._id) {
+                Ok(role) => role,
+                Err((code, text)) => return reply.error(code, text).await,
+            };
+            let target = match find_other_participant(&conn, p.room_id, user, &p.username) {
+                Ok((target, target_role)) if target_role < role => target,
+                Ok(_) => return reply.error(ErrorCode::Forbidden, "You can only remove members below your own role.").await,
+                Err((code, text)) => return reply.error(code, text).await,
+            };
+
+            match db::remove_room_participant(&mut conn, p.room_id, target._id) {
+                Ok(true) => {
+                    tracing::info!("User '{}' removed '{}' from room {}.", user.username, target.username, p.room_id);
+                    remove_user_from_room_in_memory(&state, p.room_id, target._id);
+                    let mut user_ids = db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default();
+                    user_ids.push(target._id);
+                    let updates = room_updates(&conn, p.room_id, &user_ids);
+                    send_room_updates(&state, user, updates, reply).await;
+                }
+                Ok(false) => reply.error(ErrorCode::NotFound, "That user is not in this group.").await,
+                Err(e) => {
+                    tracing::error!("Failed to remove user from room {}: {}", p.room_id, e);
+                    reply.error(ErrorCode::Internal, "Failed to remove the user.").await;
+                }
+            }
+        }
+        ClientMessage::LeaveRoom(p) => {
+            let mut conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = find_group(&conn, p.room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+
+            match db::remove_room_participant(&mut conn, p.room_id, user._id) {
+                Ok(_) => {
+                    tracing::info!("User '{}' left room {}.", user.username, p.room_id);
+                    remove_user_from_room_in_memory(&state, p.room_id, user._id);
+                    if *current_room_id == Some(p.room_id) {
+                        *current_room_id = None;
                     }
-                };
+                    let mut user_ids = db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default();
+                    user_ids.push(user._id);
+                    let updates = room_updates(&conn, p.room_id, &user_ids);
+                    send_room_updates(&state, user, updates, reply).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to leave room {}: {}", p.room_id, e);
+                    reply.error(ErrorCode::Internal, "Failed to leave the group.").await;
+                }
+            }
+        }
+
+        ClientMessage::SetRoomTopic(p) => {
+            let topic = p.topic.trim();
+            if topic.chars().count() > MAX_ROOM_TOPIC_CHARS {
+                return reply.error(ErrorCode::InvalidPayload, "Topics can be at most 256 characters long.").await;
+            }
+            let conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = find_group(&conn, p.room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+
+            if let Err(e) = db::set_room_topic(&conn, p.room_id, Some(topic).filter(|t| !t.is_empty())) {
+                tracing::error!("Failed to set the topic of room {}: {}", p.room_id, e);
+                return reply.error(ErrorCode::Internal, "Failed to change the topic.").await;
+            }
+            let participants = db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default();
+            let updates = room_updates(&conn, p.room_id, &participants);
+            send_room_updates(&state, user, updates, reply).await;
+        }
+        ClientMessage::SetRoomRole(p) => {
+            let mut conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = find_group(&conn, p.room_id, user._id) {
+                return reply.error(code, text).await;
+            }
+            let target = match find_other_participant(&conn, p.room_id, user, &p.username) {
+                Ok((target, _)) => target,
+                Err((code, text)) => return reply.error(code, text).await,
+            };
+
+            let result = match p.role {
+                db::RoomRole::Owner => db::transfer_room_ownership(&mut conn, p.room_id, user._id, target._id),
+                role => db::set_room_role(&conn, p.room_id, target._id, role).map(|_| ()),
+            };
+            if let Err(e) = result {
+                tracing::error!("Failed to change a role in room {}: {}", p.room_id, e);
+                return reply.error(ErrorCode::Internal, "Failed to change the role.").await;
+            }
+            tracing::info!("User '{}' made '{}' {} of room {}.", user.username, target.username, p.role.as_str(), p.room_id);
+            let participants = db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default();
+            let updates = room_updates(&conn, p.room_id, &participants);
+            send_room_updates(&state, user, updates, reply).await;
+        }
+
+        ClientMessage::SetRoomPublic(p) => {
+            let conn = state.db_pool.get().unwrap();
+            if let Err((code, text)) = find_group(&conn, p.room_id, user._id) {
+                return reply.error(code, text).await;
+            }
 
-                for peer_tx in peer_txs {
-                    tracing::info!("Sending voice chat invitation");
-                    send_ws_message_to(&peer_tx, "voice_chat_invitation", &invitation).await;
+            if let Err(e) = db::set_room_public(&conn, p.room_id, p.public) {
+                tracing::error!("Failed to change the visibility of room {}: {}", p.room_id, e);
+                return reply.error(ErrorCode::Internal, "Failed to change the group's visibility.").await;
+            }
+            let participants = db::get_room_participant_ids(&conn, p.room_id).unwrap_or_default();
+            let updates = room_updates(&conn, p.room_id, &participants);
+            send_room_updates(&state, user, updates, reply).await;
+        }
+        ClientMessage::ListPublicRooms(p) => {
+            let query = p.query.as_deref().map(str::trim).filter(|q| !q.is_empty());
+            let limit = p.limit.unwrap_or(PUBLIC_ROOMS_PAGE_SIZE).clamp(1, PUBLIC_ROOMS_MAX_PAGE_SIZE);
+            let conn = state.db_pool.get().unwrap();
+            match db::list_public_rooms(&conn, user._id, query, limit) {
+                Ok(rooms) => reply.send(ServerMessage::PublicRooms(rooms)).await,
+                Err(e) => {
+                    tracing::error!("Failed to list public rooms: {}", e);
+                    reply.error(ErrorCode::Internal, "Failed to list public rooms.").await;
                 }
             }
         }
 
         // --- Admin commands ---
-        "admin_get_all_users" => {
-            if user.role != "admin" { return; }
+        ClientMessage::AdminGetAllUsers {} => {
             let conn = state.db_pool.get().unwrap();
             match db::get_all_users(&conn) {
                 Ok(users) => {
-                    send_ws_message_to(own_tx, "admin_all_users", users).await;
+                    reply.send(ServerMessage::AdminAllUsers(users)).await;
                 }
                 Err(e) => {
                     tracing::error!("Failed to get all users for admin: {}", e);
-                    send_ws_message_to(own_tx, "admin_error", &serde_json::json!({ "error": "Failed to retrieve users." })).await;
+                    reply.send(ServerMessage::AdminError(FailurePayload::new("Failed to retrieve users."))).await;
                 }
             }
         }
-        "admin_get_all_rooms" => {
-            if user.role != "admin" { return; }
+        ClientMessage::AdminGetAllRooms {} => {
             let conn = state.db_pool.get().unwrap();
             match db::get_all_rooms(&conn) {
                 Ok(rooms) => {
-                    send_ws_message_to(own_tx, "admin_all_rooms", rooms).await;
+                    reply.send(ServerMessage::AdminAllRooms(rooms)).await;
                 }
                 Err(e) => {
                     tracing::error!("Failed to get all rooms for admin: {}", e);
-                    send_ws_message_to(own_tx, "admin_error", &serde_json::json!({ "error": "Failed to retrieve rooms." })).await;
+                    reply.send(ServerMessage::AdminError(FailurePayload::new("Failed to retrieve rooms."))).await;
                 }
             }
         }
-        "admin_create_user" => {
-            if user.role != "admin" { return; }
-            if let Ok(p) = serde_json::from_value::<AdminCreateUserPayload>(req.payload.clone()) {
-                let conn = state.db_pool.get().unwrap();
-                match db::create_user(&conn, &p.username, &p.password, Some(&p.role)) {
-                    Ok(_) => {
-                        send_ws_message_to(own_tx, "admin_create_user_ok", &serde_json::json!("User created successfully.")).await;
-                        // Also refresh the user list
-                        let users = db::get_all_users(&conn).unwrap_or_default();
-                        send_ws_message_to(own_tx, "admin_all_users", users).await;
-                    }
-                    Err(e) => {
-                        send_ws_message_to(own_tx, "admin_create_user_fail", &serde_json::json!({ "error": e.to_string() })).await;
-                    }
+        ClientMessage::AdminCreateUser(p) => {
+            let conn = state.db_pool.get().unwrap();
+            let validation = registration::validate_username(&p.username, true)
+                .and_then(|_| registration::validate_password(&p.password))
+                .and_then(|_| match db::username_taken(&conn, &p.username) {
+                    Ok(true) => Err("Username is already taken.".to_string()),
+                    _ => Ok(()),
+                });
+            if let Err(e) = validation {
+                reply.send(ServerMessage::AdminCreateUserFail(FailurePayload::new(e))).await;
+                return;
+            }
+            drop(conn);
+            let password_hash = match registration::hash_password(p.password).await {
+                Ok(hash) => hash,
+                Err(e) => return reply.send(ServerMessage::AdminCreateUserFail(FailurePayload::new(e))).await,
+            };
+            let conn = state.db_pool.get().unwrap();
+            match db::create_user(&conn, &p.username, &password_hash, Some(&p.role)) {
+                Ok(_) => {
+                    reply.send(ServerMessage::AdminCreateUserOk("User created successfully.".to_string())).await;
+                    // Also refresh the user list
+                    let users = db::get_all_users(&conn).unwrap_or_default();
+                    reply.send(ServerMessage::AdminAllUsers(users)).await;
+                }
+                Err(e) => {
+                    reply.send(ServerMessage::AdminCreateUserFail(FailurePayload::new(e.to_string()))).await;
                 }
             }
         }
-        "admin_shutdown_server" => {
-            if user.role != "admin" { return; }
+        ClientMessage::AdminShutdownServer {} => {
             tracing::warn!("Shutdown request received from admin: {}", user.username);
+            reply.send(ServerMessage::Ok {}).await;
             if let Some(tx) = state.shutdown_tx.lock().unwrap().take() {
                 if tx.send(()).is_err() {
                     tracing::error!("Failed to send shutdown signal.");
                 }
             }
         }
-        "admin_delete_user" => {
-            if user.role != "admin" { return; }
-            if let Ok(p) = serde_json::from_value::<AdminDeleteUserPayload>(req.payload.clone()) {
-                let conn = state.db_pool.get().unwrap();
-                match db::delete_user(&conn, p.user_id) {
-                    Ok(_) => {
-                        send_ws_message_to(own_tx, "admin_generic_ok", &serde_json::json!("User deleted successfully.")).await;
-                        let users = db::get_all_users(&conn).unwrap_or_default();
-                        send_ws_message_to(own_tx, "admin_all_users", users).await;
-                    }
-                    Err(e) => {
-                        send_ws_message_to(own_tx, "admin_error", &serde_json::json!({ "error": e.to_string() })).await;
-                    }
+        ClientMessage::AdminDeleteUser(p) => {
+            let conn = state.db_pool.get().unwrap();
+            match db::delete_user(&conn, p.user_id) {
+                Ok(0) => {
+                    reply.send(ServerMessage::AdminError(FailurePayload::new("User not found."))).await;
+                }
+                Ok(_) => {
+                    tracing::warn!("Admin '{}' deleted user {}.", user.username, p.user_id);
+                    reply.send(ServerMessage::AdminGenericOk("User deleted successfully.".to_string())).await;
+                    let users = db::get_all_users(&conn).unwrap_or_default();
+                    reply.send(ServerMessage::AdminAllUsers(users)).await;
+                    close_user_connections(&state, p.user_id).await;
+                }
+                Err(e) => {
+                    reply.send(ServerMessage::AdminError(FailurePayload::new(e.to_string()))).await;
                 }
             }
         }
-        "admin_delete_room" => {
-            if user.role != "admin" { return; }
-            if let Ok(p) = serde_json::from_value::<AdminDeleteRoomPayload>(req.payload.clone()) {
-                let conn = state.db_pool.get().unwrap();
-                match db::delete_room(&conn, p.room_id) {
-                    Ok(_) => {
-                        send_ws_message_to(own_tx, "admin_generic_ok", &serde_json::json!("Room deleted successfully.")).await;
-                        let rooms = db::get_all_rooms(&conn).unwrap_or_default();
-                        send_ws_message_to(own_tx, "admin_all_rooms", rooms).await;
-                    }
-                    Err(e) => {
-                        send_ws_message_to(own_tx, "admin_error", &serde_json::json!({ "error": e.to_string() })).await;
-                    }
+        ClientMessage::AdminResetPassword(p) => {
+            if let Err(e) = registration::validate_password(&p.new_password) {
+                reply.send(ServerMessage::AdminError(FailurePayload::new(e))).await;
+                return;
+            }
+            let password_hash = match registration::hash_password(p.new_password).await {
+                Ok(hash) => hash,
+                Err(e) => return reply.send(ServerMessage::AdminError(FailurePayload::new(e))).await,
+            };
+            let conn = state.db_pool.get().unwrap();
+            match db::set_user_password(&conn, p.user_id, &password_hash) {
+                Ok(0) => {
+                    reply.send(ServerMessage::AdminError(FailurePayload::new("User not found."))).await;
+                }
+                Ok(_) => {
+                    tracing::warn!("Admin '{}' reset the password of user {}.", user.username, p.user_id);
+                    // Sign the user out everywhere, except the admin's own session when resetting themselves.
+                    let revoked = if p.user_id == user._id {
+                        db::revoke_other_sessions(&conn, p.user_id, token)
+                    } else {
+                        db::revoke_all_sessions(&conn, p.user_id)
+                    };
+                    close_sessions(&state, &revoked.unwrap_or_default()).await;
+                    reply.send(ServerMessage::AdminGenericOk("Password reset successfully.".to_string())).await;
+                }
+                Err(e) => {
+                    reply.send(ServerMessage::AdminError(FailurePayload::new(e.to_string()))).await;
+                }
+            }
+        }
+        ClientMessage::AdminGetInvites {} => {
+            let conn = state.db_pool.get().unwrap();
+            match db::get_invite_codes(&conn) {
+                Ok(invites) => reply.send(ServerMessage::AdminInvites(invites)).await,
+                Err(e) => {
+                    tracing::error!("Failed to get invite codes: {}", e);
+                    reply.send(ServerMessage::AdminError(FailurePayload::new("Failed to retrieve invite codes."))).await;
+                }
+            }
+        }
+        ClientMessage::AdminCreateInvite(p) => {
+            let conn = state.db_pool.get().unwrap();
+            let max_uses = p.max_uses.filter(|n| *n > 0);
+            let expires_in_secs = p.expires_in_hours.filter(|h| *h > 0).map(|h| h * 3600);
+            match db::create_invite_code(&conn, user._id, max_uses, expires_in_secs) {
+                Ok(code) => {
+                    tracing::info!("Admin '{}' created invite code '{}'.", user.username, code);
+                    reply.send(ServerMessage::AdminGenericOk("Invite code created.".to_string())).await;
+                    reply.send(ServerMessage::AdminInvites(db::get_invite_codes(&conn).unwrap_or_default())).await;
+                }
+                Err(e) => {
+                    reply.send(ServerMessage::AdminError(FailurePayload::new(e.to_string()))).await;
                 }
             }
         }
-        "admin_change_port" => {
-            if user.role != "admin" { return; }
-            if let Ok(p) = serde_json::from_value::<AdminChangePortPayload>(req.payload.clone()) {
-                let mut config = load_config();
-                config.port = p.port;
-                match serde_json::to_string_pretty(&config) {
-                    Ok(json) => {
-                        if let Err(e) = fs::write("config.json", json) {
-                            tracing::error!("Failed to write new config: {}", e);
-                            send_ws_message_to(own_tx, "admin_change_port_fail", &serde_json::json!({ "error": e.to_string() })).await;
-                        } else {
-                            send_ws_message_to(own_tx, "admin_change_port_ok", &serde_json::json!({})).await;
-                            // Give the message a moment to be sent before shutting down
-                            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                            if let Some(tx) = state.shutdown_tx.lock().unwrap().take() {
-                                if tx.send(()).is_err() {
-                                    tracing::error!("Failed to send shutdown signal for port change.");
-                                }
+        ClientMessage::AdminRevokeInvite(p) => {
+            let conn = state.db_pool.get().unwrap();
+            match db::revoke_invite_code(&conn, &p.code) {
+                Ok(0) => {
+                    reply.send(ServerMessage::AdminError(FailurePayload::new("Invite code not found."))).await;
+                }
+                Ok(_) => {
+                    reply.send(ServerMessage::AdminGenericOk("Invite code revoked.".to_string())).await;
+                    reply.send(ServerMessage::AdminInvites(db::get_invite_codes(&conn).unwrap_or_default())).await;
+                }
+                Err(e) => {
+                    reply.send(ServerMessage::AdminError(FailurePayload::new(e.to_string()))).await;
+                }
+            }
+        }
+        ClientMessage::AdminGetLockouts {} => {
+            reply.send(ServerMessage::AdminLockouts(state.login_limiter.list())).await;
+        }
+        ClientMessage::AdminClearLockout(p) => {
+            if state.login_limiter.clear(p.kind, &p.key) {
+                tracing::info!("Admin '{}' cleared the login lockout for {:?} '{}'.", user.username, p.kind, p.key);
+                reply.send(ServerMessage::AdminGenericOk("Lockout cleared.".to_string())).await;
+            } else {
+                reply.send(ServerMessage::AdminError(FailurePayload::new("No lockout found."))).await;
+            }
+            reply.send(ServerMessage::AdminLockouts(state.login_limiter.list())).await;
+        }
+        ClientMessage::AdminDeleteRoom(p) => {
+            let conn = state.db_pool.get().unwrap();
+            match db::delete_room(&conn, p.room_id) {
+                Ok(_) => {
+                    state.rooms.lock().unwrap().remove(&p.room_id);
+                    reply.send(ServerMessage::AdminGenericOk("Room deleted successfully.".to_string())).await;
+                    let rooms = db::get_all_rooms(&conn).unwrap_or_default();
+                    reply.send(ServerMessage::AdminAllRooms(rooms)).await;
+                }
+                Err(e) => {
+                    reply.send(ServerMessage::AdminError(FailurePayload::new(e.to_string()))).await;
+                }
+            }
+        }
+        ClientMessage::AdminExportRooms(p) => {
+            let url = exports::all_rooms_export_url(p.format);
+            reply.send(ServerMessage::ExportReady(ExportReadyPayload { room_id: None, format: p.format, url })).await;
+        }
+        ClientMessage::AdminChangePort(p) => {
+            let mut config = load_config();
+            config.port = p.port;
+            match serde_json::to_string_pretty(&config) {
+                Ok(json) => {
+                    if let Err(e) = fs::write("config.json", json) {
+                        tracing::error!("Failed to write new config: {}", e);
+                        reply.send(ServerMessage::AdminChangePortFail(FailurePayload::new(e.to_string()))).await;
+                    } else {
+                        reply.send(ServerMessage::AdminChangePortOk {}).await;
+                        // Give the message a moment to be sent before shutting down
+                        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                        if let Some(tx) = state.shutdown_tx.lock().unwrap().take() {
+                            if tx.send(()).is_err() {
+                                tracing::error!("Failed to send shutdown signal for port change.");
                             }
                         }
                     }
-                    Err(e) => {
-                        tracing::error!("Failed to serialize new config: {}", e);
-                        send_ws_message_to(own_tx, "admin_change_port_fail", &serde_json::json!({ "error": e.to_string() })).await;
-                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to serialize new config: {}", e);
+                    reply.send(ServerMessage::AdminChangePortFail(FailurePayload::new(e.to_string()))).await;
                 }
             }
         }
 
-        _ => {}
+        ClientMessage::Unknown => {
+            reply.error(ErrorCode::UnknownType, "Unknown message type.").await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_start_at_an_at_sign_outside_words() {
+        assert_eq!(mentioned_usernames("@alice hi, ping @bob_2 and @carol-x"), ["alice", "bob_2", "carol-x"]);
+        assert!(mentioned_usernames("mail me at someone@example.com").is_empty());
+        assert!(mentioned_usernames("at the end @").is_empty());
+        assert!(mentioned_usernames("@ alice").is_empty());
+        assert!(mentioned_usernames("@@@").is_empty());
+    }
+
+    #[test]
+    fn mentions_drop_surrounding_punctuation() {
+        assert_eq!(mentioned_usernames("thanks @bob."), ["bob"]);
+        assert_eq!(mentioned_usernames("thanks @bob..."), ["bob"]);
+        assert_eq!(mentioned_usernames("(@alice), @bob! @carol? @dave-"), ["alice", "bob", "carol", "dave"]);
+        assert_eq!(mentioned_usernames("@bob's idea"), ["bob"]);
+        assert_eq!(mentioned_usernames("ask @j.doe."), ["j.doe"]);
+    }
+
+    #[test]
+    fn mentions_are_distinct_and_within_username_limits() {
+        assert_eq!(mentioned_usernames("@bob @Bob @BOB @alice @bob"), ["bob", "alice"]);
+        assert!(mentioned_usernames("@ab").is_empty());
+        assert!(mentioned_usernames(&format!("@{}", "x".repeat(registration::USERNAME_MAX_LEN + 1))).is_empty());
+        let many: Vec<String> = (0..30).map(|i| format!("@user{}", i)).collect();
+        assert_eq!(mentioned_usernames(&many.join(" ")).len(), MAX_MENTIONS_PER_MESSAGE);
+    }
+
+    #[test]
+    fn self_mentions_are_parsed_but_not_recorded() {
+        // The parser does not know the sender; `db::record_mentions` leaves them out.
+        assert_eq!(mentioned_usernames("note to self @alice"), ["alice"]);
     }
 }
@@ -0,0 +1,333 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+/// Thumbnails fit in a square of this many pixels.
+pub const THUMBNAIL_MAX_DIMENSION: u32 = 320;
+// Larger images are refused before they are decoded, so a small file cannot claim a huge canvas.
+const MAX_DECODED_DIMENSION: u32 = 16384;
+const JPEG_QUALITY: u8 = 90;
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+
+/// An uploaded image with its metadata removed, and a preview of it.
+pub struct ProcessedImage {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub thumbnail: Vec<u8>,
+    pub thumbnail_mime: &'static str,
+}
+
+/// Decodes an image of one of the supported types, drops its EXIF and other metadata,
+/// which can hold the location it was taken at, and makes its thumbnail. Returns None
+/// for other files. Images that cannot be decoded are refused rather than stored with
+/// their metadata.
+pub fn process(data: &[u8], mime_type: &str) -> Result<Option<ProcessedImage>, String> {
+    let format = match mime_type {
+        "image/jpeg" => ImageFormat::Jpeg,
+        "image/png" => ImageFormat::Png,
+        "image/gif" => ImageFormat::Gif,
+        "image/webp" => ImageFormat::WebP,
+        _ => return Ok(None),
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODED_DIMENSION);
+    limits.max_image_height = Some(MAX_DECODED_DIMENSION);
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+    let decoded = reader.into_decoder().and_then(|mut decoder| {
+        let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+        Ok((DynamicImage::from_decoder(decoder)?, orientation))
+    });
+    let (mut image, orientation) = match decoded {
+        Ok(decoded) => decoded,
+        Err(ImageError::Limits(_)) => {
+            return Err(format!("Images can be at most {} pixels wide and high.", MAX_DECODED_DIMENSION));
+        }
+        Err(_) => return Err("The image could not be read.".to_string()),
+    };
+    image.apply_orientation(orientation);
+
+    // The orientation lives in the EXIF data, so a rotated image is stored turned upright.
+    let stripped = if orientation == Orientation::NoTransforms { strip_metadata(data, format) } else { None };
+    let data = match stripped {
+        Some(data) => data,
+        None => encode(&image, format, JPEG_QUALITY).ok_or("The image could not be stored.")?,
+    };
+
+    let thumbnail = image.thumbnail(THUMBNAIL_MAX_DIMENSION, THUMBNAIL_MAX_DIMENSION);
+    let (thumbnail_format, thumbnail_mime) =
+        if thumbnail.color().has_alpha() { (ImageFormat::Png, "image/png") } else { (ImageFormat::Jpeg, "image/jpeg") };
+    let thumbnail = encode(&thumbnail, thumbnail_format, THUMBNAIL_JPEG_QUALITY).ok_or("The image could not be stored.")?;
+
+    Ok(Some(ProcessedImage { data, width: image.width(), height: image.height(), thumbnail, thumbnail_mime }))
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat, jpeg_quality: u8) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let result = match format {
+        // JPEG has no alpha channel and only 8 bits per sample.
+        ImageFormat::Jpeg => image.to_rgb8().write_with_encoder(JpegEncoder::new_with_quality(&mut out, jpeg_quality)),
+        _ => image.write_to(&mut Cursor::new(&mut out), format),
+    };
+    match result {
+        Ok(()) => Some(out),
+        Err(e) => {
+            tracing::error!("Failed to encode image as {:?}: {}", format, e);
+            None
+        }
+    }
+}
+
+/// Removes metadata without re-encoding the pixels. Returns None if the file's structure
+/// is not understood, in which case the caller re-encodes it instead.
+fn strip_metadata(data: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => strip_jpeg(data),
+        ImageFormat::Png => strip_png(data),
+        ImageFormat::WebP => strip_webp(data),
+        // GIF has no EXIF.
+        _ => Some(data.to_vec()),
+    }
+}
+
+/// Drops APP1 (EXIF and XMP) and APP13 (IPTC) segments.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = vec![0xFF, 0xD8];
+    let mut i = 2;
+    loop {
+        if *data.get(i)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(i + 1)?;
+        match marker {
+            0xFF => i += 1, // Fill byte
+            // Start of scan or end of image: the rest is image data.
+            0xDA | 0xD9 => {
+                out.extend_from_slice(&data[i..]);
+                return Some(out);
+            }
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&data[i..i + 2]);
+                i += 2;
+            }
+            _ => {
+                let len = u16::from_be_bytes([*data.get(i + 2)?, *data.get(i + 3)?]) as usize;
+                let end = i + 2 + len;
+                if len < 2 || end > data.len() {
+                    return None;
+                }
+                if marker != 0xE1 && marker != 0xED {
+                    out.extend_from_slice(&data[i..end]);
+                }
+                i = end;
+            }
+        }
+    }
+}
+
+/// Drops the `eXIf` chunk and text chunks, which is where XMP is kept.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut out = SIGNATURE.to_vec();
+    let mut i = SIGNATURE.len();
+    while i < data.len() {
+        let len = u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?) as usize;
+        let end = i.checked_add(12)?.checked_add(len)?; // Length, type, data and CRC
+        let kind = data.get(i + 4..i + 8)?;
+        if end > data.len() {
+            return None;
+        }
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt") {
+            out.extend_from_slice(&data[i..end]);
+        }
+        i = end;
+    }
+    Some(out)
+}
+
+/// Drops the `EXIF` and `XMP ` chunks and clears their flags in the `VP8X` header.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return None;
+    }
+    let mut out = data[0..12].to_vec();
+    let mut i = 12;
+    while i < data.len() {
+        let kind = data.get(i..i + 4)?;
+        let len = u32::from_le_bytes(data.get(i + 4..i + 8)?.try_into().ok()?) as usize;
+        let end = i.checked_add(8)?.checked_add(len + len % 2)?; // Chunks are padded to an even size
+        if end > data.len() {
+            return None;
+        }
+        match kind {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let start = out.len();
+                out.extend_from_slice(&data[i..end]);
+                *out.get_mut(start + 8)? &= !(0x08 | 0x04); // EXIF and XMP present
+            }
+            _ => out.extend_from_slice(&data[i..end]),
+        }
+        i = end;
+    }
+    let riff_size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    // A big-endian TIFF block with one IFD entry: Orientation (0x0112) as a SHORT.
+    fn exif(orientation: u8) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0".to_vec();
+        exif.extend_from_slice(&[orientation, 0, 0, 0, 0, 0, 0]);
+        exif
+    }
+
+    fn sample(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb([200, 40, 40])));
+        encode(&image, format, JPEG_QUALITY).unwrap()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn jpeg_with_exif(width: u32, height: u32, orientation: u8) -> Vec<u8> {
+        let jpeg = sample(width, height, ImageFormat::Jpeg);
+        let payload = exif(orientation);
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(&payload);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in bytes {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    fn png_with_exif() -> Vec<u8> {
+        let png = sample(4, 3, ImageFormat::Png);
+        // The signature and IHDR chunk take the first 33 bytes.
+        let mut out = png[..33].to_vec();
+        let payload = &exif(1)[6..]; // eXIf holds the TIFF data without the "Exif" prefix
+        let mut chunk = b"eXIf".to_vec();
+        chunk.extend_from_slice(payload);
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(&chunk);
+        out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+        out.extend_from_slice(&png[33..]);
+        out
+    }
+
+    fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    // Turns a simple lossless WebP into the extended format with an EXIF chunk.
+    fn webp_with_exif(width: u32, height: u32) -> Vec<u8> {
+        let webp = sample(width, height, ImageFormat::WebP);
+        assert_eq!(&webp[12..16], b"VP8L");
+        let mut vp8x = vec![0x08, 0, 0, 0]; // EXIF present
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        let mut body = b"WEBP".to_vec();
+        body.extend(webp_chunk(b"VP8X", &vp8x));
+        body.extend_from_slice(&webp[12..]);
+        body.extend(webp_chunk(b"EXIF", &exif(1)[6..]));
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend(body);
+        out
+    }
+
+    #[test]
+    fn strips_exif_from_jpeg() {
+        let input = jpeg_with_exif(4, 3, 1);
+        assert!(contains(&input, b"Exif\0\0"));
+        let stripped = strip_jpeg(&input).unwrap();
+        assert!(!contains(&stripped, b"Exif\0\0"));
+        assert_eq!(image::load_from_memory(&stripped).unwrap().width(), 4);
+    }
+
+    #[test]
+    fn strips_exif_from_png() {
+        let input = png_with_exif();
+        assert!(image::load_from_memory(&input).is_ok());
+        let stripped = strip_png(&input).unwrap();
+        assert!(!contains(&stripped, b"eXIf"));
+        assert_eq!(image::load_from_memory(&stripped).unwrap().width(), 4);
+    }
+
+    #[test]
+    fn strips_exif_from_webp() {
+        let input = webp_with_exif(4, 3);
+        assert!(image::load_from_memory(&input).is_ok());
+        let stripped = strip_webp(&input).unwrap();
+        assert!(!contains(&stripped, b"EXIF"));
+        assert_eq!(stripped[20] & 0x08, 0, "the VP8X EXIF flag is cleared");
+        assert_eq!(u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize, stripped.len() - 8);
+        assert_eq!(image::load_from_memory(&stripped).unwrap().width(), 4);
+    }
+
+    #[test]
+    fn uploads_keep_no_exif() {
+        for (data, mime_type) in [
+            (jpeg_with_exif(4, 3, 1), "image/jpeg"),
+            (png_with_exif(), "image/png"),
+            (webp_with_exif(4, 3), "image/webp"),
+        ] {
+            let image = process(&data, mime_type).unwrap().unwrap();
+            assert!(!contains(&image.data, b"Exif\0\0") && !contains(&image.data, b"MM\0\x2a"), "{}", mime_type);
+            assert_eq!((image.width, image.height), (4, 3));
+            assert!(image::load_from_memory(&image.thumbnail).is_ok());
+        }
+    }
+
+    #[test]
+    fn rotated_images_are_stored_upright_without_exif() {
+        // Orientation 6 is a quarter turn clockwise.
+        let image = process(&jpeg_with_exif(4, 3, 6), "image/jpeg").unwrap().unwrap();
+        assert_eq!((image.width, image.height), (3, 4));
+        assert!(!contains(&image.data, b"Exif\0\0"));
+        assert_eq!(image::load_from_memory(&image.data).unwrap().width(), 3);
+    }
+
+    #[test]
+    fn unreadable_images_are_refused() {
+        let mut truncated = jpeg_with_exif(4, 3, 1);
+        truncated.truncate(40);
+        assert!(process(&truncated, "image/jpeg").is_err());
+        assert!(process(b"not an image", "image/png").is_err());
+        assert!(process(b"plain text", "text/plain").unwrap().is_none());
+        let huge = sample(MAX_DECODED_DIMENSION + 1, 1, ImageFormat::Png);
+        assert!(matches!(process(&huge, "image/png"), Err(message) if message.contains("at most")));
+    }
+}
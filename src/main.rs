@@ -1,35 +1,98 @@
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
-use axum::extract::State;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, State};
+use axum::http::{header, HeaderMap};
 use axum::response::IntoResponse;
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::Router;
 use futures_util::{stream::StreamExt, SinkExt};
+use protocol::{AuthLockedPayload, AuthOkPayload, ClientMessage, ErrorCode, ErrorPayload, RegisterPayload, RequestId, ServerMessage};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tower_http::services::ServeDir;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 mod db;
+mod exports;
 mod handler;
+mod images;
+mod protocol;
+mod ratelimit;
+mod registration;
+mod uploads;
+mod voice_notes;
 
 // --- Type Aliases for Clarity ---
 pub type RoomId = i64;
+pub type ConnId = u64;
 
 // --- Core Application Structs ---
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct Config {
     pub port: u16,
+    /// How long an auth token stays valid without being used.
+    pub token_ttl_hours: u64,
+    /// Failed logins allowed for one username before it is locked.
+    pub login_max_attempts_per_user: u32,
+    /// Failed logins allowed from one IP address before it is locked.
+    pub login_max_attempts_per_ip: u32,
+    /// Length of the first lockout; each further failure doubles it.
+    pub login_lockout_secs: u64,
+    pub login_lockout_max_secs: u64,
+    /// Who may create accounts: "open", "invite" or "admin_only".
+    pub registration_mode: registration::RegistrationMode,
+    /// Largest file accepted by `/upload`, in megabytes.
+    pub max_upload_mb: u64,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Config { port: 3001 }
+        Config {
+            port: 3001,
+            token_ttl_hours: 24 * 7,
+            login_max_attempts_per_user: 5,
+            login_max_attempts_per_ip: 20,
+            login_lockout_secs: 30,
+            login_lockout_max_secs: 3600,
+            registration_mode: registration::RegistrationMode::Open,
+            max_upload_mb: 10,
+        }
+    }
+}
+
+impl Config {
+    pub fn token_ttl_secs(&self) -> u64 {
+        self.token_ttl_hours * 3600
+    }
+
+    pub fn max_upload_bytes(&self) -> usize {
+        (self.max_upload_mb * 1024 * 1024) as usize
+    }
+
+    pub fn voice_note_max_secs(&self) -> u32 {
+        voice_notes::max_duration_secs(self.max_upload_bytes())
+    }
+
+    /// Largest request body `/upload` takes: a file, or a voice note recording before it is encoded.
+    pub fn max_upload_body_bytes(&self) -> usize {
+        self.max_upload_bytes().max(voice_notes::max_upload_bytes(self.voice_note_max_secs()))
+    }
+
+    pub fn login_policy(&self) -> ratelimit::LoginPolicy {
+        ratelimit::LoginPolicy {
+            max_attempts_per_user: self.login_max_attempts_per_user.max(1),
+            max_attempts_per_ip: self.login_max_attempts_per_ip.max(1),
+            base_lockout: Duration::from_secs(self.login_lockout_secs),
+            max_lockout: Duration::from_secs(self.login_lockout_max_secs),
+        }
     }
 }
 
@@ -52,129 +115,37 @@ fn load_config() -> Config {
 
 #[derive(Default)]
 pub struct Room {
-    pub clients: HashMap<i32, Client>, // Keyed by user_id
+    pub clients: HashMap<ConnId, Client>, // Keyed by connection, a user may join from several devices
 }
 
 pub struct Client {
-    pub sender: mpsc::UnboundedSender<Message>,
-}
-
-pub struct AppState {
-    pub rooms: Mutex<HashMap<RoomId, Room>>,
-    pub online_users: Mutex<HashMap<i32, mpsc::UnboundedSender<Message>>>, // user_id -> sender
-    pub db_pool: db::Pool,
-    pub shutdown_tx: Mutex<Option<tokio::sync::oneshot::Sender<()>>>
-}
-
-// --- WebSocket Message Structures ---
-
-#[derive(Deserialize, Debug)]
-pub struct WsRequestMessage {
-    pub r#type: String,
-    pub payload: serde_json::Value,
-}
-
-#[derive(Serialize)]
-pub struct WsResponseMessage {
-    pub r#type: String,
-    pub payload: serde_json::Value,
-}
-
-// Payloads for specific message types
-
-#[derive(Deserialize, Debug)]
-pub struct RegisterPayload {
-    pub username: String,
-    pub password: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct LoginPayload {
+    pub user_id: i32,
     pub username: String,
-    pub password: String,
+    pub sender: mpsc::UnboundedSender<Message>,
+    pub typing_until: Option<Instant>, // Set while the user is typing on this connection
+    pub typing_announced: Option<Instant>, // Last time the room was told, for throttling
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct AuthWithTokenPayload {
+/// One authenticated WebSocket connection of a user.
+pub struct UserConnection {
     pub token: String,
+    pub sender: mpsc::UnboundedSender<Message>,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct JoinRoomPayload {
-    pub room_id: RoomId,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct ChatMessagePayload {
-    pub room_id: RoomId,
-    pub content: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct SendFriendRequestPayload {
-    pub username: String,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct RespondToFriendRequestPayload {
-    pub request_id: i32,
-    pub accept: bool,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct QuickChatPayload {
-    pub friend_id: i32,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct DeleteFriendPayload {
-    pub friend_id: i32,
-}
-
-#[derive(Serialize, Clone)]
-pub struct InvitationPayload {
-    pub from_username: String,
-    pub room_id: RoomId,
-}
-
-#[derive(Serialize, Clone)]
-pub struct VoiceChatInvitationPayload {
-    pub from_username: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct AdminCreateUserPayload {
-    pub username: String,
-    pub password: String,
-    pub role: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct AdminDeleteUserPayload {
-    pub user_id: i32,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct AdminDeleteRoomPayload {
-    pub room_id: i64,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct AdminChangePortPayload {
-    pub port: u16,
+/// Where a connection came from, recorded against its auth token.
+pub struct ClientInfo {
+    pub ip: String,
+    pub user_agent: Option<String>,
 }
 
-#[derive(Serialize)]
-pub struct FriendInfo {
-    pub id: i32,
-    pub username: String,
-    pub is_online: bool,
+pub struct AppState {
+    pub rooms: Mutex<HashMap<RoomId, Room>>,
+    pub online_users: Mutex<HashMap<i32, HashMap<ConnId, UserConnection>>>, // user_id -> live connections
+    pub next_conn_id: AtomicU64,
+    pub login_limiter: ratelimit::LoginLimiter,
+    pub db_pool: db::Pool,
+    pub config: Config,
+    pub shutdown_tx: Mutex<Option<tokio::sync::oneshot::Sender<()>>>
 }
 
 // --- Main Application Logic ---
@@ -189,17 +160,33 @@ async fn main() -> anyhow::Result<()> {
     println!("Database initialized successfully.");
 
     let config = load_config();
+    registration::prepare_dummy_hash();
+    match db::delete_expired_auth_tokens(&db::DB_POOL.get()?, config.token_ttl_secs()) {
+        Ok(n) if n > 0 => tracing::info!("Removed {} expired auth tokens.", n),
+        Ok(_) => {}
+        Err(e) => tracing::error!("Failed to remove expired auth tokens: {}", e),
+    }
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
 
     let shared_state = Arc::new(AppState {
         rooms: Mutex::new(HashMap::new()),
         online_users: Mutex::new(HashMap::new()),
+        next_conn_id: AtomicU64::new(1),
+        login_limiter: ratelimit::LoginLimiter::new(config.login_policy()),
         db_pool: db::DB_POOL.clone(),
+        config: config.clone(),
         shutdown_tx: Mutex::new(Some(shutdown_tx)),
     });
+    tokio::spawn(uploads::sweep_orphans(shared_state.clone()));
     let app = Router::new()
         .nest_service("/", ServeDir::new("public"))
         .route("/ws", get(ws_handler))
+        .route("/upload", post(uploads::upload).layer(DefaultBodyLimit::max(config.max_upload_body_bytes())))
+        .route("/session", post(uploads::set_session_cookie).delete(uploads::clear_session_cookie))
+        .route("/attachments/:id", get(uploads::download))
+        .route("/attachments/:id/thumbnail", get(uploads::download_thumbnail))
+        .route("/rooms/:id/export", get(exports::export_room))
+        .route("/admin/export", get(exports::export_all_rooms))
         .with_state(shared_state.clone());
 
     let addr = format!("0.0.0.0:{}", config.port);
@@ -236,15 +223,24 @@ async fn main() -> anyhow::Result<()> {
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    let client_info = ClientInfo {
+        ip: addr.ip().to_string(),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+    };
+    ws.on_upgrade(move |socket| handle_socket(socket, state, client_info))
 }
 
-async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
+async fn handle_socket(socket: WebSocket, state: Arc<AppState>, client_info: ClientInfo) {
     let (mut ws_sender, mut ws_receiver) = socket.split();
 
-    let user = match authenticate(&mut ws_sender, &mut ws_receiver, &state).await {
-        Some(user) => user,
+    let (user, token) = match authenticate(&mut ws_sender, &mut ws_receiver, &state, &client_info).await {
+        Some(auth) => auth,
         None => {
             tracing::warn!("Client failed authentication or disconnected during auth.");
             return;
@@ -252,13 +248,23 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
     };
 
     let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    let conn_id = state.next_conn_id.fetch_add(1, Ordering::Relaxed);
 
-    state.online_users.lock().unwrap().insert(user._id, tx.clone());
-    tracing::info!("User '{}' (id: {}) connected.", user.username, user._id);
+    let came_online = {
+        let mut online_users = state.online_users.lock().unwrap();
+        let connections = online_users.entry(user._id).or_default();
+        connections.insert(conn_id, UserConnection { token: token.clone(), sender: tx.clone() });
+        connections.len() == 1
+    };
+    tracing::info!("User '{}' (id: {}) connected (connection {}).", user.username, user._id, conn_id);
+    if came_online {
+        handler::notify_friends_of_presence(state.clone(), &user).await;
+    }
 
     let mut send_task = tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
-            if ws_sender.send(msg).await.is_err() {
+            let is_close = matches!(msg, Message::Close(_));
+            if ws_sender.send(msg).await.is_err() || is_close {
                 break;
             }
         }
@@ -266,59 +272,133 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
 
     let recv_state = state.clone();
     let user_clone_for_cleanup = user.clone();
+    let token_for_cleanup = token.clone();
+    let client_ip = client_info.ip.clone();
     let mut users_current_room_id: Option<RoomId> = None;
 
     let mut recv_task = tokio::spawn(async move {
-        handler::handle_get_user_rooms(recv_state.clone(), &user, &tx).await;
-        handler::handle_get_friend_requests(recv_state.clone(), &user, &tx).await;
-        handler::handle_get_friend_list(recv_state.clone(), &user, &tx).await;
+        handler::handle_get_user_rooms(recv_state.clone(), &user, &Reply::push(&tx)).await;
+        handler::handle_get_friend_requests(recv_state.clone(), &user, &Reply::push(&tx)).await;
+        handler::handle_get_friend_list(recv_state.clone(), &user, &Reply::push(&tx)).await;
+        handler::handle_get_mentions(recv_state.clone(), &user, &Reply::push(&tx)).await;
 
         while let Some(Ok(msg)) = ws_receiver.next().await {
             match msg {
                 Message::Text(text) => {
-                    if let Ok(req) = serde_json::from_str::<WsRequestMessage>(&text) {
-                        handler::handle_message(req, recv_state.clone(), &user, &mut users_current_room_id, &tx).await;
-                    } else {
-                        tracing::warn!("Failed to parse incoming message: {}", text);
+                    let (request_id, parsed) = ClientMessage::parse(&text);
+                    let reply = Reply { tx: &tx, request_id: request_id.as_ref() };
+                    match parsed {
+                        Ok(msg) => {
+                            let origin = handler::Origin { token: &token, ip: &client_ip, conn_id };
+                            handler::handle_message(msg, recv_state.clone(), &user, origin, &mut users_current_room_id, &reply).await;
+                        }
+                        Err(error) => {
+                            tracing::warn!("Rejected message from '{}': {}", user.username, error.message);
+                            reply.send(ServerMessage::Error(error)).await;
+                        }
                     }
                 }
                 Message::Binary(data) => {
-                    if let Some(ref room_id) = users_current_room_id {
-                        let rooms = recv_state.rooms.lock().unwrap();
-                        if let Some(room) = rooms.get(room_id) {
-                            for (peer_id, client) in room.clients.iter() {
-                                if *peer_id != user._id {
-                                    let _ = client.sender.send(Message::Binary(data.clone()));
+                    if let Some(room_id) = users_current_room_id {
+                        // Only relay audio from connections still in the room; kicked users are removed from it.
+                        let relayed = {
+                            let rooms = recv_state.rooms.lock().unwrap();
+                            match rooms.get(&room_id) {
+                                Some(room) if room.clients.contains_key(&conn_id) => {
+                                    for client in room.clients.values() {
+                                        if client.user_id != user._id {
+                                            let _ = client.sender.send(Message::Binary(data.clone()));
+                                        }
+                                    }
+                                    true
                                 }
+                                _ => false,
                             }
+                        };
+                        if !relayed {
+                            // Reject once, then drop further frames until the user joins a room again.
+                            tracing::warn!("Dropped audio from '{}' for room {} they are not in.", user.username, room_id);
+                            users_current_room_id = None;
+                            Reply::push(&tx).error(ErrorCode::Forbidden, "You are not a member of this room.").await;
                         }
                     }
                 }
                 _ => {}
             }
         }
-        users_current_room_id
     });
 
-    let final_room_id = tokio::select! {
-        room_id = (&mut recv_task) => room_id.ok().flatten(),
-        _ = (&mut send_task) => None,
+    // Whichever side finishes first ends the connection.
+    tokio::select! {
+        _ = (&mut recv_task) => send_task.abort(),
+        _ = (&mut send_task) => recv_task.abort(),
     };
 
-    state.online_users.lock().unwrap().remove(&user_clone_for_cleanup._id);
-    tracing::info!("User '{}' disconnected.", user_clone_for_cleanup.username);
-
-    if let Some(room_id) = final_room_id {
-        let mut rooms = state.rooms.lock().unwrap();
-        if let Some(room) = rooms.get_mut(&room_id) {
-            if room.clients.remove(&user_clone_for_cleanup._id).is_some() {
-                tracing::info!("Removed user from room '{}' in memory.", room_id);
+    let went_offline = {
+        let mut online_users = state.online_users.lock().unwrap();
+        match online_users.get_mut(&user_clone_for_cleanup._id) {
+            Some(connections) => {
+                connections.remove(&conn_id);
+                if connections.is_empty() {
+                    online_users.remove(&user_clone_for_cleanup._id);
+                    true
+                } else {
+                    false
+                }
             }
-            if room.clients.is_empty() {
-                rooms.remove(&room_id);
-                tracing::info!("Room '{}' is now empty and has been removed from memory.", room_id);
+            None => false,
+        }
+    };
+    tracing::info!("User '{}' disconnected (connection {}).", user_clone_for_cleanup.username, conn_id);
+    if went_offline {
+        handler::notify_friends_of_presence(state.clone(), &user_clone_for_cleanup).await;
+    }
+
+    // Count the whole connection as use of the token. A no-op if it was revoked.
+    if let Ok(conn) = state.db_pool.get() {
+        let _ = db::touch_auth_token(&conn, &token_for_cleanup);
+    }
+
+    handler::leave_room_in_memory(&state, conn_id);
+}
+
+/// Creates an account under the configured registration mode. The first account on a
+/// fresh server can always be registered so there is someone to administer it.
+async fn register(state: &Arc<AppState>, p: RegisterPayload) -> Result<(), String> {
+    let bootstrap = {
+        let conn = state.db_pool.get().map_err(|e| e.to_string())?;
+        !db::has_users(&conn).map_err(|e| e.to_string())?
+    };
+
+    registration::validate_username(&p.username, bootstrap)?;
+    registration::validate_password(&p.password)?;
+
+    let invite_code = match state.config.registration_mode {
+        _ if bootstrap => None,
+        registration::RegistrationMode::Open => None,
+        registration::RegistrationMode::Invite => {
+            match p.invite_code.as_deref().map(str::trim).filter(|c| !c.is_empty()) {
+                Some(code) => Some(code.to_string()),
+                None => return Err("An invite code is required to register.".to_string()),
             }
         }
+        registration::RegistrationMode::AdminOnly => {
+            return Err("Registration is closed. Ask an administrator for an account.".to_string());
+        }
+    };
+
+    let password_hash = registration::hash_password(p.password).await?;
+
+    let conn = state.db_pool.get().map_err(|e| e.to_string())?;
+    match db::register_user(&conn, &p.username, &password_hash, invite_code.as_deref(), bootstrap) {
+        Ok(db::RegisterOutcome::Created) => {
+            tracing::info!("New user '{}' registered.", p.username);
+            Ok(())
+        }
+        Ok(db::RegisterOutcome::UsernameTaken) => Err("Username is already taken.".to_string()),
+        Ok(db::RegisterOutcome::InvalidInvite) => Err("Invalid or expired invite code.".to_string()),
+        Ok(db::RegisterOutcome::NotFirst) => Err("Another account was just registered as the first one. Please try again.".to_string()),
+        Err(e) => Err(e.to_string()),
     }
 }
 
@@ -326,100 +406,189 @@ async fn authenticate(
     sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
     receiver: &mut futures_util::stream::SplitStream<WebSocket>,
     state: &Arc<AppState>,
-) -> Option<db::User> {
+    client_info: &ClientInfo,
+) -> Option<(db::User, String)> {
     loop {
-        if let Some(Ok(Message::Text(text))) = receiver.next().await {
-            if let Ok(msg) = serde_json::from_str::<WsRequestMessage>(&text) {
-                match msg.r#type.as_str() {
-                    "register" => {
-                        if let Ok(p) = serde_json::from_value::<RegisterPayload>(msg.payload) {
-                            let conn = state.db_pool.get().unwrap();
-                            match db::create_user(&conn, &p.username, &p.password, None) {
-                                Ok(_) => {
-                                    send_ws_message(sender, "register_ok", "Registration successful. Please log in.").await;
-                                }
-                                Err(e) => {
-                                    send_ws_message(sender, "register_fail", e.to_string()).await;
-                                }
-                            }
-                        }
-                        return None; // Close connection after any registration attempt
+        let text = match receiver.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(_)) => continue,
+            Some(Err(_)) | None => return None,
+        };
+        let (request_id, parsed) = ClientMessage::parse(&text);
+        let request_id = request_id.as_ref();
+        let msg = match parsed {
+            Ok(msg) => msg,
+            Err(error) => {
+                send_ws_message(sender, request_id, ServerMessage::Error(error)).await;
+                continue;
+            }
+        };
+        match msg {
+            ClientMessage::Register(p) => {
+                match register(state, p).await {
+                    Ok(()) => {
+                        send_ws_message(sender, request_id, ServerMessage::RegisterOk("Registration successful. Please log in.".to_string())).await;
                     }
-                    "login" => {
-                        if let Ok(p) = serde_json::from_value::<LoginPayload>(msg.payload) {
-                            let conn = state.db_pool.get().unwrap();
-                            if let Ok(user) = db::get_user_by_username(&conn, &p.username) {
-                                if bcrypt::verify(&p.password, &user.password_hash).unwrap_or(false) {
-                                    match db::create_auth_token(&conn, user._id) {
-                                        Ok(token) => {
-                                            let payload = serde_json::json!({
-                                                "username": user.username.clone(),
-                                                "role": user.role.clone(),
-                                                "token": token
-                                            });
-                                            send_ws_message(sender, "auth_ok", payload).await;
-                                            return Some(user);
-                                        }
-                                        Err(_) => {
-                                            send_ws_message(sender, "auth_fail", "Failed to create auth token.").await;
-                                        }
-                                    }
-                                } else {
-                                    send_ws_message(sender, "auth_fail", "Invalid username or password.").await;
-                                }
-                            } else {
-                                send_ws_message(sender, "auth_fail", "Invalid username or password.").await;
+                    Err(e) => {
+                        send_ws_message(sender, request_id, ServerMessage::RegisterFail(e)).await;
+                    }
+                }
+                return None; // Close connection after any registration attempt
+            }
+            ClientMessage::Login(p) => {
+                if let Some(wait) = state.login_limiter.check(&p.username, &client_info.ip) {
+                    send_auth_locked(sender, request_id, wait).await;
+                    return None;
+                }
+
+                let user = {
+                    let conn = state.db_pool.get().unwrap();
+                    db::get_user_by_username(&conn, &p.username).ok()
+                };
+                let hash = user.as_ref().map(|u| u.password_hash.clone());
+                let verified = registration::verify_password(p.password.clone(), hash).await;
+
+                match user {
+                    Some(user) if verified => {
+                        state.login_limiter.record_success(&p.username);
+                        let conn = state.db_pool.get().unwrap();
+                        match db::create_auth_token(&conn, user._id, &client_info.ip, client_info.user_agent.as_deref()) {
+                            Ok(token) => {
+                                let payload = AuthOkPayload {
+                                    username: user.username.clone(),
+                                    role: user.role.clone(),
+                                    token: token.clone(),
+                                    read_receipts_enabled: db::get_read_receipts_enabled(&conn, user._id).unwrap_or(true),
+                                    voice_note_max_secs: state.config.voice_note_max_secs(),
+                                };
+                                send_ws_message(sender, request_id, ServerMessage::AuthOk(payload)).await;
+                                return Some((user, token));
+                            }
+                            Err(_) => {
+                                send_ws_message(sender, request_id, ServerMessage::AuthFail("Failed to create auth token.".to_string())).await;
                             }
                         }
-                        return None; // Close connection on any failed login path
                     }
-                    "auth_with_token" => {
-                        if let Ok(p) = serde_json::from_value::<AuthWithTokenPayload>(msg.payload) {
-                            let conn = state.db_pool.get().unwrap();
-                            if let Ok(user) = db::get_user_by_token(&conn, &p.token) {
-                                let payload = serde_json::json!({
-                                    "username": user.username.clone(),
-                                    "role": user.role.clone(),
-                                    "token": p.token
-                                });
-                                send_ws_message(sender, "auth_ok", payload).await;
-                                return Some(user);
-                            }
+                    _ => {
+                        tracing::warn!("Failed login for '{}' from {}.", p.username, client_info.ip);
+                        match state.login_limiter.record_failure(&p.username, &client_info.ip) {
+                            Some(wait) => send_auth_locked(sender, request_id, wait).await,
+                            None => send_ws_message(sender, request_id, ServerMessage::AuthFail("Invalid username or password.".to_string())).await,
                         }
-                        // If token auth fails, just close the connection.
-                        return None;
                     }
-                    _ => return None,
                 }
+                return None; // Close connection on any failed login path
+            }
+            ClientMessage::AuthWithToken(p) => {
+                let conn = state.db_pool.get().unwrap();
+                match db::get_user_by_token(&conn, &p.token, state.config.token_ttl_secs()) {
+                    Ok(db::TokenLookup::Valid(user)) => {
+                        let _ = db::update_auth_token_client(&conn, &p.token, &client_info.ip, client_info.user_agent.as_deref());
+                        let payload = AuthOkPayload {
+                            username: user.username.clone(),
+                            role: user.role.clone(),
+                            token: p.token.clone(),
+                            read_receipts_enabled: db::get_read_receipts_enabled(&conn, user._id).unwrap_or(true),
+                            voice_note_max_secs: state.config.voice_note_max_secs(),
+                        };
+                        send_ws_message(sender, request_id, ServerMessage::AuthOk(payload)).await;
+                        return Some((user, p.token));
+                    }
+                    Ok(db::TokenLookup::Expired) => {
+                        send_ws_message(sender, request_id, ServerMessage::AuthExpired("Your session has expired. Please log in again.".to_string())).await;
+                    }
+                    Ok(db::TokenLookup::Invalid) => {}
+                    Err(e) => {
+                        tracing::error!("Failed to look up auth token: {}", e);
+                    }
+                }
+                // If token auth fails, just close the connection.
+                return None;
+            }
+            ClientMessage::Unknown => {
+                let error = ErrorPayload::new(ErrorCode::UnknownType, "Unknown message type.");
+                send_ws_message(sender, request_id, ServerMessage::Error(error)).await;
+                return None;
+            }
+            _ => {
+                let error = ErrorPayload::new(ErrorCode::Unauthenticated, "Log in before sending this message.");
+                send_ws_message(sender, request_id, ServerMessage::Error(error)).await;
+                return None;
             }
         }
     }
 }
 
-pub async fn send_ws_message<T: Serialize>(
+async fn send_auth_locked(
     sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
-    r#type: &str,
-    payload: T,
+    request_id: Option<&RequestId>,
+    wait: Duration,
 ) {
-    let resp = WsResponseMessage {
-        r#type: r#type.to_string(),
-        payload: serde_json::to_value(payload).unwrap(),
+    let retry_after_secs = wait.as_secs() + 1;
+    let payload = AuthLockedPayload {
+        retry_after_secs,
+        message: format!("Too many failed login attempts. Try again in {} seconds.", retry_after_secs),
     };
-    if sender.send(Message::Text(serde_json::to_string(&resp).unwrap())).await.is_err() {
+    send_ws_message(sender, request_id, ServerMessage::AuthLocked(payload)).await;
+}
+
+pub async fn send_ws_message(
+    sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
+    request_id: Option<&RequestId>,
+    msg: ServerMessage,
+) {
+    if sender.send(msg.to_reply_frame(request_id)).await.is_err() {
         tracing::warn!("Failed to send direct message to client.");
     }
 }
 
-pub async fn send_ws_message_to<T: Serialize>(
-    sender: &mpsc::UnboundedSender<Message>,
-    r#type: &str,
-    payload: T,
-) {
-    let resp = WsResponseMessage {
-        r#type: r#type.to_string(),
-        payload: serde_json::to_value(payload).unwrap(),
-    };
-    if sender.send(Message::Text(serde_json::to_string(&resp).unwrap())).is_err() {
+/// The connection a request came from. Messages sent through it echo the request's id.
+pub struct Reply<'a> {
+    pub tx: &'a mpsc::UnboundedSender<Message>,
+    pub request_id: Option<&'a RequestId>,
+}
+
+impl<'a> Reply<'a> {
+    /// A target for messages that do not answer any request, such as pushes to other devices.
+    pub fn push(tx: &'a mpsc::UnboundedSender<Message>) -> Self {
+        Reply { tx, request_id: None }
+    }
+
+    pub async fn send(&self, msg: ServerMessage) {
+        if self.tx.send(msg.to_reply_frame(self.request_id)).is_err() {
+            tracing::warn!("Failed to send channel message to client.");
+        }
+    }
+
+    /// Rejects the request without acting on it.
+    pub async fn error(&self, code: ErrorCode, message: &str) {
+        self.send(ServerMessage::Error(ErrorPayload::new(code, message))).await;
+    }
+}
+
+/// Returns the senders of every live connection of a user.
+pub fn user_senders(state: &AppState, user_id: i32) -> Vec<mpsc::UnboundedSender<Message>> {
+    state
+        .online_users
+        .lock()
+        .unwrap()
+        .get(&user_id)
+        .map(|connections| connections.values().map(|c| c.sender.clone()).collect())
+        .unwrap_or_default()
+}
+
+/// Sends a message to every device the user is connected from.
+pub async fn send_ws_message_to_user(state: &AppState, user_id: i32, msg: ServerMessage) {
+    let frame = msg.to_frame();
+    for sender in user_senders(state, user_id) {
+        if sender.send(frame.clone()).is_err() {
+            tracing::warn!("Failed to send channel message to client.");
+        }
+    }
+}
+
+pub async fn send_ws_message_to(sender: &mpsc::UnboundedSender<Message>, msg: ServerMessage) {
+    if sender.send(msg.to_frame()).is_err() {
         tracing::warn!("Failed to send channel message to client.");
     }
 }
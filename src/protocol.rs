@@ -0,0 +1,745 @@
+//! The JSON protocol spoken over the WebSocket. Every text frame is an object of the
+//! form `{"type": "...", "payload": ...}` in both directions. Requests may also carry a
+//! `request_id`, which the server echoes on the replies to that request.
+
+use crate::{db, exports, ratelimit, RoomId};
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+
+// --- Client -> Server ---
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // Authentication, only valid before the connection is authenticated
+    Register(RegisterPayload),
+    Login(LoginPayload),
+    AuthWithToken(AuthWithTokenPayload),
+
+    // Session
+    Logout {},
+    ListSessions {},
+    RevokeSession(RevokeSessionPayload),
+    RevokeAllOtherSessions {},
+    ChangePassword(ChangePasswordPayload),
+    SetReadReceipts(ReadReceiptsSettingPayload),
+
+    // Rooms & chatting
+    JoinRoom(JoinRoomPayload),
+    LoadOlderMessages(LoadOlderMessagesPayload),
+    SendChatMessage(ChatMessagePayload),
+    EditMessage(EditMessagePayload),
+    DeleteMessage(MessageIdPayload),
+    GetMessageEdits(MessageIdPayload),
+    GetThread(MessageIdPayload),
+    AddReaction(ReactionPayload),
+    RemoveReaction(ReactionPayload),
+    GetMentions {},
+    MarkMentionsRead(MarkMentionsReadPayload),
+    SearchMessages(SearchMessagesPayload),
+    ExportRoom(ExportRoomPayload),
+    GetChatList {},
+    MarkRead(MarkReadPayload),
+    TypingStart(RoomIdPayload),
+    TypingStop(RoomIdPayload),
+    RequestVoiceChat {},
+
+    // Group chats
+    CreateGroup(CreateGroupPayload),
+    RenameRoom(RenameRoomPayload),
+    InviteToRoom(RoomMemberPayload),
+    KickFromRoom(RoomMemberPayload),
+    LeaveRoom(RoomIdPayload),
+    SetRoomTopic(SetRoomTopicPayload),
+    SetRoomRole(SetRoomRolePayload),
+    SetRoomPublic(SetRoomPublicPayload),
+    ListPublicRooms(ListPublicRoomsPayload),
+
+    // Friends
+    GetFriendList {},
+    GetFriendRequests {},
+    QuickChatWithFriend(QuickChatPayload),
+    SendFriendRequest(SendFriendRequestPayload),
+    RespondToFriendRequest(RespondToFriendRequestPayload),
+    DeleteFriend(DeleteFriendPayload),
+
+    // Admin
+    AdminGetAllUsers {},
+    AdminGetAllRooms {},
+    AdminCreateUser(AdminCreateUserPayload),
+    AdminDeleteUser(AdminDeleteUserPayload),
+    AdminResetPassword(AdminResetPasswordPayload),
+    AdminGetInvites {},
+    AdminCreateInvite(AdminCreateInvitePayload),
+    AdminRevokeInvite(AdminRevokeInvitePayload),
+    AdminGetLockouts {},
+    AdminClearLockout(AdminClearLockoutPayload),
+    AdminDeleteRoom(AdminDeleteRoomPayload),
+    AdminExportRooms(AdminExportRoomsPayload),
+    AdminChangePort(AdminChangePortPayload),
+    AdminShutdownServer {},
+
+    // Only matches a bare `{"type": ...}`, which is how `parse` tells unknown types apart.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A client-chosen id for a request, echoed verbatim on its replies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(i64),
+    Text(String),
+}
+
+/// The outer shape every request must have before its payload is looked at.
+#[derive(Deserialize)]
+struct Envelope {
+    r#type: String,
+    #[serde(default)]
+    payload: serde_json::Value,
+}
+
+impl ClientMessage {
+    /// Whether only admins may send this message.
+    pub fn requires_admin(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::AdminGetAllUsers {}
+                | ClientMessage::AdminGetAllRooms {}
+                | ClientMessage::AdminCreateUser(_)
+                | ClientMessage::AdminDeleteUser(_)
+                | ClientMessage::AdminResetPassword(_)
+                | ClientMessage::AdminGetInvites {}
+                | ClientMessage::AdminCreateInvite(_)
+                | ClientMessage::AdminRevokeInvite(_)
+                | ClientMessage::AdminGetLockouts {}
+                | ClientMessage::AdminClearLockout(_)
+                | ClientMessage::AdminDeleteRoom(_)
+                | ClientMessage::AdminExportRooms(_)
+                | ClientMessage::AdminChangePort(_)
+                | ClientMessage::AdminShutdownServer {}
+        )
+    }
+
+    /// The room and the lowest role in it needed to send this message, if it is
+    /// restricted by room role.
+    pub fn required_room_role(&self) -> Option<(RoomId, db::RoomRole)> {
+        match self {
+            ClientMessage::RenameRoom(p) => Some((p.room_id, db::RoomRole::Moderator)),
+            ClientMessage::SetRoomTopic(p) => Some((p.room_id, db::RoomRole::Moderator)),
+            ClientMessage::KickFromRoom(p) => Some((p.room_id, db::RoomRole::Moderator)),
+            ClientMessage::SetRoomRole(p) => Some((p.room_id, db::RoomRole::Owner)),
+            ClientMessage::SetRoomPublic(p) => Some((p.room_id, db::RoomRole::Owner)),
+            _ => None,
+        }
+    }
+
+    /// Parses a text frame, telling malformed JSON, unknown types and bad payloads apart.
+    /// The request id is returned even when the request is rejected, so the error can echo it.
+    pub fn parse(text: &str) -> (Option<RequestId>, Result<ClientMessage, ErrorPayload>) {
+        let value: serde_json::Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(e) => return (None, Err(ErrorPayload::new(ErrorCode::Malformed, e.to_string()))),
+        };
+
+        let request_id = match value.get("request_id") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(id) => match RequestId::deserialize(id) {
+                Ok(id) => Some(id),
+                Err(_) => {
+                    let error = ErrorPayload::new(ErrorCode::Malformed, "request_id must be a string or an integer.");
+                    return (None, Err(error));
+                }
+            },
+        };
+
+        (request_id, Self::from_value(value))
+    }
+
+    fn from_value(value: serde_json::Value) -> Result<ClientMessage, ErrorPayload> {
+        let envelope: Envelope = serde_json::from_value(value)
+            .map_err(|e| ErrorPayload::new(ErrorCode::Malformed, e.to_string()))?;
+
+        // Requests without arguments may omit the payload or send null.
+        let payload = match envelope.payload {
+            serde_json::Value::Null => serde_json::json!({}),
+            payload => payload,
+        };
+
+        serde_json::from_value(serde_json::json!({ "type": &envelope.r#type, "payload": payload })).map_err(|e| {
+            let bare = serde_json::from_value(serde_json::json!({ "type": &envelope.r#type }));
+            if matches!(bare, Ok(ClientMessage::Unknown)) {
+                ErrorPayload::new(ErrorCode::UnknownType, format!("Unknown message type '{}'.", envelope.r#type))
+            } else {
+                ErrorPayload::new(ErrorCode::InvalidPayload, format!("Invalid payload for '{}': {}", envelope.r#type, e))
+            }
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterPayload {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub invite_code: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoginPayload {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthWithTokenPayload {
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinRoomPayload {
+    pub room_id: RoomId,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadOlderMessagesPayload {
+    pub room_id: RoomId,
+    pub before_id: i32,
+    pub limit: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EditMessagePayload {
+    pub message_id: i32,
+    pub content: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageIdPayload {
+    pub message_id: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionPayload {
+    pub message_id: i32,
+    pub emoji: String,
+}
+
+/// Mentions to mark as read. All of the user's mentions if `ids` is missing.
+#[derive(Deserialize, Debug)]
+pub struct MarkMentionsReadPayload {
+    pub ids: Option<Vec<i64>>,
+}
+
+/// Moves the read marker to `messageId`, or to the room's latest message if missing.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkReadPayload {
+    pub room_id: RoomId,
+    pub message_id: Option<i32>,
+}
+
+/// Asks for a download of the room's whole history.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportRoomPayload {
+    pub room_id: RoomId,
+    pub format: exports::ExportFormat,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomIdPayload {
+    pub room_id: RoomId,
+}
+
+/// A new group chat. `members` are usernames of the creator's friends. Public groups
+/// are listed in the room directory for anyone to join.
+#[derive(Deserialize, Debug)]
+pub struct CreateGroupPayload {
+    pub name: String,
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub public: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameRoomPayload {
+    pub room_id: RoomId,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomMemberPayload {
+    pub room_id: RoomId,
+    pub username: String,
+}
+
+/// Sets the room's topic. An empty topic clears it.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SetRoomTopicPayload {
+    pub room_id: RoomId,
+    pub topic: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SetRoomPublicPayload {
+    pub room_id: RoomId,
+    pub public: bool,
+}
+
+/// Searches the public groups by name and topic. All of them if `query` is missing.
+#[derive(Deserialize, Debug)]
+pub struct ListPublicRoomsPayload {
+    pub query: Option<String>,
+    pub limit: Option<u32>,
+}
+
+/// Makes a participant a member or moderator. Giving someone the `owner` role hands
+/// the room over to them.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SetRoomRolePayload {
+    pub room_id: RoomId,
+    pub username: String,
+    pub role: db::RoomRole,
+}
+
+/// Full-text search over the caller's rooms. `after`/`before` bound the message
+/// timestamp (UTC); `beforeId` pages back from the last result of a previous search.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchMessagesPayload {
+    pub query: String,
+    pub room_id: Option<RoomId>,
+    pub sender: Option<String>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+    pub before_id: Option<i32>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatMessagePayload {
+    pub room_id: RoomId,
+    pub content: String,
+    /// Id of an earlier message in the same room that this one answers.
+    pub reply_to: Option<i32>,
+    /// Ids of files the sender uploaded and has not sent yet.
+    #[serde(default)]
+    pub attachments: Vec<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SendFriendRequestPayload {
+    pub username: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RespondToFriendRequestPayload {
+    pub request_id: i32,
+    pub accept: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QuickChatPayload {
+    pub friend_id: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteFriendPayload {
+    pub friend_id: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokeSessionPayload {
+    pub session_id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordPayload {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+/// Whether the user shares read receipts in one-on-one rooms. Sent by the client to
+/// change the setting and echoed back by the server.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ReadReceiptsSettingPayload {
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminCreateUserPayload {
+    pub username: String,
+    pub password: String,
+    pub role: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminDeleteUserPayload {
+    pub user_id: i32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminResetPasswordPayload {
+    pub user_id: i32,
+    pub new_password: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminClearLockoutPayload {
+    pub kind: ratelimit::LockoutKind,
+    pub key: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminCreateInvitePayload {
+    pub max_uses: Option<u32>, // None for unlimited
+    pub expires_in_hours: Option<u64>, // None never expires
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminRevokeInvitePayload {
+    pub code: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminDeleteRoomPayload {
+    pub room_id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminExportRoomsPayload {
+    pub format: exports::ExportFormat,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminChangePortPayload {
+    pub port: u16,
+}
+
+// --- Server -> Client ---
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ServerMessage {
+    // Authentication
+    AuthOk(AuthOkPayload),
+    AuthFail(String),
+    AuthLocked(AuthLockedPayload),
+    AuthExpired(String),
+    RegisterOk(String),
+    RegisterFail(String),
+
+    // Session
+    LogoutOk {},
+    SessionRevoked {},
+    SessionList(Vec<db::SessionInfo>),
+    SessionError(FailurePayload),
+    ChangePasswordOk {},
+    ChangePasswordFail(FailurePayload),
+    ReadReceiptsSetting(ReadReceiptsSettingPayload),
+
+    // Rooms & chatting
+    JoinOk(JoinOkPayload),
+    /// The newest page of a room's history, sent on join.
+    MessageHistory(MessagePage),
+    /// A page of history before a given message, in answer to `load_older_messages`.
+    OlderMessages(MessagePage),
+    NewChatMessage(db::ChatMessage),
+    MessageAck(MessageAckPayload),
+    MessageEdited(MessageEditedPayload),
+    MessageDeleted(MessageDeletedPayload),
+    MessageEdits(MessageEditsPayload),
+    Thread(ThreadPayload),
+    ReactionUpdated(ReactionUpdatedPayload),
+    /// Pushed to every connection of a user named in a new message.
+    Mention(db::MentionInfo),
+    /// The user's unread mentions, newest first.
+    Mentions(Vec<db::MentionInfo>),
+    SearchResults(SearchResultsPayload),
+    /// Where to download an export asked for with `export_room` or `admin_export_rooms`.
+    ExportReady(ExportReadyPayload),
+    ChatList(Vec<db::RoomInfo>),
+    /// A room's unread count or latest message changed. Pushed to connections that don't
+    /// have the room open, and sent to every connection of a user who marks it read.
+    RoomUnread(RoomUnreadPayload),
+    /// Another participant of a one-on-one room has read up to `message_id`.
+    ReadReceipt(ReadReceiptPayload),
+    /// A room in the user's chat list was created, changed or left. Sent to every
+    /// member affected, on every connection.
+    RoomUpdated(RoomUpdatedPayload),
+    /// Public groups matching a `list_public_rooms` search.
+    PublicRooms(Vec<db::PublicRoomInfo>),
+    /// Another user with the room open started or stopped typing.
+    Typing(TypingUpdatePayload),
+    Invitation(InvitationPayload),
+    VoiceChatInvitation(VoiceChatInvitationPayload),
+
+    // Friends
+    FriendList(Vec<FriendInfo>),
+    FriendRequests(Vec<db::FriendRequestInfo>),
+    NewFriendRequest(db::FriendRequestInfo),
+    FriendRequestSent(FriendRequestSentPayload),
+    FriendRequestAccepted(FriendRequestUpdatePayload),
+    FriendRequestRejected(FriendRequestUpdatePayload),
+    FriendRequestFail(FailurePayload),
+
+    // Admin
+    AdminAllUsers(Vec<db::User>),
+    AdminAllRooms(Vec<db::AdminRoomInfo>),
+    AdminInvites(Vec<db::InviteCode>),
+    AdminLockouts(Vec<ratelimit::LockoutInfo>),
+    AdminCreateUserOk(String),
+    AdminCreateUserFail(FailurePayload),
+    AdminChangePortOk {},
+    AdminChangePortFail(FailurePayload),
+    AdminGenericOk(String),
+    AdminError(FailurePayload),
+
+    /// A request that was rejected outright. See `ErrorCode`.
+    Error(ErrorPayload),
+    /// Confirms a request that has no other reply, such as `typing_start`.
+    Ok {},
+}
+
+/// A server message as sent on the wire, with the id of the request it answers.
+#[derive(Serialize)]
+struct Frame<'a> {
+    #[serde(flatten)]
+    message: &'a ServerMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a RequestId>,
+}
+
+impl ServerMessage {
+    /// Serializes the message into a WebSocket text frame.
+    pub fn to_frame(&self) -> Message {
+        self.to_reply_frame(None)
+    }
+
+    /// Serializes the message as a reply to the request with the given id.
+    pub fn to_reply_frame(&self, request_id: Option<&RequestId>) -> Message {
+        let frame = Frame { message: self, request_id };
+        Message::Text(serde_json::to_string(&frame).expect("server messages always serialize"))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuthOkPayload {
+    pub username: String,
+    pub role: String,
+    pub token: String,
+    pub read_receipts_enabled: bool,
+    /// Longest voice note that can be uploaded, in seconds.
+    pub voice_note_max_secs: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuthLockedPayload {
+    pub retry_after_secs: u64,
+    pub message: String,
+}
+
+/// A human-readable reason an operation failed, as used by the `*_fail` and `*_error` replies.
+#[derive(Serialize, Debug)]
+pub struct FailurePayload {
+    pub error: String,
+}
+
+impl FailurePayload {
+    pub fn new(error: impl Into<String>) -> Self {
+        FailurePayload { error: error.into() }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinOkPayload {
+    pub room_id: RoomId,
+}
+
+/// A run of consecutive messages in a room, oldest first.
+#[derive(Serialize, Debug)]
+pub struct MessagePage {
+    pub room_id: RoomId,
+    pub messages: Vec<db::ChatMessage>,
+    /// Whether older messages exist before the first one in `messages`.
+    pub has_more: bool,
+}
+
+/// Confirms to the sender that a chat message was stored.
+#[derive(Serialize, Debug)]
+pub struct MessageAckPayload {
+    pub id: i32,
+    pub room_id: RoomId,
+    pub timestamp: String,
+}
+
+/// Sent to everyone in the room when a message is edited, and to the editor.
+#[derive(Serialize, Debug)]
+pub struct MessageEditedPayload {
+    pub id: i32,
+    pub room_id: RoomId,
+    pub content: String,
+    pub edited_at: Option<String>,
+}
+
+/// Sent to everyone in the room when a message is deleted, and to whoever deleted it.
+#[derive(Serialize, Debug)]
+pub struct MessageDeletedPayload {
+    pub id: i32,
+    pub room_id: RoomId,
+    pub deleted_at: Option<String>,
+}
+
+/// Previous versions of a message, oldest first.
+#[derive(Serialize, Debug)]
+pub struct MessageEditsPayload {
+    pub message_id: i32,
+    pub edits: Vec<db::MessageEdit>,
+}
+
+/// A message and every reply below it, oldest first.
+#[derive(Serialize, Debug)]
+pub struct ThreadPayload {
+    pub root: db::ChatMessage,
+    pub replies: Vec<db::ChatMessage>,
+}
+
+/// The reactions of a message after one was added or removed, as the recipient sees them.
+#[derive(Serialize, Debug)]
+pub struct ReactionUpdatedPayload {
+    pub message_id: i32,
+    pub room_id: RoomId,
+    pub reactions: Vec<db::ReactionSummary>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RoomUnreadPayload {
+    pub room_id: RoomId,
+    pub unread_count: u32,
+    pub last_message: Option<db::LastMessagePreview>,
+}
+
+/// The room as it now appears in the user's chat list, or None if they are no longer
+/// part of it.
+#[derive(Serialize, Debug)]
+pub struct RoomUpdatedPayload {
+    pub room_id: RoomId,
+    pub room: Option<db::RoomInfo>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TypingUpdatePayload {
+    pub room_id: RoomId,
+    pub username: String,
+    pub typing: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReadReceiptPayload {
+    pub room_id: RoomId,
+    pub user: String,
+    pub message_id: i32,
+}
+
+/// Matches for a `search_messages` request, newest first.
+#[derive(Serialize, Debug)]
+pub struct SearchResultsPayload {
+    pub query: String,
+    pub results: Vec<db::SearchResult>,
+    /// Whether older matches exist after the last one in `results`.
+    pub has_more: bool,
+}
+
+/// `url` needs the login token, as a Bearer header or the `session` cookie set by `POST /session`.
+#[derive(Serialize, Debug)]
+pub struct ExportReadyPayload {
+    pub room_id: Option<RoomId>, // None for an export of every room
+    pub format: exports::ExportFormat,
+    pub url: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct InvitationPayload {
+    pub from_username: String,
+    pub room_id: RoomId,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct VoiceChatInvitationPayload {
+    pub from_username: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FriendInfo {
+    pub id: i32,
+    pub username: String,
+    pub is_online: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FriendRequestSentPayload {
+    pub username: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FriendRequestUpdatePayload {
+    pub from_username: String,
+}
+
+/// Why a request was rejected without being acted on.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame was not JSON or had no `type`.
+    Malformed,
+    /// The `type` is not one the server knows.
+    UnknownType,
+    /// The payload is missing fields or has the wrong shape for its `type`.
+    InvalidPayload,
+    /// The request needs an authenticated connection.
+    Unauthenticated,
+    /// Login or registration was sent on an already authenticated connection.
+    AlreadyAuthenticated,
+    /// The user is not allowed to do this.
+    Forbidden,
+    /// The request refers to a room the connection has not joined.
+    NotInRoom,
+    /// The message or other object the request refers to does not exist.
+    NotFound,
+    /// The server failed while handling an otherwise valid request.
+    Internal,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorPayload {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorPayload { code, message: message.into() }
+    }
+}
@@ -0,0 +1,257 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Failure records older than this are forgotten once they are no longer locked.
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 3600);
+
+/// Whether a limiter entry tracks a username or a source IP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockoutKind {
+    Username,
+    Ip,
+}
+
+/// Thresholds for the login limiter, taken from `Config`.
+#[derive(Debug, Clone)]
+pub struct LoginPolicy {
+    pub max_attempts_per_user: u32,
+    pub max_attempts_per_ip: u32,
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+struct Entry {
+    failures: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// A lockout as shown in the admin panel.
+#[derive(Debug, Serialize)]
+pub struct LockoutInfo {
+    pub kind: LockoutKind,
+    pub key: String,
+    pub failures: u32,
+    pub locked_for_secs: u64,
+}
+
+/// Counts failed logins per username and per source IP. Once a key passes its
+/// threshold it is locked, and every further failure doubles the lockout.
+pub struct LoginLimiter {
+    policy: LoginPolicy,
+    entries: Mutex<HashMap<(LockoutKind, String), Entry>>,
+}
+
+impl LoginLimiter {
+    pub fn new(policy: LoginPolicy) -> Self {
+        LoginLimiter {
+            policy,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns how long the caller must wait if either the username or the IP is locked.
+    pub fn check(&self, username: &str, ip: &str) -> Option<Duration> {
+        self.check_at(Instant::now(), username, ip)
+    }
+
+    fn check_at(&self, now: Instant, username: &str, ip: &str) -> Option<Duration> {
+        let entries = self.entries.lock().unwrap();
+        [(LockoutKind::Username, username), (LockoutKind::Ip, ip)]
+            .iter()
+            .filter_map(|(kind, key)| entries.get(&(*kind, normalize(*kind, key))))
+            .filter_map(|e| e.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max()
+    }
+
+    /// Records a failed attempt against both keys and returns the resulting lockout, if any.
+    pub fn record_failure(&self, username: &str, ip: &str) -> Option<Duration> {
+        self.record_failure_at(Instant::now(), username, ip)
+    }
+
+    fn record_failure_at(&self, now: Instant, username: &str, ip: &str) -> Option<Duration> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| {
+            e.locked_until.is_some_and(|until| until > now) || now - e.last_failure < FORGET_AFTER
+        });
+
+        let mut lockout = None;
+        for (kind, key, threshold) in [
+            (LockoutKind::Username, username, self.policy.max_attempts_per_user),
+            (LockoutKind::Ip, ip, self.policy.max_attempts_per_ip),
+        ] {
+            let entry = entries.entry((kind, normalize(kind, key))).or_insert(Entry {
+                failures: 0,
+                locked_until: None,
+                last_failure: now,
+            });
+            entry.failures += 1;
+            entry.last_failure = now;
+
+            if entry.failures >= threshold {
+                let duration = self.lockout_duration(entry.failures - threshold);
+                entry.locked_until = Some(now + duration);
+                lockout = lockout.max(Some(duration));
+            }
+        }
+        lockout
+    }
+
+    /// Clears the username counter after a successful login. The IP counter is left to
+    /// expire so one valid account cannot be used to reset guessing from that address.
+    pub fn record_success(&self, username: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&(LockoutKind::Username, normalize(LockoutKind::Username, username)));
+    }
+
+    /// Lists every key with recorded failures, locked ones first.
+    pub fn list(&self) -> Vec<LockoutInfo> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        let mut list: Vec<LockoutInfo> = entries
+            .iter()
+            .map(|((kind, key), e)| LockoutInfo {
+                kind: *kind,
+                key: key.clone(),
+                failures: e.failures,
+                locked_for_secs: e
+                    .locked_until
+                    .filter(|until| *until > now)
+                    .map(|until| (until - now).as_secs() + 1)
+                    .unwrap_or(0),
+            })
+            .collect();
+        list.sort_by(|a, b| b.locked_for_secs.cmp(&a.locked_for_secs).then(a.key.cmp(&b.key)));
+        list
+    }
+
+    /// Removes a key's failure record and lockout. Returns false if there was none.
+    pub fn clear(&self, kind: LockoutKind, key: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&(kind, normalize(kind, key)))
+            .is_some()
+    }
+
+    fn lockout_duration(&self, excess_failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(excess_failures.min(16));
+        self.policy
+            .base_lockout
+            .saturating_mul(factor)
+            .min(self.policy.max_lockout)
+    }
+}
+
+fn normalize(kind: LockoutKind, key: &str) -> String {
+    match kind {
+        LockoutKind::Username => key.to_lowercase(),
+        LockoutKind::Ip => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: Duration = Duration::from_secs(30);
+
+    fn limiter() -> LoginLimiter {
+        LoginLimiter::new(LoginPolicy {
+            max_attempts_per_user: 3,
+            max_attempts_per_ip: 5,
+            base_lockout: BASE,
+            max_lockout: Duration::from_secs(100),
+        })
+    }
+
+    #[test]
+    fn lockout_doubles_with_each_failure_up_to_the_limit() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let lockouts: Vec<_> = (0..6).map(|i| limiter.record_failure_at(now, "alice", &format!("10.0.0.{}", i))).collect();
+        let secs = |n| Some(Duration::from_secs(n));
+        assert_eq!(lockouts, [None, None, secs(30), secs(60), secs(100), secs(100)]);
+        assert_eq!(limiter.check_at(now, "alice", "10.0.0.99"), secs(100));
+    }
+
+    #[test]
+    fn usernames_are_case_insensitive() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for name in ["alice", "Alice", "ALICE"] {
+            limiter.record_failure_at(now, name, "10.0.0.1");
+        }
+        assert_eq!(limiter.check_at(now, "aLiCe", "10.0.0.2"), Some(BASE));
+    }
+
+    #[test]
+    fn the_ip_is_locked_across_usernames() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for i in 0..5 {
+            limiter.record_failure_at(now, &format!("user{}", i), "10.0.0.1");
+        }
+        assert_eq!(limiter.check_at(now, "someone_else", "10.0.0.1"), Some(BASE));
+        assert_eq!(limiter.check_at(now, "someone_else", "10.0.0.2"), None);
+    }
+
+    #[test]
+    fn success_resets_the_username_but_not_the_ip() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..5 {
+            limiter.record_failure_at(now, "alice", "10.0.0.1");
+        }
+        limiter.record_success("alice");
+        assert_eq!(limiter.check_at(now, "alice", "10.0.0.2"), None);
+        assert!(limiter.check_at(now, "alice", "10.0.0.1").is_some());
+        // The username starts counting from zero again.
+        assert_eq!(limiter.record_failure_at(now, "alice", "10.0.0.2"), None);
+    }
+
+    #[test]
+    fn lockouts_expire() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.record_failure_at(now, "alice", "10.0.0.1");
+        }
+        assert_eq!(limiter.check_at(now + BASE / 2, "alice", "10.0.0.2"), Some(BASE / 2));
+        assert_eq!(limiter.check_at(now + BASE, "alice", "10.0.0.2"), None);
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.record_failure_at(now, "alice", "10.0.0.1");
+        }
+        // Any later failure prunes records that are unlocked and old enough.
+        limiter.record_failure_at(now + FORGET_AFTER, "bob", "10.0.0.2");
+        let keys: Vec<_> = limiter.list().into_iter().map(|info| info.key).collect();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&"bob".to_string()) && keys.contains(&"10.0.0.2".to_string()));
+        assert_eq!(limiter.record_failure_at(now + FORGET_AFTER, "alice", "10.0.0.3"), None);
+    }
+
+    #[test]
+    fn cleared_keys_are_unlocked() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.record_failure_at(now, "alice", "10.0.0.1");
+        }
+        assert!(limiter.clear(LockoutKind::Username, "ALICE"));
+        assert!(!limiter.clear(LockoutKind::Username, "alice"));
+        assert_eq!(limiter.check_at(now, "alice", "10.0.0.2"), None);
+    }
+}
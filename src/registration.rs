@@ -0,0 +1,93 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+pub const USERNAME_MIN_LEN: usize = 3;
+pub const USERNAME_MAX_LEN: usize = 32;
+pub const PASSWORD_MIN_LEN: usize = 8;
+// bcrypt ignores everything after the first 72 bytes.
+pub const PASSWORD_MAX_BYTES: usize = 72;
+
+// Names that could be mistaken for the server or its staff. Compared case-insensitively.
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "root", "system", "server", "moderator", "support", "null", "undefined",
+];
+
+/// Who may create an account through the `register` message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    /// Anyone can register.
+    #[default]
+    Open,
+    /// Registration requires a valid invite code minted by an admin.
+    Invite,
+    /// Only admins can create accounts, from the admin panel.
+    AdminOnly,
+}
+
+/// Checks a new username against the length, character and reserved-name rules.
+/// Admins creating accounts may use reserved names.
+pub fn validate_username(username: &str, allow_reserved: bool) -> Result<(), String> {
+    let len = username.chars().count();
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+        return Err(format!(
+            "Username must be between {} and {} characters.",
+            USERNAME_MIN_LEN, USERNAME_MAX_LEN
+        ));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+        return Err("Username may only contain letters, digits, '_', '-' and '.'.".to_string());
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("Username must start with a letter or digit.".to_string());
+    }
+    if !allow_reserved && RESERVED_USERNAMES.iter().any(|r| r.eq_ignore_ascii_case(username)) {
+        return Err("This username is reserved.".to_string());
+    }
+    Ok(())
+}
+
+/// Checks a new password against the length rules.
+pub fn validate_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < PASSWORD_MIN_LEN {
+        return Err(format!("Password must be at least {} characters.", PASSWORD_MIN_LEN));
+    }
+    if password.len() > PASSWORD_MAX_BYTES {
+        return Err(format!("Password must be at most {} bytes.", PASSWORD_MAX_BYTES));
+    }
+    Ok(())
+}
+
+/// Hashes a password for storage. bcrypt is deliberately slow, so this runs off the async workers.
+pub async fn hash_password(password: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || bcrypt::hash(password, bcrypt::DEFAULT_COST))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+lazy_static! {
+    // Checked in place of a missing user's hash, so a login takes as long whether or not
+    // the username exists.
+    static ref DUMMY_HASH: String = bcrypt::hash("not anyone's password", bcrypt::DEFAULT_COST).unwrap();
+}
+
+/// Checks a password against a stored hash, off the async workers. With no hash, which
+/// is the case for unknown users, it spends the same time and fails.
+pub async fn verify_password(password: String, hash: Option<String>) -> bool {
+    tokio::task::spawn_blocking(move || match hash {
+        Some(hash) => bcrypt::verify(password, &hash).unwrap_or(false),
+        None => {
+            let _ = bcrypt::verify(password, &DUMMY_HASH);
+            false
+        }
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Computes the dummy hash up front, so the first login for an unknown user is not
+/// slower than the rest.
+pub fn prepare_dummy_hash() {
+    lazy_static::initialize(&DUMMY_HASH);
+}
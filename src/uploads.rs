@@ -0,0 +1,419 @@
+use crate::db;
+use crate::images;
+use crate::voice_notes;
+use crate::protocol::{ErrorCode, ErrorPayload};
+use crate::AppState;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Where uploaded files are kept, each named after the SHA-256 of its content.
+pub const UPLOAD_DIR: &str = "uploads";
+// Longest file name kept for an upload, in characters.
+const MAX_FILENAME_CHARS: usize = 255;
+// How long an upload may wait to be sent with a message before it is removed.
+const UNSENT_UPLOAD_TTL_SECS: u64 = 24 * 3600;
+// Files changed this recently are kept, since an upload may not have recorded its file yet.
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(3600);
+const ORPHAN_SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    /// Name to show for the file; only its last path component is kept.
+    name: Option<String>,
+    /// Marks the file as a recorded voice note, which must be a WAV recording.
+    #[serde(default)]
+    voice: bool,
+}
+
+/// Cookie that lets `<img>`, `<audio>` and `<a>` tags authenticate downloads, since they
+/// cannot set headers. It holds the login token, out of reach of scripts and never in a URL.
+pub const SESSION_COOKIE: &str = "session";
+
+pub fn error_response(status: StatusCode, code: ErrorCode, message: &str) -> Response {
+    (status, Json(ErrorPayload::new(code, message))).into_response()
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn cookie_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| match pair.trim().split_once('=') {
+            Some((name, token)) if name == SESSION_COOKIE => Some(token),
+            _ => None,
+        })
+}
+
+fn check_token(state: &AppState, token: Option<&str>) -> Result<db::User, (StatusCode, ErrorCode, &'static str)> {
+    let Some(token) = token else {
+        return Err((StatusCode::UNAUTHORIZED, ErrorCode::Unauthenticated, "Log in before uploading or downloading files."));
+    };
+
+    let conn = state.db_pool.get().unwrap();
+    match db::get_user_by_token(&conn, token.trim(), state.config.token_ttl_secs()) {
+        Ok(db::TokenLookup::Valid(user)) => Ok(user),
+        Ok(_) => Err((StatusCode::UNAUTHORIZED, ErrorCode::Unauthenticated, "Your session is invalid or has expired.")),
+        Err(e) => {
+            tracing::error!("Failed to check auth token: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Failed to check your session."))
+        }
+    }
+}
+
+/// Finds the user behind the `Authorization: Bearer` header.
+pub fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<db::User, (StatusCode, ErrorCode, &'static str)> {
+    check_token(state, bearer_token(headers))
+}
+
+/// Like `authenticate`, but also accepts the session cookie. Only for requests that read,
+/// since the cookie is sent without the page asking for it.
+pub fn authenticate_download(state: &AppState, headers: &HeaderMap) -> Result<db::User, (StatusCode, ErrorCode, &'static str)> {
+    check_token(state, bearer_token(headers).or_else(|| cookie_token(headers)))
+}
+
+fn session_cookie(token: &str, max_age_secs: u64) -> String {
+    format!("{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}", SESSION_COOKIE, token, max_age_secs)
+}
+
+/// `POST /session`, sets the session cookie to the login token in the `Authorization` header.
+pub async fn set_session_cookie(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Err((status, code, text)) = authenticate(&state, &headers) {
+        return error_response(status, code, text);
+    }
+    let token = bearer_token(&headers).unwrap_or_default().trim();
+    match HeaderValue::from_str(&session_cookie(token, state.config.token_ttl_secs())) {
+        Ok(cookie) => (StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)]).into_response(),
+        Err(_) => error_response(StatusCode::BAD_REQUEST, ErrorCode::InvalidPayload, "The login token is malformed."),
+    }
+}
+
+/// `DELETE /session`, removes the session cookie.
+pub async fn clear_session_cookie() -> Response {
+    let cookie = HeaderValue::from_str(&session_cookie("", 0)).unwrap();
+    (StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)]).into_response()
+}
+
+/// Keeps the last path component of a client-supplied name, without control characters.
+fn clean_filename(name: Option<&str>) -> String {
+    let name = name.unwrap_or_default();
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base.chars().filter(|c| !c.is_control()).take(MAX_FILENAME_CHARS).collect();
+    match cleaned.trim() {
+        "" | "." | ".." => "file".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+fn blob_path(sha256: &str) -> PathBuf {
+    PathBuf::from(UPLOAD_DIR).join(&sha256[..2]).join(sha256)
+}
+
+fn thumbnail_path(sha256: &str) -> PathBuf {
+    blob_path(sha256).with_extension("thumb")
+}
+
+/// Writes the content to `path` unless an identical file is already stored there.
+async fn store_blob(path: PathBuf, data: &[u8]) -> std::io::Result<()> {
+    if tokio::fs::try_exists(&path).await? {
+        // Mark the file as recent so the orphan sweep leaves it to the new upload.
+        let file = tokio::fs::OpenOptions::new().write(true).open(&path).await?;
+        return file.into_std().await.set_modified(SystemTime::now());
+    }
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    // Write to a temporary name first so a half-written file is never served.
+    let partial = path.with_extension(format!("{}.part", uuid::Uuid::new_v4()));
+    tokio::fs::write(&partial, data).await?;
+    tokio::fs::rename(&partial, &path).await
+}
+
+/// An upload as it is stored.
+struct ProcessedUpload {
+    data: Bytes,
+    mime_type: &'static str,
+    thumbnail: Option<Vec<u8>>,
+    details: db::MediaDetails<'static>,
+}
+
+/// Converts voice notes, given with their longest allowed duration, and cleans up images, refusing images that cannot be cleaned.
+/// Other files are kept as they are.
+fn process_upload(body: Bytes, mime_type: &'static str, voice: Option<u32>) -> Result<ProcessedUpload, String> {
+    if let Some(max_duration_secs) = voice {
+        let note = voice_notes::encode(&body, max_duration_secs)?;
+        return Ok(ProcessedUpload {
+            data: note.data.into(),
+            mime_type: "audio/wav",
+            thumbnail: None,
+            details: db::MediaDetails { duration_ms: Some(note.duration_ms), ..Default::default() },
+        });
+    }
+    Ok(match images::process(&body, mime_type)? {
+        Some(image) => ProcessedUpload {
+            data: image.data.into(),
+            mime_type,
+            thumbnail: Some(image.thumbnail),
+            details: db::MediaDetails {
+                width: Some(image.width),
+                height: Some(image.height),
+                thumbnail_mime: Some(image.thumbnail_mime),
+                duration_ms: None,
+            },
+        },
+        None => ProcessedUpload { data: body, mime_type, thumbnail: None, details: db::MediaDetails::default() },
+    })
+}
+
+/// `POST /upload?name=...` with the file as the request body. Replies with the new attachment.
+pub async fn upload(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<UploadParams>,
+    body: Bytes,
+) -> Response {
+    let user = match authenticate(&state, &headers) {
+        Ok(user) => user,
+        Err((status, code, text)) => return error_response(status, code, text),
+    };
+    if body.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, ErrorCode::InvalidPayload, "The file is empty.");
+    }
+    // The route admits the larger raw recordings of voice notes, which shrink once encoded.
+    if !params.voice && body.len() > state.config.max_upload_bytes() {
+        return error_response(StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::InvalidPayload, "The file is too large.");
+    }
+
+    let mime_type = infer::get(&body).map_or("application/octet-stream", |kind| kind.mime_type());
+    // Decoding and encoding are CPU-bound, so keep them off the async workers.
+    let voice = params.voice.then(|| state.config.voice_note_max_secs());
+    let upload = match tokio::task::spawn_blocking(move || process_upload(body, mime_type, voice)).await {
+        Ok(Ok(upload)) => upload,
+        Ok(Err(message)) => return error_response(StatusCode::BAD_REQUEST, ErrorCode::InvalidPayload, &message),
+        Err(e) => {
+            tracing::error!("Failed to process upload: {}", e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Failed to store the file.");
+        }
+    };
+
+    // The thumbnail goes first, so a stored image always has one.
+    let sha256 = format!("{:x}", Sha256::digest(&upload.data));
+    let mut stored = Ok(());
+    if let Some(thumbnail) = &upload.thumbnail {
+        stored = store_blob(thumbnail_path(&sha256), thumbnail).await;
+    }
+    if stored.is_ok() {
+        stored = store_blob(blob_path(&sha256), &upload.data).await;
+    }
+    if let Err(e) = stored {
+        tracing::error!("Failed to store upload {}: {}", sha256, e);
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Failed to store the file.");
+    }
+
+    let filename = clean_filename(params.name.as_deref());
+    let conn = state.db_pool.get().unwrap();
+    match db::create_attachment(&conn, &sha256, upload.data.len() as i64, upload.mime_type, &filename, user._id, &upload.details) {
+        Ok(attachment) => {
+            tracing::info!("User '{}' uploaded attachment {} ({} bytes, {}).", user.username, attachment.id, attachment.size, upload.mime_type);
+            (StatusCode::CREATED, Json(attachment)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to record upload: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Failed to store the file.")
+        }
+    }
+}
+
+/// Encodes a file name for `Content-Disposition` as RFC 5987 UTF-8.
+pub fn encode_filename(filename: &str) -> String {
+    filename
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Checks that the user may see the attachment. Unsent uploads are only shown to their
+/// uploader, sent ones to the participants of the message's room.
+fn authorize_download(state: &AppState, user: &db::User, attachment_id: i64) -> Result<db::StoredAttachment, (StatusCode, ErrorCode, &'static str)> {
+    let conn = state.db_pool.get().unwrap();
+    let stored = match db::get_stored_attachment(&conn, attachment_id) {
+        Ok(Some(stored)) if !stored.message_deleted => stored,
+        Ok(_) => return Err((StatusCode::NOT_FOUND, ErrorCode::NotFound, "Attachment not found.")),
+        Err(e) => {
+            tracing::error!("Failed to load attachment {}: {}", attachment_id, e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Failed to load the attachment."));
+        }
+    };
+    let allowed = match stored.room_id {
+        Some(room_id) => db::is_room_participant(&conn, room_id, user._id).unwrap_or(false),
+        None => stored.uploader_id == user._id,
+    };
+    if !allowed {
+        tracing::warn!("User '{}' was refused attachment {}.", user.username, attachment_id);
+        return Err((StatusCode::FORBIDDEN, ErrorCode::Forbidden, "You are not a member of this room."));
+    }
+    Ok(stored)
+}
+
+async fn file_response(path: PathBuf, mime_type: &str, filename: &str) -> Response {
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::error!("Failed to read {}: {}", path.display(), e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "Failed to load the attachment.");
+        }
+    };
+
+    // Only media is shown in the browser; anything else, HTML included, is downloaded.
+    let inline = ["image/", "audio/", "video/"].iter().any(|prefix| mime_type.starts_with(prefix));
+    let disposition = format!(
+        "{}; filename*=UTF-8''{}",
+        if inline { "inline" } else { "attachment" },
+        encode_filename(filename)
+    );
+    let mut response = data.into_response();
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(mime_type) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&disposition) {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static("default-src 'none'; sandbox"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private, max-age=86400"));
+    response
+}
+
+/// `GET /attachments/:id`, the file as it was uploaded, without image metadata.
+pub async fn download(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(attachment_id): Path<i64>,
+) -> Response {
+    let stored = match authenticate_download(&state, &headers).and_then(|user| authorize_download(&state, &user, attachment_id)) {
+        Ok(stored) => stored,
+        Err((status, code, text)) => return error_response(status, code, text),
+    };
+    let attachment = stored.attachment;
+    file_response(blob_path(&stored.sha256), &attachment.mime_type, &attachment.filename).await
+}
+
+/// `GET /attachments/:id/thumbnail`, a preview of an image at most
+/// `THUMBNAIL_MAX_DIMENSION` pixels wide and high.
+pub async fn download_thumbnail(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(attachment_id): Path<i64>,
+) -> Response {
+    let stored = match authenticate_download(&state, &headers).and_then(|user| authorize_download(&state, &user, attachment_id)) {
+        Ok(stored) => stored,
+        Err((status, code, text)) => return error_response(status, code, text),
+    };
+    let Some(thumbnail_mime) = stored.thumbnail_mime else {
+        return error_response(StatusCode::NOT_FOUND, ErrorCode::NotFound, "This attachment has no thumbnail.");
+    };
+    file_response(thumbnail_path(&stored.sha256), &thumbnail_mime, &stored.attachment.filename).await
+}
+
+/// Deletes the files in `dir` that were not changed within `grace` and belong to none of
+/// the `referenced` hashes: blobs, their thumbnails and abandoned partial writes.
+fn remove_unreferenced_files(dir: &FsPath, referenced: &HashSet<String>, grace: Duration) -> std::io::Result<usize> {
+    let mut removed = 0;
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for shard in entries {
+        let shard = shard?.path();
+        if !shard.is_dir() {
+            continue;
+        }
+        for file in std::fs::read_dir(&shard)? {
+            let file = file?;
+            let name = file.file_name();
+            let sha256 = name.to_string_lossy();
+            let sha256 = sha256.split('.').next().unwrap_or_default();
+            let age = file.metadata()?.modified()?.elapsed().unwrap_or_default();
+            if !referenced.contains(sha256) && age >= grace {
+                std::fs::remove_file(file.path())?;
+                removed += 1;
+            }
+        }
+        // Only succeeds once the directory is empty.
+        let _ = std::fs::remove_dir(&shard);
+    }
+    Ok(removed)
+}
+
+/// Removes attachments that were never sent or whose message was deleted, then the files
+/// no attachment refers to.
+fn remove_orphans(conn: &db::Connection) -> Result<(usize, usize), String> {
+    let rows = db::delete_orphaned_attachments(conn, UNSENT_UPLOAD_TTL_SECS).map_err(|e| e.to_string())?;
+    let referenced = db::get_attachment_hashes(conn).map_err(|e| e.to_string())?;
+    let files = remove_unreferenced_files(FsPath::new(UPLOAD_DIR), &referenced, ORPHAN_GRACE_PERIOD).map_err(|e| e.to_string())?;
+    Ok((rows, files))
+}
+
+/// Runs `remove_orphans` now and then every `ORPHAN_SWEEP_INTERVAL`, for as long as the server runs.
+pub async fn sweep_orphans(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(ORPHAN_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pool = state.db_pool.clone();
+        let swept = tokio::task::spawn_blocking(move || remove_orphans(&pool.get().map_err(|e| e.to_string())?)).await;
+        match swept {
+            Ok(Ok((0, 0))) => {}
+            Ok(Ok((rows, files))) => tracing::info!("Removed {} orphaned attachments and {} unused files.", rows, files),
+            Ok(Err(e)) => tracing::error!("Failed to remove orphaned attachments: {}", e),
+            Err(e) => tracing::error!("Failed to remove orphaned attachments: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_old_unreferenced_files_are_removed() {
+        let dir = std::env::temp_dir().join(format!("simple_talk_uploads_{}", uuid::Uuid::new_v4()));
+        let shard = dir.join("ab");
+        std::fs::create_dir_all(&shard).unwrap();
+        for name in ["abc1", "abc1.thumb", "abc2", "abc2.thumb", "abc3.0f1e.part"] {
+            std::fs::write(shard.join(name), b"data").unwrap();
+        }
+        let referenced = HashSet::from(["abc1".to_string()]);
+
+        // Recent files are left alone, whether referenced or not.
+        assert_eq!(remove_unreferenced_files(&dir, &referenced, Duration::from_secs(3600)).unwrap(), 0);
+
+        assert_eq!(remove_unreferenced_files(&dir, &referenced, Duration::ZERO).unwrap(), 3);
+        let mut left: Vec<String> = std::fs::read_dir(&shard).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        left.sort();
+        assert_eq!(left, ["abc1", "abc1.thumb"]);
+
+        assert_eq!(remove_unreferenced_files(&dir, &HashSet::new(), Duration::ZERO).unwrap(), 2);
+        assert!(!shard.exists());
+        assert_eq!(remove_unreferenced_files(&dir.join("missing"), &referenced, Duration::ZERO).unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
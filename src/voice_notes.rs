@@ -0,0 +1,197 @@
+use hound::{SampleFormat, WavReader};
+use std::io::Cursor;
+
+/// Voice notes are stored at this sample rate, the one telephone speech uses.
+pub const SAMPLE_RATE: u32 = 8000;
+// A stored voice note is a WAV file of G.711 µ-law samples, one byte each. The header has
+// the format chunk with its extension size field and the `fact` chunk other formats than PCM need.
+const WAV_HEADER_BYTES: u64 = 58;
+const STORED_BYTES_PER_SEC: u64 = SAMPLE_RATE as u64;
+// Recordings as the browser client uploads them: a plain WAV header, then 16-bit mono samples.
+const UPLOAD_HEADER_BYTES: u64 = 44;
+const UPLOAD_BYTES_PER_SEC: u64 = SAMPLE_RATE as u64 * 2;
+// Longest voice note, however large uploads may be.
+const MAX_DURATION_SECS: u32 = 300;
+const MIN_INPUT_SAMPLE_RATE: u32 = 8000;
+const MAX_INPUT_SAMPLE_RATE: u32 = 192000;
+const WAVE_FORMAT_MULAW: u16 = 7;
+
+/// A recorded clip as it is stored.
+pub struct VoiceNote {
+    pub data: Vec<u8>,
+    pub duration_ms: u32,
+}
+
+/// How long a voice note may be. It is the most that fits into `max_upload_bytes` once it is
+/// encoded, and at most `MAX_DURATION_SECS`.
+pub fn max_duration_secs(max_upload_bytes: usize) -> u32 {
+    let fits = (max_upload_bytes as u64).saturating_sub(WAV_HEADER_BYTES) / STORED_BYTES_PER_SEC;
+    fits.min(MAX_DURATION_SECS as u64) as u32
+}
+
+/// The largest recording that may be uploaded as a voice note: one of `max_duration_secs`
+/// in the format the browser client records, 16-bit mono PCM at `SAMPLE_RATE`. Recordings
+/// with a higher sample rate or more channels reach this limit sooner.
+pub fn max_upload_bytes(max_duration_secs: u32) -> usize {
+    (UPLOAD_HEADER_BYTES + max_duration_secs as u64 * UPLOAD_BYTES_PER_SEC) as usize
+}
+
+/// Encodes a WAV recording as a mono G.711 µ-law WAV file at `SAMPLE_RATE`, 8 KB per second.
+pub fn encode(data: &[u8], max_duration_secs: u32) -> Result<VoiceNote, String> {
+    let reader = WavReader::new(Cursor::new(data)).map_err(|_| "Voice notes must be WAV recordings.".to_string())?;
+    let spec = reader.spec();
+    if !(MIN_INPUT_SAMPLE_RATE..=MAX_INPUT_SAMPLE_RATE).contains(&spec.sample_rate) || spec.channels == 0 {
+        return Err(format!("Unsupported recording format: {} Hz, {} channels.", spec.sample_rate, spec.channels));
+    }
+    let frames = reader.duration();
+    if frames == 0 {
+        return Err("The recording is empty.".to_string());
+    }
+    let duration_ms = (frames as u64 * 1000 / spec.sample_rate as u64) as u32;
+    if duration_ms > max_duration_secs * 1000 {
+        return Err(format!("Voice notes can be at most {} seconds long.", max_duration_secs));
+    }
+
+    let samples = read_mono(reader).map_err(|e| format!("The recording could not be read: {}", e))?;
+    let samples = resample(&samples, spec.sample_rate, SAMPLE_RATE);
+    let mut out = mulaw_wav_header(samples.len() as u32);
+    out.extend(samples.iter().map(|sample| mulaw((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)));
+    Ok(VoiceNote { data: out, duration_ms })
+}
+
+/// Compresses a sample to 8 bits with the G.711 µ-law curve, which keeps more precision for
+/// quiet sounds than for loud ones.
+fn mulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let (sign, magnitude) = if sample < 0 { (0x80, -(sample as i32)) } else { (0, sample as i32) };
+    let biased = magnitude.min(CLIP) + BIAS;
+    let exponent = 31 - biased.leading_zeros() as i32 - 7;
+    let mantissa = (biased >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+/// The header of a mono µ-law WAV file with `samples` samples at `SAMPLE_RATE`.
+fn mulaw_wav_header(samples: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(WAV_HEADER_BYTES as usize + samples as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_BYTES as u32 - 8 + samples).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&18u32.to_le_bytes());
+    header.extend_from_slice(&WAVE_FORMAT_MULAW.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // Mono
+    header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(&(STORED_BYTES_PER_SEC as u32).to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // Bytes per sample
+    header.extend_from_slice(&8u16.to_le_bytes()); // Bits per sample
+    header.extend_from_slice(&0u16.to_le_bytes()); // No format extension
+    header.extend_from_slice(b"fact");
+    header.extend_from_slice(&4u32.to_le_bytes());
+    header.extend_from_slice(&samples.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&samples.to_le_bytes());
+    header
+}
+
+/// Returns the samples between -1 and 1, with all channels mixed into one.
+fn read_mono(reader: WavReader<Cursor<&[u8]>>) -> hound::Result<Vec<f32>> {
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.into_samples::<f32>().collect::<hound::Result<_>>()?,
+        SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.into_samples::<i32>().map(|s| s.map(|s| s as f32 / scale)).collect::<hound::Result<_>>()?
+        }
+    };
+    let channels = spec.channels as usize;
+    Ok(samples.chunks(channels).map(|frame| frame.iter().sum::<f32>() / channels as f32).collect())
+}
+
+/// Lowers the sample rate by averaging the input samples that fall into each output sample,
+/// which also filters out most of what the lower rate cannot represent.
+fn resample(samples: &[f32], in_rate: u32, out_rate: u32) -> Vec<f32> {
+    if in_rate == out_rate {
+        return samples.to_vec();
+    }
+    let ratio = in_rate as f64 / out_rate as f64;
+    let out_len = (samples.len() as f64 / ratio) as usize;
+    (0..out_len)
+        .map(|i| {
+            let start = (i as f64 * ratio) as usize;
+            let end = (((i + 1) as f64 * ratio) as usize).clamp(start + 1, samples.len());
+            samples[start..end].iter().sum::<f32>() / (end - start) as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::{WavSpec, WavWriter};
+
+    fn wav(sample_rate: u32, channels: u16, secs: u32) -> Vec<u8> {
+        let spec = WavSpec { channels, sample_rate, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut out, spec).unwrap();
+        for i in 0..sample_rate * secs * channels as u32 {
+            writer.write_sample((i % 200) as i16 * 100).unwrap();
+        }
+        writer.finalize().unwrap();
+        out.into_inner()
+    }
+
+    /// Expands a µ-law byte back to a 16-bit sample, as players do.
+    fn decode_mulaw(byte: u8) -> i16 {
+        let byte = !byte;
+        let exponent = (byte >> 4) & 0x07;
+        let magnitude = ((((byte & 0x0F) as i32) << 3) + 0x84) << exponent;
+        let sample = magnitude - 0x84;
+        (if byte & 0x80 != 0 { -sample } else { sample }) as i16
+    }
+
+    #[test]
+    fn duration_limit_follows_the_encoded_size() {
+        assert_eq!(max_duration_secs(10 * 1024 * 1024), MAX_DURATION_SECS);
+        assert_eq!(max_duration_secs(1024 * 1024), 131);
+        assert_eq!(max_duration_secs(0), 0);
+        // A note of the longest allowed length fits the upload limit once stored, and may be
+        // uploaded as the client records it.
+        let limit = 1024 * 1024;
+        let recording = wav(SAMPLE_RATE, 1, max_duration_secs(limit));
+        assert_eq!(recording.len(), max_upload_bytes(max_duration_secs(limit)));
+        assert!(encode(&recording, max_duration_secs(limit)).unwrap().data.len() <= limit);
+    }
+
+    #[test]
+    fn mulaw_follows_g711() {
+        assert_eq!([mulaw(0), mulaw(-1), mulaw(i16::MAX), mulaw(i16::MIN)], [0xFF, 0x7F, 0x80, 0x00]);
+        for sample in [-32000i16, -1000, -100, -8, 0, 8, 100, 1000, 32000] {
+            let error = (decode_mulaw(mulaw(sample)) as i32 - sample as i32).abs();
+            // The step between codes grows with the loudness, to 1/16 of the magnitude at most.
+            assert!(error <= (sample as i32).abs() / 16 + 8, "{} came back {} off", sample, error);
+        }
+    }
+
+    #[test]
+    fn recordings_are_stored_as_mono_mulaw_at_the_speech_rate() {
+        for (rate, channels) in [(48000, 2), (SAMPLE_RATE, 1)] {
+            let note = encode(&wav(rate, channels, 2), 10).unwrap();
+            assert_eq!(note.duration_ms, 2000);
+            assert_eq!(note.data.len() as u64, WAV_HEADER_BYTES + 2 * STORED_BYTES_PER_SEC);
+            let header = &note.data[..WAV_HEADER_BYTES as usize];
+            let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+            assert_eq!((&header[..4], &header[8..16], &header[38..42], &header[50..54]), (&b"RIFF"[..], &b"WAVEfmt "[..], &b"fact"[..], &b"data"[..]));
+            assert_eq!(field(4) as usize, note.data.len() - 8);
+            assert_eq!(u16::from_le_bytes([header[20], header[21]]), WAVE_FORMAT_MULAW);
+            assert_eq!((u16::from_le_bytes([header[22], header[23]]), field(24), field(28)), (1, SAMPLE_RATE, SAMPLE_RATE));
+            assert_eq!((field(46), field(54)), (2 * SAMPLE_RATE, 2 * SAMPLE_RATE));
+        }
+    }
+
+    #[test]
+    fn long_or_invalid_recordings_are_refused() {
+        assert!(matches!(encode(&wav(SAMPLE_RATE, 1, 3), 2), Err(message) if message.contains("at most 2 seconds")));
+        assert!(encode(b"not a recording", 10).is_err());
+        assert!(matches!(encode(&wav(SAMPLE_RATE, 1, 0), 10), Err(message) if message.contains("empty")));
+    }
+}
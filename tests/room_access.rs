@@ -0,0 +1,485 @@
+//! Checks that users who are not members of a room cannot read, write or talk in it.
+//!
+//! Every test starts its own server in a temporary directory, so each gets a fresh database.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const PASSWORD: &str = "password1";
+
+struct TestServer {
+    _child: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "simple_talk_test_{}_{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.json"), r#"{"port": 0}"#).unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_simple_talk_client"))
+            .current_dir(&dir)
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("Failed to start the server.");
+
+        // The server picks a free port and prints it once it is listening.
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let port = tokio::time::timeout(TIMEOUT, async {
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if let Some(url) = line.trim().strip_prefix("> On this machine: http://localhost:") {
+                    return url.parse().unwrap();
+                }
+            }
+            panic!("The server exited before it was listening.");
+        })
+        .await
+        .expect("The server did not start in time.");
+        // Keep reading so the server never blocks on a full pipe.
+        tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+
+        TestServer { _child: child, port, dir }
+    }
+
+    async fn connect(&self) -> TestClient {
+        let (ws, _) = connect_async(format!("ws://127.0.0.1:{}/ws", self.port)).await.unwrap();
+        TestClient { ws }
+    }
+
+    async fn register(&self, username: &str) {
+        let mut client = self.connect().await;
+        client.send("register", json!({ "username": username, "password": PASSWORD })).await;
+        client.expect("register_ok").await;
+    }
+
+    async fn login(&self, username: &str) -> TestClient {
+        self.login_with_token(username).await.0
+    }
+
+    async fn login_with_token(&self, username: &str) -> (TestClient, String) {
+        let mut client = self.connect().await;
+        client.send("login", json!({ "username": username, "password": PASSWORD })).await;
+        let token = client.expect("auth_ok").await["token"].as_str().unwrap().to_string();
+        (client, token)
+    }
+
+    /// Sends a plain HTTP request and returns the status, the response headers and the body.
+    async fn http(&self, request_line: &str, headers: &[(&str, &str)], body: &[u8]) -> (u16, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).await.unwrap();
+        let mut request = format!("{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n", request_line, body.len());
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut body = response[split + 4..].to_vec();
+        if head.to_ascii_lowercase().contains("transfer-encoding: chunked") {
+            body = decode_chunked(&body);
+        }
+        (status, head, body)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    async fn send(&mut self, kind: &str, payload: Value) {
+        let frame = json!({ "type": kind, "payload": payload }).to_string();
+        self.ws.send(Message::Text(frame)).await.unwrap();
+    }
+
+    async fn send_request(&mut self, kind: &str, payload: Value, request_id: u32) {
+        let frame = json!({ "type": kind, "payload": payload, "request_id": request_id }).to_string();
+        self.ws.send(Message::Text(frame)).await.unwrap();
+    }
+
+    /// Waits for the reply to a request and returns its type.
+    async fn expect_reply(&mut self, request_id: u32) -> String {
+        let frame = self
+            .next_matching(TIMEOUT, |frame| {
+                let Message::Text(text) = frame else { return false };
+                serde_json::from_str::<Value>(text).is_ok_and(|value| value["request_id"] == request_id)
+            })
+            .await
+            .unwrap_or_else(|| panic!("No reply to request {} arrived.", request_id));
+        message_type(&frame).unwrap()
+    }
+
+    async fn send_audio(&mut self, data: &[u8]) {
+        self.ws.send(Message::Binary(data.to_vec())).await.unwrap();
+    }
+
+    /// Reads frames until one matches, skipping everything else.
+    async fn next_matching(&mut self, wait: Duration, matches: impl Fn(&Message) -> bool) -> Option<Message> {
+        tokio::time::timeout(wait, async {
+            loop {
+                match self.ws.next().await {
+                    Some(Ok(frame)) if matches(&frame) => return Some(frame),
+                    Some(Ok(_)) => {}
+                    _ => return None,
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Waits for a message of the given type and returns its payload.
+    async fn expect(&mut self, kind: &str) -> Value {
+        let frame = self
+            .next_matching(TIMEOUT, |frame| message_type(frame).as_deref() == Some(kind))
+            .await
+            .unwrap_or_else(|| panic!("No '{}' message arrived.", kind));
+        let Message::Text(text) = frame else { unreachable!() };
+        serde_json::from_str::<Value>(&text).unwrap()["payload"].take()
+    }
+
+    /// Waits for an error and returns its code.
+    async fn expect_error(&mut self) -> String {
+        self.expect("error").await["code"].as_str().unwrap().to_string()
+    }
+
+    async fn expect_audio(&mut self) -> Option<Vec<u8>> {
+        match self.next_matching(Duration::from_millis(500), |frame| matches!(frame, Message::Binary(_))).await {
+            Some(Message::Binary(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    async fn join_room(&mut self, room_id: i64) {
+        self.send("join_room", json!({ "roomId": room_id })).await;
+        self.expect("join_ok").await;
+    }
+}
+
+/// Joins the chunks of a `Transfer-Encoding: chunked` body.
+fn decode_chunked(mut data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = usize::from_str_radix(std::str::from_utf8(&data[..line_end]).unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            return body;
+        }
+        body.extend_from_slice(&data[line_end + 2..line_end + 2 + size]);
+        data = &data[line_end + 4 + size..];
+    }
+}
+
+fn message_type(frame: &Message) -> Option<String> {
+    let Message::Text(text) = frame else { return None };
+    let value: Value = serde_json::from_str(text).ok()?;
+    Some(value["type"].as_str()?.to_string())
+}
+
+/// Makes the two users friends and returns their one-on-one room, with both connections in it.
+async fn open_private_room(alice: &mut TestClient, bob: &mut TestClient) -> i64 {
+    alice.send("send_friend_request", json!({ "username": "bob" })).await;
+    let request = bob.expect("new_friend_request").await;
+    bob.send("respond_to_friend_request", json!({ "requestId": request["id"], "accept": true })).await;
+    alice.expect("friend_request_accepted").await;
+
+    bob.send("quick_chat_with_friend", json!({ "friendId": request["from_user_id"] })).await;
+    let room_id = bob.expect("join_ok").await["roomId"].as_i64().unwrap();
+    alice.join_room(room_id).await;
+    room_id
+}
+
+#[tokio::test]
+async fn members_can_chat_and_talk() {
+    let server = TestServer::start().await;
+    server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+    let room_id = open_private_room(&mut alice, &mut bob).await;
+
+    bob.send("send_chat_message", json!({ "roomId": room_id, "content": "hello" })).await;
+    assert_eq!(alice.expect("new_chat_message").await["content"], "hello");
+
+    bob.send_audio(&[1, 2, 3]).await;
+    assert_eq!(alice.expect_audio().await, Some(vec![1, 2, 3]));
+}
+
+#[tokio::test]
+async fn non_members_cannot_join_private_rooms() {
+    let server = TestServer::start().await;
+    for username in ["alice", "bob", "mallory"] {
+        server.register(username).await;
+    }
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+    let room_id = open_private_room(&mut alice, &mut bob).await;
+    bob.send("send_chat_message", json!({ "roomId": room_id, "content": "secret" })).await;
+    bob.expect("message_ack").await;
+
+    let mut mallory = server.login("mallory").await;
+    mallory.send("join_room", json!({ "roomId": room_id })).await;
+    assert_eq!(mallory.expect_error().await, "forbidden");
+    mallory.send("join_room", json!({ "roomId": 9999 })).await;
+    assert_eq!(mallory.expect_error().await, "forbidden");
+
+    // Without joining, nothing in the room is reachable.
+    mallory.send("load_older_messages", json!({ "roomId": room_id, "beforeId": 1000 })).await;
+    assert_eq!(mallory.expect_error().await, "not_in_room");
+    mallory.send("send_chat_message", json!({ "roomId": room_id, "content": "hi" })).await;
+    assert_eq!(mallory.expect_error().await, "not_in_room");
+    mallory.send("request_voice_chat", json!({})).await;
+    assert_eq!(mallory.expect_error().await, "not_in_room");
+    mallory.send_audio(&[9, 9, 9]).await;
+    assert_eq!(alice.expect_audio().await, None);
+    assert_eq!(bob.expect_audio().await, None);
+}
+
+#[tokio::test]
+async fn kicked_members_lose_access() {
+    let server = TestServer::start().await;
+    server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+    open_private_room(&mut alice, &mut bob).await;
+
+    alice.send("create_group", json!({ "name": "Team", "members": ["bob"] })).await;
+    let room_id = alice.expect("room_updated").await["room_id"].as_i64().unwrap();
+    alice.join_room(room_id).await;
+    bob.join_room(room_id).await;
+    bob.send_audio(&[1]).await;
+    assert_eq!(alice.expect_audio().await, Some(vec![1]));
+    bob.send("send_chat_message", json!({ "roomId": room_id, "content": "before the kick" })).await;
+    let message_id = bob.expect("message_ack").await["id"].clone();
+
+    alice.send("kick_from_room", json!({ "roomId": room_id, "username": "bob" })).await;
+    bob.expect("room_updated").await;
+
+    // Bob's connection still has the room open, but every room-scoped request is refused.
+    bob.send("send_chat_message", json!({ "roomId": room_id, "content": "still here" })).await;
+    assert_eq!(bob.expect_error().await, "forbidden");
+    bob.send("load_older_messages", json!({ "roomId": room_id, "beforeId": 1000 })).await;
+    assert_eq!(bob.expect_error().await, "forbidden");
+    bob.send("request_voice_chat", json!({})).await;
+    assert_eq!(bob.expect_error().await, "forbidden");
+    bob.send("edit_message", json!({ "messageId": message_id, "content": "rewritten" })).await;
+    assert_eq!(bob.expect_error().await, "forbidden");
+    bob.send("delete_message", json!({ "messageId": message_id })).await;
+    assert_eq!(bob.expect_error().await, "forbidden");
+    bob.send("join_room", json!({ "roomId": room_id })).await;
+    assert_eq!(bob.expect_error().await, "forbidden");
+
+    bob.send_audio(&[2]).await;
+    assert_eq!(bob.expect_error().await, "forbidden");
+    assert_eq!(alice.expect_audio().await, None);
+}
+
+#[tokio::test]
+async fn only_public_groups_can_be_joined_by_anyone() {
+    let server = TestServer::start().await;
+    server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    alice.send("create_group", json!({ "name": "Lobby", "members": [], "public": true })).await;
+    let room_id = alice.expect("room_updated").await["room_id"].as_i64().unwrap();
+    alice.send("set_room_public", json!({ "roomId": room_id, "public": false })).await;
+    alice.expect("room_updated").await;
+
+    bob.send("join_room", json!({ "roomId": room_id })).await;
+    assert_eq!(bob.expect_error().await, "forbidden");
+
+    alice.send("set_room_public", json!({ "roomId": room_id, "public": true })).await;
+    alice.expect("room_updated").await;
+    bob.join_room(room_id).await;
+    bob.expect("message_history").await;
+}
+
+#[tokio::test]
+async fn attachments_are_only_served_to_members_with_a_session() {
+    let server = TestServer::start().await;
+    for username in ["alice", "bob", "mallory"] {
+        server.register(username).await;
+    }
+    let (mut alice, alice_token) = server.login_with_token("alice").await;
+    let (mut bob, bob_token) = server.login_with_token("bob").await;
+    let room_id = open_private_room(&mut alice, &mut bob).await;
+
+    let bearer = format!("Bearer {}", alice_token);
+    let (status, _, body) = server.http("POST /upload?name=notes.txt", &[("Authorization", &bearer)], b"meeting notes").await;
+    assert_eq!(status, 201);
+    let attachment_id = serde_json::from_slice::<Value>(&body).unwrap()["id"].clone();
+    alice.send("send_chat_message", json!({ "roomId": room_id, "content": "notes", "attachments": [attachment_id] })).await;
+    alice.expect("message_ack").await;
+    let path = format!("GET /attachments/{}", attachment_id);
+
+    // The login token is not accepted in the URL, where it would end up in logs and history.
+    assert_eq!(server.http(&path, &[], b"").await.0, 401);
+    assert_eq!(server.http(&format!("{}?token={}", path, bob_token), &[], b"").await.0, 401);
+
+    let (status, head, _) = server.http("POST /session", &[("Authorization", &format!("Bearer {}", bob_token))], b"").await;
+    assert_eq!(status, 204);
+    assert!(head.contains(&format!("session={}; Path=/; HttpOnly; SameSite=Strict", bob_token)));
+    let (status, _, body) = server.http(&path, &[("Cookie", &format!("theme=dark; session={}", bob_token))], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, b"meeting notes");
+
+    // The cookie only reads; uploads still need the header.
+    assert_eq!(server.http("POST /upload?name=x.txt", &[("Cookie", &format!("session={}", bob_token))], b"x").await.0, 401);
+
+    let (_, mallory_token) = server.login_with_token("mallory").await;
+    assert_eq!(server.http(&path, &[("Cookie", &format!("session={}", mallory_token))], b"").await.0, 403);
+    assert_eq!(server.http(&path, &[("Cookie", "session=not-a-token")], b"").await.0, 401);
+}
+
+#[tokio::test]
+async fn exports_are_only_streamed_to_members() {
+    let server = TestServer::start().await;
+    for username in ["alice", "bob", "mallory"] {
+        server.register(username).await;
+    }
+    let (mut alice, alice_token) = server.login_with_token("alice").await;
+    let mut bob = server.login("bob").await;
+    let room_id = open_private_room(&mut alice, &mut bob).await;
+
+    let bearer = format!("Bearer {}", alice_token);
+    let (_, _, body) = server.http("POST /upload?name=notes.txt", &[("Authorization", &bearer)], b"meeting notes").await;
+    let attachment_id = serde_json::from_slice::<Value>(&body).unwrap()["id"].as_i64().unwrap();
+    alice.send("send_chat_message", json!({ "roomId": room_id, "content": "see notes", "attachments": [attachment_id] })).await;
+    alice.expect("message_ack").await;
+
+    alice.send("export_room", json!({ "roomId": room_id, "format": "text" })).await;
+    let url = alice.expect("export_ready").await["url"].as_str().unwrap().to_string();
+    let path = format!("GET {}", url);
+    let cookie = format!("session={}", alice_token);
+    let (status, head, body) = server.http(&path, &[("Cookie", &cookie)], b"").await;
+    assert_eq!(status, 200);
+    assert!(head.to_ascii_lowercase().contains("transfer-encoding: chunked"));
+    let text = String::from_utf8(body).unwrap();
+    assert!(text.contains("Messages: 1\n"));
+    assert!(text.contains(": see notes\n"));
+    assert!(text.contains(&format!("Attachment #{}: notes.txt (application/octet-stream, 13 B) http://localhost/attachments/{}", attachment_id, attachment_id)));
+
+    let (_, _, body) = server.http(&path.replace("format=text", "format=html"), &[("Cookie", &cookie)], b"").await;
+    let html = String::from_utf8(body).unwrap();
+    assert!(html.contains(&format!("Attachment #{}: <a href=\"http://localhost/attachments/{}\">notes.txt", attachment_id, attachment_id)));
+    assert!(html.ends_with("</html>\n"));
+
+    let (status, _, body) = server.http(&path.replace("format=text", "format=jsonl"), &[("Cookie", &cookie)], b"").await;
+    assert_eq!(status, 200);
+    let lines: Vec<Value> = String::from_utf8(body).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.iter().map(|line| line["type"].as_str().unwrap()).collect::<Vec<_>>(), ["export", "room", "message"]);
+    assert_eq!(lines[2]["attachments"][0]["id"], attachment_id);
+
+    assert_eq!(server.http(&format!("{}&token={}", path, alice_token), &[], b"").await.0, 401);
+    let (_, mallory_token) = server.login_with_token("mallory").await;
+    assert_eq!(server.http(&path, &[("Cookie", &format!("session={}", mallory_token))], b"").await.0, 403);
+    assert_eq!(server.http("GET /admin/export?format=html", &[("Cookie", &format!("session={}", mallory_token))], b"").await.0, 403);
+}
+
+#[tokio::test]
+async fn commands_without_a_reply_are_acknowledged() {
+    let server = TestServer::start().await;
+    // The first user to register becomes the admin.
+    server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+    let room_id = open_private_room(&mut alice, &mut bob).await;
+
+    bob.send_request("typing_start", json!({ "roomId": room_id }), 1).await;
+    assert_eq!(bob.expect_reply(1).await, "ok");
+    bob.send_request("typing_stop", json!({ "roomId": room_id }), 2).await;
+    assert_eq!(bob.expect_reply(2).await, "ok");
+    bob.send_request("request_voice_chat", json!({}), 3).await;
+    assert_eq!(bob.expect_reply(3).await, "ok");
+    alice.expect("voice_chat_invitation").await;
+
+    alice.send_request("admin_shutdown_server", json!({}), 4).await;
+    assert_eq!(alice.expect_reply(4).await, "ok");
+}
+
+#[tokio::test]
+async fn deleted_users_are_disconnected_everywhere() {
+    let server = TestServer::start().await;
+    // The first user to register becomes the admin.
+    server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+    let mut bob_elsewhere = server.login("bob").await;
+    let room_id = open_private_room(&mut alice, &mut bob).await;
+    bob_elsewhere.join_room(room_id).await;
+
+    alice.send("admin_get_all_users", json!({})).await;
+    let users = alice.expect("admin_all_users").await;
+    let bob_id = users.as_array().unwrap().iter().find(|u| u["username"] == "bob").unwrap()["_id"].clone();
+    alice.send("admin_delete_user", json!({ "user_id": bob_id })).await;
+    alice.expect("admin_generic_ok").await;
+
+    for connection in [&mut bob, &mut bob_elsewhere] {
+        connection.expect("session_revoked").await;
+        let closed = tokio::time::timeout(TIMEOUT, async {
+            while let Some(Ok(frame)) = connection.ws.next().await {
+                if matches!(frame, Message::Close(_)) {
+                    break;
+                }
+            }
+        });
+        assert!(closed.await.is_ok(), "The connection stayed open.");
+    }
+
+    alice.send("admin_delete_user", json!({ "user_id": bob_id })).await;
+    alice.expect("admin_error").await;
+}
+
+#[tokio::test]
+async fn private_chats_are_only_opened_with_friends() {
+    let server = TestServer::start().await;
+    // The first user to register becomes the admin.
+    server.register("alice").await;
+    server.register("mallory").await;
+    let mut alice = server.login("alice").await;
+    let mut mallory = server.login("mallory").await;
+    alice.send("admin_get_all_users", json!({})).await;
+    let users = alice.expect("admin_all_users").await;
+    let alice_id = users.as_array().unwrap().iter().find(|u| u["username"] == "alice").unwrap()["_id"].clone();
+
+    mallory.send("quick_chat_with_friend", json!({ "friendId": alice_id })).await;
+    assert_eq!(mallory.expect_error().await, "forbidden");
+    let invited = alice.next_matching(Duration::from_millis(500), |frame| message_type(frame).as_deref() == Some("invitation")).await;
+    assert!(invited.is_none(), "A stranger's invitation got through.");
+}